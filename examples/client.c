@@ -28,6 +28,7 @@
 #include <stdio.h>
 #include <stdlib.h>
 #include <stdint.h>
+#include <inttypes.h>
 #include <stdbool.h>
 #include <unistd.h>
 
@@ -184,14 +185,12 @@ static void timeout_cb(EV_P_ ev_timer *w, int revents) {
     flush_egress(loop, conn_io);
 
     if (quiche_conn_is_closed(conn_io->conn)) {
-        uint64_t sent, lost, rtt;
+        quiche_stats stats;
 
-        quiche_conn_stats_sent(conn_io->conn, &sent);
-        quiche_conn_stats_lost(conn_io->conn, &lost);
-        quiche_conn_stats_rtt_as_nanos(conn_io->conn, &rtt);
+        quiche_conn_stats(conn_io->conn, &stats);
 
-        fprintf(stderr, "connection closed, sent=%ld lost=%ld rtt=%ldns\n",
-                sent, lost, rtt);
+        fprintf(stderr, "connection closed, sent=%zu lost=%zu rtt=%" PRIu64 "ns\n",
+                stats.sent, stats.lost, stats.rtt);
 
         ev_break(EV_A_ EVBREAK_ONE);
         return;
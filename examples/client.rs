@@ -98,8 +98,14 @@ fn main() -> Result<(), Box<std::error::Error>> {
         config.verify_peer(false);
     }
 
-    if std::env::var_os("SSLKEYLOGFILE").is_some() {
-        config.log_keys();
+    if let Some(path) = std::env::var_os("SSLKEYLOGFILE") {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+
+        config.log_keys(Box::new(file));
     }
 
     let mut conn = quiche::connect(url.domain(), &scid, &mut config)?;
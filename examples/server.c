@@ -243,12 +243,13 @@ static void recv_cb(EV_P_ ev_io *w, int revents) {
         HASH_FIND(hh, conns->h, dcid, dcid_len, conn_io);
 
         if (conn_io == NULL) {
-            if (version != QUICHE_VERSION_DRAFT17) {
+            if (!quiche_version_is_supported(config, version)) {
                 fprintf(stderr, "version negotiation\n");
 
                 ssize_t written = quiche_negotiate_version(scid, scid_len,
                                                            dcid, dcid_len,
-                                                           out, sizeof(out));
+                                                           config, out,
+                                                           sizeof(out));
 
                 if (written < 0) {
                     fprintf(stderr, "failed to create vneg packet: %ld\n",
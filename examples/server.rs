@@ -149,11 +149,11 @@ fn main() -> Result<(), Box<std::error::Error>> {
                     continue;
                 }
 
-                if hdr.version != quiche::VERSION_DRAFT17 {
+                if !quiche::version_is_supported(&config, hdr.version) {
                     warn!("Doing version negotiation");
 
                     let len = quiche::negotiate_version(
-                        &hdr.scid, &hdr.dcid, &mut out,
+                        &hdr.scid, &hdr.dcid, &config, &mut out,
                     )?;
 
                     let out = &out[..len];
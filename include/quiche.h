@@ -35,9 +35,12 @@ extern "C" {
 #include <stdint.h>
 #include <stdbool.h>
 
-// The current QUIC wire version.
+// The QUIC wire version of draft-17.
 #define QUICHE_VERSION_DRAFT17 0xff000011
 
+// The QUIC wire version of draft-18.
+#define QUICHE_VERSION_DRAFT18 0xff000012
+
 // The maximum length of a connection ID.
 #define QUICHE_MAX_CONN_ID_LEN 18
 
@@ -100,11 +103,70 @@ int quiche_config_load_cert_chain_from_pem_file(quiche_config *config,
 int quiche_config_load_priv_key_from_pem_file(quiche_config *config,
                                               const char *path);
 
+// Configures the given PEM-encoded certificate chain from memory.
+int quiche_config_load_cert_chain_from_pem(quiche_config *config,
+                                           const uint8_t *buf, size_t buf_len);
+
+// Configures the given certificate chain from memory, as an array of |count|
+// DER-encoded certificates starting with the leaf certificate.
+int quiche_config_load_cert_chain_from_der(quiche_config *config,
+                                           const uint8_t *const *certs,
+                                           const size_t *certs_len,
+                                           size_t count);
+
+// Configures the given PEM-encoded private key from memory.
+int quiche_config_load_priv_key_from_pem(quiche_config *config,
+                                         const uint8_t *buf, size_t buf_len);
+
+// Configures the given DER-encoded private key from memory.
+int quiche_config_load_priv_key_from_der(quiche_config *config,
+                                         const uint8_t *buf, size_t buf_len);
+
+// Adds the given PEM-encoded trusted CA certificates from memory.
+int quiche_config_load_verify_locations_from_pem(quiche_config *config,
+                                                 const uint8_t *buf,
+                                                 size_t buf_len);
+
+// Configures the allowed TLS 1.3 cipher suites, as a colon-separated list of
+// IANA names. A connection negotiating any other suite fails its handshake.
+int quiche_config_set_cipher_suites(quiche_config *config, const char *suites);
+
+// Configures the supported QUIC versions, in order of preference.
+int quiche_config_set_versions(quiche_config *config, const uint32_t *versions,
+                               size_t versions_len);
+
+// Configures the key exchange groups, as a colon-separated list of names in
+// order of preference.
+int quiche_config_set_groups(quiche_config *config, const char *groups);
+
+// Configures the session ticket keys, as a buffer of concatenated 48 bytes
+// keys, the first one being used to issue new tickets.
+int quiche_config_set_ticket_keys(quiche_config *config, const uint8_t *keys,
+                                  size_t keys_len);
+
+// Configures whether servers issue session tickets.
+void quiche_config_enable_session_tickets(quiche_config *config, bool v);
+
+enum quiche_client_auth {
+    // Clients are not asked for a certificate.
+    QUICHE_CLIENT_AUTH_NONE = 0,
+
+    // Clients are asked for a certificate, which is verified if sent.
+    QUICHE_CLIENT_AUTH_REQUEST = 1,
+
+    // Clients must send a valid certificate, or the handshake fails.
+    QUICHE_CLIENT_AUTH_REQUIRE = 2,
+};
+
+// Configures whether servers request a certificate from clients.
+void quiche_config_set_client_auth(quiche_config *config, uint8_t mode);
+
 // Configures whether to verify the peer's certificate.
 void quiche_config_verify_peer(quiche_config *config, bool v);
 
-// Enables logging of secrets.
-void quiche_config_log_keys(quiche_config *config);
+// Enables logging of secrets to the file at the given path, for all
+// connections. Returns false if the file can't be opened.
+bool quiche_config_log_keys(quiche_config *config, const char *path);
 
 // Sets the `idle_timeout` transport parameter.
 void quiche_config_set_idle_timeout(quiche_config *config, uint64_t v);
@@ -139,6 +201,29 @@ void quiche_config_set_max_ack_delay(quiche_config *config, uint64_t v);
 // Sets the `disable_migration` transport parameter.
 void quiche_config_set_disable_migration(quiche_config *config, bool v);
 
+// Sets the maximum size of the connection-level receive window.
+void quiche_config_set_max_connection_window(quiche_config *config, uint64_t v);
+
+// Sets the maximum size of a stream's receive window.
+void quiche_config_set_max_stream_window(quiche_config *config, uint64_t v);
+
+// Sets the number of ack-eliciting packets to receive before sending an ACK.
+void quiche_config_set_ack_frequency(quiche_config *config, size_t v);
+
+// Configures whether to mark outgoing packets as ECN-capable.
+void quiche_config_enable_ecn(quiche_config *config, bool v);
+
+// Configures whether to enable path MTU discovery.
+void quiche_config_enable_pmtud(quiche_config *config, bool v);
+
+// Configures whether to enable sending and receiving DATAGRAM frames.
+void quiche_config_enable_dgram(quiche_config *config, bool enabled,
+                                size_t recv_queue_len, size_t send_queue_len);
+
+// Uses a deterministic RNG initialized with the given seed, instead of the
+// system's secure RNG. This must only be used for testing.
+void quiche_config_set_rng_seed(quiche_config *config, uint64_t seed);
+
 // Frees the config object.
 void quiche_config_free(quiche_config *config);
 
@@ -162,9 +247,14 @@ quiche_conn *quiche_accept(const uint8_t *scid, size_t scid_len,
 quiche_conn *quiche_connect(const char *server_name, const uint8_t *scid,
                             size_t scid_len, quiche_config *config);
 
-// Writes a version negotiation packet.
+// Returns true if the given protocol version is enabled by the config.
+bool quiche_version_is_supported(quiche_config *config, uint32_t version);
+
+// Writes a version negotiation packet offering the versions enabled by the
+// config.
 ssize_t quiche_negotiate_version(const uint8_t *scid, size_t scid_len,
                                  const uint8_t *dcid, size_t dcid_len,
+                                 quiche_config *config,
                                  uint8_t *out, size_t out_len);
 
 // Writes a retry packet.
@@ -174,6 +264,8 @@ ssize_t quiche_retry(const uint8_t *scid, size_t scid_len,
                      const uint8_t *token, size_t token_len,
                      uint8_t *out, size_t out_len);
 
+// Creates a connection using an existing BoringSSL `SSL` object. Not available
+// when built with the pure-Rust TLS backend.
 quiche_conn *quiche_conn_new_with_tls(const uint8_t *scid, size_t scid_len,
                                       const uint8_t *odcid, size_t odcid_len,
                                       quiche_config *config, void *ssl,
@@ -185,6 +277,20 @@ ssize_t quiche_conn_recv(quiche_conn *conn, uint8_t *buf, size_t buf_len);
 // Writes a single QUIC packet to be sent to the peer.
 ssize_t quiche_conn_send(quiche_conn *conn, uint8_t *out, size_t out_len);
 
+// Writes multiple QUIC packets of the same size contiguously, and returns the
+// number of packets written. The size of each packet is stored in
+// |segment_size|.
+ssize_t quiche_conn_send_batch(quiche_conn *conn, uint8_t *out, size_t out_len,
+                               size_t max_packets, size_t *segment_size);
+
+// Processes QUIC packets received from the peer with the given ECN codepoint,
+// i.e. the two ECN bits of the IP header.
+ssize_t quiche_conn_recv_with_ecn(quiche_conn *conn, uint8_t *buf,
+                                  size_t buf_len, uint8_t ecn);
+
+// Returns the ECN codepoint outgoing packets should be marked with.
+uint8_t quiche_conn_ecn_marking(quiche_conn *conn);
+
 // Buffer holding data at a specific offset.
 typedef struct RangeBuf quiche_rangebuf;
 
@@ -196,6 +302,13 @@ ssize_t quiche_conn_stream_recv(quiche_conn *conn, uint64_t stream_id,
 ssize_t quiche_conn_stream_send(quiche_conn *conn, uint64_t stream_id,
                                 const uint8_t *buf, size_t buf_len, bool fin);
 
+// Reads the first received DATAGRAM.
+ssize_t quiche_conn_dgram_recv(quiche_conn *conn, uint8_t *buf, size_t buf_len);
+
+// Queues data to be sent in a DATAGRAM frame.
+ssize_t quiche_conn_dgram_send(quiche_conn *conn, const uint8_t *buf,
+                               size_t buf_len);
+
 // An iterator over the streams that have outstanding data to read.
 typedef struct Readable quiche_readable;
 
@@ -219,16 +332,76 @@ void quiche_conn_on_timeout(quiche_conn *conn);
 int quiche_conn_close(quiche_conn *conn, bool app, uint16_t err,
                       const uint8_t *reason, size_t reason_len);
 
+// Returns the server name requested by the client. |out_len| is set to 0 if
+// there is none.
+void quiche_conn_server_name(quiche_conn *conn, const uint8_t **out,
+                             size_t *out_len);
+
+// Returns the peer's DER-encoded leaf certificate. |out_len| is set to 0 if
+// there is none.
+void quiche_conn_peer_cert(quiche_conn *conn, const uint8_t **out,
+                           size_t *out_len);
+
+// Sets the file the connection's secrets are logged to, instead of the one set
+// with quiche_config_log_keys(). Returns false if the file can't be opened.
+bool quiche_conn_set_keylog_path(quiche_conn *conn, const char *path);
+
+// Returns true if the peer presented a certificate which was verified.
+bool quiche_conn_is_peer_verified(quiche_conn *conn);
+
 // Returns true if the connection handshake is complete.
 bool quiche_conn_is_established(quiche_conn *conn);
 
 // Returns true if the connection is closed.
 bool quiche_conn_is_closed(quiche_conn *conn);
 
+typedef struct {
+    // The number of QUIC packets sent on this connection.
+    size_t sent;
+
+    // The number of QUIC packets received on this connection.
+    size_t recv;
+
+    // The number of QUIC packets that were lost.
+    size_t lost;
+
+    // The number of bytes sent and received on this connection.
+    uint64_t sent_bytes;
+    uint64_t recv_bytes;
+
+    // The number of stream and crypto data bytes that were retransmitted.
+    uint64_t retrans_bytes;
+
+    // The smoothed, minimum (0 if unknown) and variation of the round-trip
+    // time, in nanoseconds.
+    uint64_t rtt;
+    uint64_t min_rtt;
+    uint64_t rttvar;
+
+    // The size of the congestion window and the bytes in flight.
+    size_t cwnd;
+    size_t bytes_in_flight;
+
+    // The number of consecutive PTO expirations.
+    uint32_t pto_count;
+
+    // The path MTU validated by path MTU discovery.
+    size_t pmtu;
+
+    // The number of streams opened by the local endpoint and by the peer.
+    size_t local_streams;
+    size_t peer_streams;
+
+    // The number of DATA_BLOCKED and STREAM_DATA_BLOCKED frames sent.
+    size_t data_blocked;
+    size_t stream_data_blocked;
+
+    // The time it took to complete the handshake in nanoseconds, or 0.
+    uint64_t handshake_duration;
+} quiche_stats;
+
 // Collects and returns statistics about the connection.
-void quiche_conn_stats_sent(quiche_conn *conn, uint64_t *out);
-void quiche_conn_stats_lost(quiche_conn *conn, uint64_t *out);
-void quiche_conn_stats_rtt_as_nanos(quiche_conn *conn, uint64_t *out);
+void quiche_conn_stats(quiche_conn *conn, quiche_stats *out);
 
 // Frees the connection object.
 void quiche_conn_free(quiche_conn *conn);
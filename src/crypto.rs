@@ -35,7 +35,7 @@ use crate::Result;
 
 use crate::octets;
 
-const INITIAL_SALT: [u8; 20] = [
+const INITIAL_SALT_DRAFT17: [u8; 20] = [
     0xef, 0x4f, 0xb0, 0xab, 0xb4, 0x74, 0x70, 0xc4, 0x1b, 0xef, 0xcf, 0x80, 0x31,
     0x33, 0x4f, 0xae, 0x48, 0x5e, 0x09, 0xa0,
 ];
@@ -67,6 +67,28 @@ pub enum Algorithm {
 }
 
 impl Algorithm {
+    /// Returns the algorithm of the TLS 1.3 cipher suite with the given IANA
+    /// name.
+    pub fn from_cipher_suite(name: &str) -> Option<Algorithm> {
+        match name {
+            "TLS_AES_128_GCM_SHA256" => Some(Algorithm::AES128_GCM),
+            "TLS_AES_256_GCM_SHA384" => Some(Algorithm::AES256_GCM),
+            "TLS_CHACHA20_POLY1305_SHA256" => Some(Algorithm::ChaCha20_Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Returns the IANA name of the TLS 1.3 cipher suite using the
+    /// algorithm.
+    pub fn cipher_suite(self) -> &'static str {
+        match self {
+            Algorithm::AES128_GCM => "TLS_AES_128_GCM_SHA256",
+            Algorithm::AES256_GCM => "TLS_AES_256_GCM_SHA384",
+            Algorithm::ChaCha20_Poly1305 => "TLS_CHACHA20_POLY1305_SHA256",
+            Algorithm::Null => "",
+        }
+    }
+
     fn get_ring_aead(self) -> &'static aead::Algorithm {
         match self {
             Algorithm::AES128_GCM => &aead::AES_128_GCM,
@@ -214,7 +236,7 @@ impl Seal {
 }
 
 pub fn derive_initial_key_material(
-    cid: &[u8], is_server: bool,
+    cid: &[u8], version: u32, is_server: bool,
 ) -> Result<(Open, Seal)> {
     let mut secret = [0; 32];
 
@@ -223,7 +245,7 @@ pub fn derive_initial_key_material(
     let key_len = aead.key_len();
     let nonce_len = aead.nonce_len();
 
-    let initial_secret = derive_initial_secret(&cid)?;
+    let initial_secret = derive_initial_secret(&cid, version)?;
 
     // Client.
     let mut client_key = vec![0; key_len];
@@ -260,8 +282,17 @@ pub fn derive_initial_key_material(
     Ok((open, seal))
 }
 
-fn derive_initial_secret(secret: &[u8]) -> Result<hmac::SigningKey> {
-    let salt = hmac::SigningKey::new(&digest::SHA256, &INITIAL_SALT);
+fn derive_initial_secret(
+    secret: &[u8], version: u32,
+) -> Result<hmac::SigningKey> {
+    let salt = match version {
+        // Draft-18 didn't change the salt.
+        crate::VERSION_DRAFT17 | crate::VERSION_DRAFT18 => &INITIAL_SALT_DRAFT17,
+
+        _ => return Err(Error::UnknownVersion),
+    };
+
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
     Ok(hkdf::extract(&salt, secret))
 }
 
@@ -379,7 +410,10 @@ mod tests {
 
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret = derive_initial_secret(&dcid).unwrap();
+        assert!(derive_initial_secret(&dcid, 0xbabababa).is_err());
+
+        let initial_secret =
+            derive_initial_secret(&dcid, crate::VERSION_DRAFT17).unwrap();
 
         // Client.
         assert!(
@@ -457,7 +491,8 @@ mod tests {
 
         let aead = Algorithm::AES128_GCM;
 
-        let initial_secret = derive_initial_secret(&dcid).unwrap();
+        let initial_secret =
+            derive_initial_secret(&dcid, crate::VERSION_DRAFT17).unwrap();
 
         // Client.
         assert!(
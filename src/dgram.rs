@@ -0,0 +1,102 @@
+// Copyright (C) 2019, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::collections::VecDeque;
+
+use crate::Error;
+use crate::Result;
+
+/// Bounded queue of DATAGRAM frame payloads.
+#[derive(Default)]
+pub struct DatagramQueue {
+    queue: VecDeque<Vec<u8>>,
+    max_len: usize,
+}
+
+impl DatagramQueue {
+    pub fn new(max_len: usize) -> DatagramQueue {
+        DatagramQueue {
+            queue: VecDeque::new(),
+            max_len,
+        }
+    }
+
+    /// Appends `data` to the back of the queue.
+    ///
+    /// Returns `Done` if the queue is full.
+    pub fn push(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.is_full() {
+            return Err(Error::Done);
+        }
+
+        self.queue.push_back(data);
+
+        Ok(())
+    }
+
+    /// Returns the length of the datagram at the front of the queue.
+    pub fn peek_front_len(&self) -> Option<usize> {
+        self.queue.front().map(Vec::len)
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.queue.pop_front()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.max_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let mut queue = DatagramQueue::new(2);
+        assert!(!queue.has_pending());
+        assert_eq!(queue.pop(), None);
+
+        assert_eq!(queue.push(b"hello".to_vec()), Ok(()));
+        assert_eq!(queue.push(b"world!".to_vec()), Ok(()));
+        assert!(queue.is_full());
+        assert_eq!(queue.push(b"again".to_vec()), Err(Error::Done));
+
+        assert_eq!(queue.peek_front_len(), Some(5));
+        assert_eq!(queue.pop(), Some(b"hello".to_vec()));
+
+        assert_eq!(queue.peek_front_len(), Some(6));
+        assert_eq!(queue.pop(), Some(b"world!".to_vec()));
+
+        assert!(!queue.has_pending());
+        assert_eq!(queue.peek_front_len(), None);
+    }
+}
@@ -101,14 +101,170 @@ pub extern fn quiche_config_load_priv_key_from_pem_file(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_config_load_cert_chain_from_pem(
+    config: &mut Config, buf: *const u8, buf_len: usize,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match config.load_cert_chain_from_pem(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_load_cert_chain_from_der(
+    config: &mut Config, certs: *const *const u8, certs_len: *const usize,
+    count: usize,
+) -> c_int {
+    let certs = unsafe { slice::from_raw_parts(certs, count) };
+    let certs_len = unsafe { slice::from_raw_parts(certs_len, count) };
+
+    let chain: Vec<&[u8]> = certs
+        .iter()
+        .zip(certs_len)
+        .map(|(&cert, &len)| unsafe { slice::from_raw_parts(cert, len) })
+        .collect();
+
+    match config.load_cert_chain_from_der(&chain) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_load_priv_key_from_pem(
+    config: &mut Config, buf: *const u8, buf_len: usize,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match config.load_priv_key_from_pem(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_load_priv_key_from_der(
+    config: &mut Config, buf: *const u8, buf_len: usize,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match config.load_priv_key_from_der(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_load_verify_locations_from_pem(
+    config: &mut Config, buf: *const u8, buf_len: usize,
+) -> c_int {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match config.load_verify_locations_from_pem(buf) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_cipher_suites(
+    config: &mut Config, suites: *const c_char,
+) -> c_int {
+    let suites = unsafe { ffi::CStr::from_ptr(suites).to_str().unwrap() };
+    let suites: Vec<&str> = suites.split(':').collect();
+
+    match config.set_cipher_suites(&suites) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_versions(
+    config: &mut Config, versions: *const u32, versions_len: usize,
+) -> c_int {
+    let versions = unsafe { slice::from_raw_parts(versions, versions_len) };
+
+    match config.set_versions(versions) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_groups(
+    config: &mut Config, groups: *const c_char,
+) -> c_int {
+    let groups = unsafe { ffi::CStr::from_ptr(groups).to_str().unwrap() };
+    let groups: Vec<&str> = groups.split(':').collect();
+
+    match config.set_groups(&groups) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_ticket_keys(
+    config: &mut Config, keys: *const u8, keys_len: usize,
+) -> c_int {
+    let keys = unsafe { slice::from_raw_parts(keys, keys_len) };
+    let keys: Vec<&[u8]> = keys.chunks(48).collect();
+
+    match config.set_ticket_keys(&keys) {
+        Ok(_) => 0,
+
+        Err(e) => e.to_c() as c_int,
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_session_tickets(config: &mut Config, v: bool) {
+    config.enable_session_tickets(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_client_auth(config: &mut Config, mode: u8) {
+    let mode = match mode {
+        1 => ClientAuth::Request,
+
+        2 => ClientAuth::Require,
+
+        _ => ClientAuth::None,
+    };
+
+    config.set_client_auth(mode);
+}
+
 #[no_mangle]
 pub extern fn quiche_config_verify_peer(config: &mut Config, v: bool) {
     config.verify_peer(v);
 }
 
 #[no_mangle]
-pub extern fn quiche_config_log_keys(config: &mut Config) {
-    config.log_keys();
+pub extern fn quiche_config_log_keys(
+    config: &mut Config, path: *const c_char,
+) -> bool {
+    match open_keylog(path) {
+        Some(f) => {
+            config.log_keys(Box::new(f));
+
+            true
+        },
+
+        None => false,
+    }
 }
 
 #[no_mangle]
@@ -176,6 +332,46 @@ pub extern fn quiche_config_set_disable_migration(config: &mut Config, v: bool)
     config.set_disable_migration(v);
 }
 
+#[no_mangle]
+pub extern fn quiche_config_set_max_connection_window(
+    config: &mut Config, v: u64,
+) {
+    config.set_max_connection_window(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_max_stream_window(config: &mut Config, v: u64) {
+    config.set_max_stream_window(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_ack_frequency(config: &mut Config, v: usize) {
+    config.set_ack_frequency(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_ecn(config: &mut Config, v: bool) {
+    config.enable_ecn(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_pmtud(config: &mut Config, v: bool) {
+    config.enable_pmtud(v);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_enable_dgram(
+    config: &mut Config, enabled: bool, recv_queue_len: usize,
+    send_queue_len: usize,
+) {
+    config.enable_dgram(enabled, recv_queue_len, send_queue_len);
+}
+
+#[no_mangle]
+pub extern fn quiche_config_set_rng_seed(config: &mut Config, seed: u64) {
+    config.set_rng(SeededRng::new(seed));
+}
+
 #[no_mangle]
 pub extern fn quiche_config_free(config: *mut Config) {
     unsafe { Box::from_raw(config) };
@@ -286,16 +482,21 @@ pub extern fn quiche_connect(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_version_is_supported(config: &Config, version: u32) -> bool {
+    version_is_supported(config, version)
+}
+
 #[no_mangle]
 pub extern fn quiche_negotiate_version(
     scid: *const u8, scid_len: usize, dcid: *const u8, dcid_len: usize,
-    out: *mut u8, out_len: usize,
+    config: &Config, out: *mut u8, out_len: usize,
 ) -> ssize_t {
     let scid = unsafe { slice::from_raw_parts(scid, scid_len) };
     let dcid = unsafe { slice::from_raw_parts(dcid, dcid_len) };
     let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
 
-    match negotiate_version(scid, dcid, out) {
+    match negotiate_version(scid, dcid, config, out) {
         Ok(v) => v as ssize_t,
 
         Err(e) => e.to_c(),
@@ -321,6 +522,7 @@ pub extern fn quiche_retry(
     }
 }
 
+#[cfg(not(feature = "no_bssl"))]
 #[no_mangle]
 pub extern fn quiche_conn_new_with_tls(
     scid: *const u8, scid_len: usize, odcid: *const u8, odcid_len: usize,
@@ -369,6 +571,42 @@ pub extern fn quiche_conn_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_send_batch(
+    conn: &mut Connection, out: *mut u8, out_len: usize, max_packets: usize,
+    segment_size: &mut usize,
+) -> ssize_t {
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.send_batch(out, max_packets) {
+        Ok((size, count)) => {
+            *segment_size = size;
+
+            count as ssize_t
+        },
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_recv_with_ecn(
+    conn: &mut Connection, buf: *mut u8, buf_len: usize, ecn: u8,
+) -> ssize_t {
+    let buf = unsafe { slice::from_raw_parts_mut(buf, buf_len) };
+
+    match conn.recv_with_ecn(buf, Ecn::from_bits(ecn)) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_ecn_marking(conn: &Connection) -> u8 {
+    conn.ecn_marking() as u8
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_stream_recv(
     conn: &mut Connection, stream_id: u64, out: *mut u8, out_len: usize,
@@ -402,6 +640,32 @@ pub extern fn quiche_conn_stream_send(
     }
 }
 
+#[no_mangle]
+pub extern fn quiche_conn_dgram_recv(
+    conn: &mut Connection, out: *mut u8, out_len: usize,
+) -> ssize_t {
+    let out = unsafe { slice::from_raw_parts_mut(out, out_len) };
+
+    match conn.dgram_recv(out) {
+        Ok(v) => v as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_dgram_send(
+    conn: &mut Connection, buf: *const u8, buf_len: usize,
+) -> ssize_t {
+    let buf = unsafe { slice::from_raw_parts(buf, buf_len) };
+
+    match conn.dgram_send(buf) {
+        Ok(_) => buf_len as ssize_t,
+
+        Err(e) => e.to_c(),
+    }
+}
+
 #[no_mangle]
 pub extern fn quiche_conn_readable(conn: &mut Connection) -> *mut Readable {
     let iter = conn.readable();
@@ -455,33 +719,131 @@ pub extern fn quiche_conn_on_timeout(conn: &mut Connection) {
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_is_established(conn: &mut Connection) -> bool {
-    conn.is_established()
+pub extern fn quiche_conn_server_name(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut usize,
+) {
+    match conn.server_name() {
+        Some(name) => {
+            *out = name.as_ptr();
+            *out_len = name.len();
+        },
+
+        None => {
+            *out = ptr::null();
+            *out_len = 0;
+        },
+    }
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_is_closed(conn: &mut Connection) -> bool {
-    conn.is_closed()
+pub extern fn quiche_conn_peer_cert(
+    conn: &mut Connection, out: &mut *const u8, out_len: &mut usize,
+) {
+    match conn.peer_cert() {
+        Some(cert) => {
+            *out = cert.as_ptr();
+            *out_len = cert.len();
+        },
+
+        None => {
+            *out = ptr::null();
+            *out_len = 0;
+        },
+    }
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_set_keylog_path(
+    conn: &mut Connection, path: *const c_char,
+) -> bool {
+    match open_keylog(path) {
+        Some(f) => {
+            conn.set_keylog(Box::new(f));
+
+            true
+        },
+
+        None => false,
+    }
+}
+
+/// Opens the keylog file at `path` for appending.
+fn open_keylog(path: *const c_char) -> Option<std::fs::File> {
+    let path = unsafe { ffi::CStr::from_ptr(path).to_str().ok()? };
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .ok()
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_stats_sent(conn: &Connection, out: &mut u64) {
-    *out = conn.stats().sent as u64;
+pub extern fn quiche_conn_is_peer_verified(conn: &mut Connection) -> bool {
+    conn.is_peer_verified()
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_stats_lost(conn: &Connection, out: &mut u64) {
-    *out = conn.stats().lost as u64;
+pub extern fn quiche_conn_is_established(conn: &mut Connection) -> bool {
+    conn.is_established()
 }
 
 #[no_mangle]
-pub extern fn quiche_conn_stats_rtt_as_nanos(conn: &Connection, out: &mut u64) {
-    let rtt = conn.stats().rtt;
+pub extern fn quiche_conn_is_closed(conn: &mut Connection) -> bool {
+    conn.is_closed()
+}
 
-    *out = rtt.as_secs() * 1_000_000_000 + u64::from(rtt.subsec_nanos());
+#[repr(C)]
+pub struct CStats {
+    sent: usize,
+    recv: usize,
+    lost: usize,
+    sent_bytes: u64,
+    recv_bytes: u64,
+    retrans_bytes: u64,
+    rtt: u64,
+    min_rtt: u64,
+    rttvar: u64,
+    cwnd: usize,
+    bytes_in_flight: usize,
+    pto_count: u32,
+    pmtu: usize,
+    local_streams: usize,
+    peer_streams: usize,
+    data_blocked: usize,
+    stream_data_blocked: usize,
+    handshake_duration: u64,
+}
+
+#[no_mangle]
+pub extern fn quiche_conn_stats(conn: &Connection, out: &mut CStats) {
+    let stats = conn.stats();
+
+    out.sent = stats.sent;
+    out.recv = stats.recv;
+    out.lost = stats.lost;
+    out.sent_bytes = stats.sent_bytes;
+    out.recv_bytes = stats.recv_bytes;
+    out.retrans_bytes = stats.retrans_bytes;
+    out.rtt = as_nanos(stats.rtt);
+    out.min_rtt = stats.min_rtt.map_or(0, as_nanos);
+    out.rttvar = as_nanos(stats.rttvar);
+    out.cwnd = stats.cwnd;
+    out.bytes_in_flight = stats.bytes_in_flight;
+    out.pto_count = stats.pto_count;
+    out.pmtu = stats.pmtu;
+    out.local_streams = stats.local_streams;
+    out.peer_streams = stats.peer_streams;
+    out.data_blocked = stats.data_blocked;
+    out.stream_data_blocked = stats.stream_data_blocked;
+    out.handshake_duration = stats.handshake_duration.map_or(0, as_nanos);
 }
 
 #[no_mangle]
 pub extern fn quiche_conn_free(conn: *mut Connection) {
     unsafe { Box::from_raw(conn) };
 }
+
+fn as_nanos(d: std::time::Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
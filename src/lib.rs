@@ -177,9 +177,21 @@ use std::cmp;
 use std::mem;
 use std::time;
 
-/// The current QUIC wire version.
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::tls::TlsContext;
+use crate::tls::TlsHandshake;
+
+/// The QUIC wire version of draft-17.
 pub const VERSION_DRAFT17: u32 = 0xff00_0011;
 
+/// The QUIC wire version of draft-18.
+pub const VERSION_DRAFT18: u32 = 0xff00_0012;
+
+/// The supported QUIC wire versions, in order of preference.
+const SUPPORTED_VERSIONS: [u32; 2] = [VERSION_DRAFT18, VERSION_DRAFT17];
+
 /// The maximum length of a connection ID.
 pub const MAX_CONN_ID_LEN: usize = crate::packet::MAX_CID_LEN as usize;
 
@@ -187,6 +199,24 @@ const CLIENT_INITIAL_MIN_LEN: usize = 1200;
 
 const PAYLOAD_MIN_LEN: usize = 4;
 
+// The largest packet size that can be sent, so that it can be always encoded
+// with a 2-byte varint.
+const MAX_SEND_PKT_LEN: usize = 16383;
+
+// The default maximum size of the connection-level receive window.
+const DEFAULT_MAX_CONNECTION_WINDOW: u64 = 24 * 1024 * 1024;
+
+// The default maximum size of a stream's receive window.
+const DEFAULT_MAX_STREAM_WINDOW: u64 = 16 * 1024 * 1024;
+
+// The value of the `max_datagram_frame_size` transport parameter advertised
+// when DATAGRAM frames are enabled.
+const MAX_DGRAM_FRAME_SIZE: u64 = 65536;
+
+// The default number of ack-eliciting packets received before an ACK is sent
+// without waiting for the ACK timer.
+const DEFAULT_ACK_FREQUENCY: usize = 2;
+
 // TODO: calculate draining timer as 3 * RTO
 const DRAINING_TIMEOUT: time::Duration = time::Duration::from_millis(200);
 
@@ -312,15 +342,91 @@ impl std::error::Error for Error {
     }
 }
 
+/// An Explicit Congestion Notification codepoint.
+///
+/// The values match the two ECN bits of the IP header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub enum Ecn {
+    /// Not ECN-capable transport.
+    NotEct = 0,
+
+    /// ECN-capable transport, ECT(1).
+    Ect1   = 1,
+
+    /// ECN-capable transport, ECT(0).
+    Ect0   = 2,
+
+    /// Congestion experienced.
+    Ce     = 3,
+}
+
+impl Ecn {
+    /// Returns the codepoint corresponding to the two least significant bits
+    /// of `bits`, e.g. the IP header's TOS or traffic class field.
+    pub fn from_bits(bits: u8) -> Ecn {
+        match bits & 0x03 {
+            0x01 => Ecn::Ect1,
+            0x02 => Ecn::Ect0,
+            0x03 => Ecn::Ce,
+            _ => Ecn::NotEct,
+        }
+    }
+}
+
+/// A callback selecting the server's configuration for a server name.
+type ServerNameCallback = dyn Fn(&str) -> Option<Arc<Config>>;
+
+/// A callback verifying the peer's certificate chain.
+type VerifyCallback = dyn Fn(&[&[u8]], Option<&str>) -> bool;
+
+/// Whether a server requests a certificate from clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub enum ClientAuth {
+    /// Clients are not asked for a certificate.
+    None    = 0,
+
+    /// Clients are asked for a certificate, which is verified if sent.
+    Request = 1,
+
+    /// Clients must send a valid certificate, or the handshake fails.
+    Require = 2,
+}
+
 /// Stores configuration shared between multiple connections.
 pub struct Config {
     local_transport_params: TransportParams,
 
     version: u32,
 
+    versions: Vec<u32>,
+
     tls_ctx: tls::Context,
 
     application_protos: Vec<Vec<u8>>,
+
+    ticket_keys: Vec<[u8; tls::TICKET_KEY_LEN]>,
+
+    server_name_cb: Option<Arc<ServerNameCallback>>,
+
+    verify_cb: Option<Arc<VerifyCallback>>,
+
+    keylog: Option<Arc<Mutex<Box<dyn std::io::Write + Send>>>>,
+
+    rng: Arc<Mutex<dyn Rng>>,
+
+    max_connection_window: u64,
+    max_stream_window: u64,
+
+    dgram_recv_max_queue_len: usize,
+    dgram_send_max_queue_len: usize,
+
+    ack_frequency: usize,
+
+    ecn: bool,
+
+    pmtud: bool,
 }
 
 impl Config {
@@ -331,8 +437,21 @@ impl Config {
         Ok(Config {
             local_transport_params: TransportParams::default(),
             version,
+            versions: SUPPORTED_VERSIONS.to_vec(),
             tls_ctx,
             application_protos: Vec::new(),
+            ticket_keys: Vec::new(),
+            server_name_cb: None,
+            verify_cb: None,
+            keylog: None,
+            rng: Arc::new(Mutex::new(rand::SystemRng::default())),
+            max_connection_window: DEFAULT_MAX_CONNECTION_WINDOW,
+            max_stream_window: DEFAULT_MAX_STREAM_WINDOW,
+            dgram_recv_max_queue_len: 0,
+            dgram_send_max_queue_len: 0,
+            ack_frequency: DEFAULT_ACK_FREQUENCY,
+            ecn: false,
+            pmtud: false,
         })
     }
 
@@ -340,6 +459,11 @@ impl Config {
     ///
     /// The content of `file` is parsed as a PEM-encoded leaf certificate,
     /// followed by optional intermediate certificates.
+    ///
+    /// On clients, the certificate is sent to servers that request one (see
+    /// [`set_client_auth()`]).
+    ///
+    /// [`set_client_auth()`]: struct.Config.html#method.set_client_auth
     pub fn load_cert_chain_from_pem_file(&mut self, file: &str) -> Result<()> {
         self.tls_ctx
             .use_certificate_chain_file(file)
@@ -355,20 +479,211 @@ impl Config {
             .map_err(|_| Error::TlsFail)
     }
 
+    /// Configures the given certificate chain from memory.
+    ///
+    /// The content of `buf` is parsed as a PEM-encoded leaf certificate,
+    /// followed by optional intermediate certificates.
+    pub fn load_cert_chain_from_pem(&mut self, buf: &[u8]) -> Result<()> {
+        self.tls_ctx
+            .use_certificate_chain_pem(buf)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the given certificate chain from memory.
+    ///
+    /// Each element of `chain` is parsed as a DER-encoded certificate, the
+    /// first one being the leaf certificate, followed by optional
+    /// intermediate certificates.
+    pub fn load_cert_chain_from_der(&mut self, chain: &[&[u8]]) -> Result<()> {
+        self.tls_ctx
+            .use_certificate_chain_der(chain)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the given private key from memory.
+    ///
+    /// The content of `buf` is parsed as a PEM-encoded private key.
+    pub fn load_priv_key_from_pem(&mut self, buf: &[u8]) -> Result<()> {
+        self.tls_ctx
+            .use_privkey_pem(buf)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the given private key from memory.
+    ///
+    /// The content of `buf` is parsed as a DER-encoded private key, either
+    /// in PKCS#8 or in its algorithm-specific format.
+    pub fn load_priv_key_from_der(&mut self, buf: &[u8]) -> Result<()> {
+        self.tls_ctx
+            .use_privkey_der(buf)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Adds the given trusted CA certificates from memory.
+    ///
+    /// The content of `buf` is parsed as one or more PEM-encoded
+    /// certificates, which are used to verify the peer's certificate chain
+    /// when [`verify_peer()`] is enabled, in addition to the system's default
+    /// trust store.
+    ///
+    /// [`verify_peer()`]: struct.Config.html#method.verify_peer
+    pub fn load_verify_locations_from_pem(&mut self, buf: &[u8]) -> Result<()> {
+        self.tls_ctx
+            .load_verify_locations_pem(buf)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Sets the callback used by servers to select a certificate based on
+    /// the server name (SNI) requested by the client.
+    ///
+    /// The callback is invoked during the handshake with the name sent by the
+    /// client, if any. When it returns a `Config`, the certificate chain and
+    /// private key configured on it are used for the connection instead of
+    /// the ones of this config. Other settings, such as the transport
+    /// parameters, are still taken from this config.
+    pub fn set_server_name_callback<F>(&mut self, cb: F)
+    where
+        F: Fn(&str) -> Option<Arc<Config>> + 'static,
+    {
+        self.server_name_cb = Some(Arc::new(cb));
+    }
+
+    /// Sets a callback used to verify the peer's certificate chain, instead
+    /// of the default verification against the trusted CA certificates.
+    ///
+    /// The callback is invoked during the handshake with the DER-encoded
+    /// certificates sent by the peer, starting with the leaf certificate, and
+    /// with the server name requested by the client, if any. The handshake
+    /// fails if the callback returns false.
+    ///
+    /// Setting a callback enables verification of the peer's certificate, as
+    /// with [`verify_peer()`]. Calling [`verify_peer()`] or
+    /// [`set_client_auth()`] afterwards changes whether the peer is verified,
+    /// but the callback is still used to do it.
+    ///
+    /// [`verify_peer()`]: struct.Config.html#method.verify_peer
+    /// [`set_client_auth()`]: struct.Config.html#method.set_client_auth
+    pub fn set_verify_callback<F>(&mut self, cb: F)
+    where
+        F: Fn(&[&[u8]], Option<&str>) -> bool + 'static,
+    {
+        self.verify_cb = Some(Arc::new(cb));
+
+        self.tls_ctx.set_custom_verify();
+    }
+
+    /// Configures the TLS 1.3 cipher suites allowed on connections.
+    ///
+    /// The `suites` parameter is a list of IANA cipher suite names, i.e.
+    /// `TLS_AES_128_GCM_SHA256`, `TLS_AES_256_GCM_SHA384` and
+    /// `TLS_CHACHA20_POLY1305_SHA256`. All of them are allowed by default.
+    ///
+    /// With BoringSSL the suites offered can't be restricted, so instead the
+    /// handshake fails with a `handshake_failure` alert when the negotiated
+    /// suite isn't allowed.
+    pub fn set_cipher_suites(&mut self, suites: &[&str]) -> Result<()> {
+        let suites = suites
+            .iter()
+            .map(|s| crypto::Algorithm::from_cipher_suite(s))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::TlsFail)?;
+
+        if suites.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        self.tls_ctx
+            .set_cipher_suites(&suites)
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the key exchange groups to use, in order of preference.
+    ///
+    /// The `groups` parameter is a list of group names, such as `X25519`,
+    /// `P-256` and `P-384`.
+    pub fn set_groups(&mut self, groups: &[&str]) -> Result<()> {
+        self.tls_ctx
+            .set_groups(&groups.join(":"))
+            .map_err(|_| Error::TlsFail)
+    }
+
+    /// Configures the keys used by servers to encrypt session tickets.
+    ///
+    /// Each key is 48 bytes long: a 16 bytes key name, followed by a 16 bytes
+    /// HMAC-SHA256 key and a 16 bytes AES-128 key. The first key is used to
+    /// issue new tickets, while the others are only used to decrypt tickets
+    /// issued with previous keys, which get renewed.
+    ///
+    /// Sharing the keys between servers allows clients to resume sessions on
+    /// any of them. Keys can be rotated by calling this again with a new key
+    /// followed by the previous ones, which only affects new connections.
+    ///
+    /// By default random keys are generated for each config.
+    pub fn set_ticket_keys(&mut self, keys: &[&[u8]]) -> Result<()> {
+        if keys.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        let mut ticket_keys = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            if key.len() != tls::TICKET_KEY_LEN {
+                return Err(Error::TlsFail);
+            }
+
+            let mut k = [0; tls::TICKET_KEY_LEN];
+            k.copy_from_slice(key);
+
+            ticket_keys.push(k);
+        }
+
+        self.tls_ctx
+            .set_ticket_key_callback()
+            .map_err(|_| Error::TlsFail)?;
+
+        self.ticket_keys = ticket_keys;
+
+        Ok(())
+    }
+
+    /// Configures whether servers issue session tickets.
+    ///
+    /// When disabled, clients can't resume sessions. The default is `true`.
+    pub fn enable_session_tickets(&mut self, v: bool) {
+        self.tls_ctx.set_session_tickets(v);
+    }
+
+    /// Configures whether servers request a certificate from clients.
+    ///
+    /// Clients' certificates are verified in the same way as servers', that
+    /// is against the trusted CA certificates, or by the callback set with
+    /// [`set_verify_callback()`]. On servers, this and [`verify_peer()`] both
+    /// set whether a certificate is requested, and the last one called takes
+    /// effect.
+    ///
+    /// The default is `ClientAuth::None`.
+    ///
+    /// [`set_verify_callback()`]: struct.Config.html#method.set_verify_callback
+    /// [`verify_peer()`]: struct.Config.html#method.verify_peer
+    pub fn set_client_auth(&mut self, mode: ClientAuth) {
+        self.tls_ctx.set_client_auth(mode);
+    }
+
     /// Configures whether to verify the peer's certificate.
     pub fn verify_peer(&mut self, verify: bool) {
         self.tls_ctx.set_verify(verify);
     }
 
-    /// Enables logging of secrets.
+    /// Enables logging of secrets to the given writer.
     ///
-    /// A connection's cryptographic secrets will be logged in the [keylog]
-    /// format in the file pointed to by the `SSLKEYLOGFILE` environment
-    /// variable.
+    /// The cryptographic secrets of all connections created with this config
+    /// will be logged in the [keylog] format to `writer`, unless a connection
+    /// sets its own writer with [`set_keylog()`].
     ///
     /// [keylog]: https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
-    pub fn log_keys(&mut self) {
-        self.tls_ctx.enable_keylog();
+    /// [`set_keylog()`]: struct.Connection.html#method.set_keylog
+    pub fn log_keys(&mut self, writer: Box<dyn std::io::Write + Send>) {
+        self.keylog = Some(Arc::new(Mutex::new(writer)));
     }
 
     /// Configures the list of support application protocolos.
@@ -447,12 +762,119 @@ impl Config {
     pub fn set_disable_migration(&mut self, v: bool) {
         self.local_transport_params.disable_migration = v;
     }
+
+    /// Sets the maximum size of the connection-level receive window.
+    ///
+    /// The window starts at `initial_max_data` and is grown up to this value
+    /// when the application reads data faster than the window allows the
+    /// peer to send it. The default value is 24 MiB.
+    pub fn set_max_connection_window(&mut self, v: u64) {
+        self.max_connection_window = v;
+    }
+
+    /// Sets the maximum size of a stream's receive window.
+    ///
+    /// The window starts at the relevant `initial_max_stream_data_*` value
+    /// and is grown up to this value when the application reads data faster
+    /// than the window allows the peer to send it. The default value is
+    /// 16 MiB.
+    pub fn set_max_stream_window(&mut self, v: u64) {
+        self.max_stream_window = v;
+    }
+
+    /// Sets the number of ack-eliciting packets to receive before sending an
+    /// ACK frame.
+    ///
+    /// Until this many packets are received, the ACK is delayed by up to
+    /// `max_ack_delay`, unless packets arrive out of order. A value of `1`
+    /// disables delayed acknowledgements. The default value is `2`.
+    pub fn set_ack_frequency(&mut self, v: usize) {
+        self.ack_frequency = cmp::max(v, 1);
+    }
+
+    /// Configures whether to mark outgoing packets as ECN-capable.
+    ///
+    /// When enabled, [`ecn_marking()`] returns the codepoint the application
+    /// should set on outgoing packets, as long as the path is validated as
+    /// ECN-capable.
+    ///
+    /// [`ecn_marking()`]: struct.Connection.html#method.ecn_marking
+    pub fn enable_ecn(&mut self, v: bool) {
+        self.ecn = v;
+    }
+
+    /// Configures whether to enable path MTU discovery.
+    ///
+    /// When enabled, packets are limited to the path MTU validated by sending
+    /// probes of increasing size, starting from the minimum QUIC packet size.
+    /// The search is bounded by the peer's `max_packet_size` transport
+    /// parameter, capped at 16383 bytes. Probes are only sent when the buffer
+    /// passed to [`send()`] is large enough to hold them.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn enable_pmtud(&mut self, v: bool) {
+        self.pmtud = v;
+    }
+
+    /// Configures whether to enable sending and receiving DATAGRAM frames.
+    ///
+    /// When enabled, the `max_datagram_frame_size` transport parameter is
+    /// advertised, and up to `recv_queue_len` received and `send_queue_len`
+    /// outgoing datagrams are buffered by each connection.
+    pub fn enable_dgram(
+        &mut self, enabled: bool, recv_queue_len: usize, send_queue_len: usize,
+    ) {
+        self.local_transport_params.max_datagram_frame_size = if enabled {
+            Some(MAX_DGRAM_FRAME_SIZE)
+        } else {
+            None
+        };
+
+        self.dgram_recv_max_queue_len = recv_queue_len;
+        self.dgram_send_max_queue_len = send_queue_len;
+    }
+
+    /// Sets the QUIC versions supported by connections, in order of
+    /// preference.
+    ///
+    /// When receiving a Version Negotiation packet, clients switch to the
+    /// first of these versions that is also offered by the server. Servers
+    /// accept connections using any of them. By default all the versions
+    /// supported by the library are enabled.
+    ///
+    /// An error is returned if the list is empty or contains an unsupported
+    /// version.
+    pub fn set_versions(&mut self, versions: &[u32]) -> Result<()> {
+        if versions.is_empty() ||
+            !versions.iter().all(|v| SUPPORTED_VERSIONS.contains(v))
+        {
+            return Err(Error::UnknownVersion);
+        }
+
+        self.versions = versions.to_vec();
+
+        Ok(())
+    }
+
+    /// Sets the RNG used by connections created with this config.
+    ///
+    /// The RNG is shared by all of the connections, and it's only used for
+    /// values sent on the wire, like connection IDs. By default the system's
+    /// secure RNG is used. A [`SeededRng`] can be used to make connections
+    /// reproducible, e.g. when fuzzing.
+    ///
+    /// [`SeededRng`]: struct.SeededRng.html
+    pub fn set_rng<R: Rng + 'static>(&mut self, rng: R) {
+        self.rng = Arc::new(Mutex::new(rng));
+    }
 }
 
 /// A QUIC connection.
 pub struct Connection {
     version: u32,
 
+    versions: Vec<u32>,
+
     dcid: Vec<u8>,
     scid: Vec<u8>,
 
@@ -472,18 +894,57 @@ pub struct Connection {
 
     application_protos: Vec<Vec<u8>>,
 
+    ticket_keys: Vec<[u8; tls::TICKET_KEY_LEN]>,
+
+    server_name_cb: Option<Arc<ServerNameCallback>>,
+
+    verify_cb: Option<Arc<VerifyCallback>>,
+
     sent_count: usize,
+    recv_count: usize,
     lost_count: usize,
 
+    sent_bytes: u64,
+    recv_bytes: u64,
+    retrans_bytes: u64,
+
+    data_blocked_count: usize,
+    stream_data_blocked_count: usize,
+
+    start_time: Option<time::Instant>,
+    handshake_duration: Option<time::Duration>,
+
     rx_data: usize,
     max_rx_data: usize,
     new_max_rx_data: usize,
 
+    rx_window: usize,
+    max_rx_window: usize,
+    last_rx_update: Option<time::Instant>,
+
     tx_data: usize,
     max_tx_data: usize,
 
+    blocked_limit: Option<usize>,
+
     streams: stream::StreamMap,
 
+    dgram_recv_queue: dgram::DatagramQueue,
+    dgram_send_queue: dgram::DatagramQueue,
+
+    ack_frequency: usize,
+
+    ecn_enabled: bool,
+    ecn_failed: bool,
+
+    pmtud: pmtud::Pmtud,
+
+    qlog: Option<qlog::QlogStreamer>,
+
+    observer: Option<Box<dyn ConnectionObserver>>,
+
+    keylog: Option<Arc<Mutex<Box<dyn std::io::Write + Send>>>>,
+
     odcid: Option<Vec<u8>>,
 
     token: Option<Vec<u8>>,
@@ -511,6 +972,8 @@ pub struct Connection {
 
     handshake_completed: bool,
 
+    path_validated: bool,
+
     draining: bool,
 
     closed: bool,
@@ -540,7 +1003,7 @@ pub fn accept(
 pub fn connect(
     server_name: Option<&str>, scid: &[u8], config: &mut Config,
 ) -> Result<Box<Connection>> {
-    let conn = Connection::new(scid, None, config, false)?;
+    let mut conn = Connection::new(scid, None, config, false)?;
 
     if server_name.is_some() {
         conn.tls_state
@@ -551,15 +1014,25 @@ pub fn connect(
     Ok(conn)
 }
 
-/// Writes a version negotiation packet.
+/// Returns true if the given protocol version is enabled by the config.
+///
+/// See [`set_versions()`].
+///
+/// [`set_versions()`]: struct.Config.html#method.set_versions
+pub fn version_is_supported(config: &Config, version: u32) -> bool {
+    config.versions.contains(&version)
+}
+
+/// Writes a version negotiation packet offering the versions enabled by the
+/// config.
 ///
 /// The `scid` and `dcid` parameters are the source connection ID and the
 /// destination connection ID extracted from the received client's Initial
 /// packet that advertises an unsupported version.
 pub fn negotiate_version(
-    scid: &[u8], dcid: &[u8], out: &mut [u8],
+    scid: &[u8], dcid: &[u8], config: &Config, out: &mut [u8],
 ) -> Result<usize> {
-    packet::negotiate_version(scid, dcid, out)
+    packet::negotiate_version(scid, dcid, &config.versions, out)
 }
 
 /// Writes a stateless retry packet.
@@ -603,6 +1076,8 @@ impl Connection {
         let mut conn = Box::new(Connection {
             version: config.version,
 
+            versions: config.versions.clone(),
+
             dcid: Vec::new(),
             scid: scid.to_vec(),
 
@@ -622,18 +1097,62 @@ impl Connection {
 
             application_protos: config.application_protos.clone(),
 
+            ticket_keys: config.ticket_keys.clone(),
+
+            server_name_cb: config.server_name_cb.clone(),
+
+            verify_cb: config.verify_cb.clone(),
+
             sent_count: 0,
+            recv_count: 0,
             lost_count: 0,
 
+            sent_bytes: 0,
+            recv_bytes: 0,
+            retrans_bytes: 0,
+
+            data_blocked_count: 0,
+            stream_data_blocked_count: 0,
+
+            start_time: None,
+            handshake_duration: None,
+
             rx_data: 0,
             max_rx_data: max_rx_data as usize,
             new_max_rx_data: max_rx_data as usize,
 
+            rx_window: max_rx_data as usize,
+            max_rx_window: cmp::max(max_rx_data, config.max_connection_window)
+                as usize,
+            last_rx_update: None,
+
             tx_data: 0,
             max_tx_data: 0,
 
+            blocked_limit: None,
+
             streams: stream::StreamMap::default(),
 
+            dgram_recv_queue: dgram::DatagramQueue::new(
+                config.dgram_recv_max_queue_len,
+            ),
+            dgram_send_queue: dgram::DatagramQueue::new(
+                config.dgram_send_max_queue_len,
+            ),
+
+            ack_frequency: config.ack_frequency,
+
+            ecn_enabled: config.ecn,
+            ecn_failed: false,
+
+            pmtud: pmtud::Pmtud::new(config.pmtud, MAX_SEND_PKT_LEN),
+
+            qlog: None,
+
+            observer: None,
+
+            keylog: config.keylog.clone(),
+
             odcid: None,
 
             token: None,
@@ -661,6 +1180,8 @@ impl Connection {
 
             handshake_completed: false,
 
+            path_validated: false,
+
             draining: false,
 
             closed: false,
@@ -673,6 +1194,8 @@ impl Connection {
 
         conn.tls_state.init(&conn).map_err(|_| Error::TlsFail)?;
 
+        conn.set_transport_params()?;
+
         conn.streams.update_local_max_streams_bidi(
             config.local_transport_params.initial_max_streams_bidi as usize,
         );
@@ -681,14 +1204,29 @@ impl Connection {
             config.local_transport_params.initial_max_streams_uni as usize,
         );
 
+        conn.streams
+            .set_max_stream_window(config.max_stream_window as usize);
+
         // Derive initial secrets for the client. We can do this here because
         // we already generated the random destination connection ID.
         if !is_server {
             let mut dcid = [0; 16];
-            rand::rand_bytes(&mut dcid[..]);
+            config.rng.lock().unwrap().fill(&mut dcid[..]);
+
+            // Clients can start with an unsupported version to trigger
+            // version negotiation. Servers can't decrypt such a packet
+            // anyway, so protect it with the keys of our preferred version.
+            let initial_version = if conn.versions.contains(&conn.version) {
+                conn.version
+            } else {
+                conn.versions[0]
+            };
 
-            let (aead_open, aead_seal) =
-                crypto::derive_initial_key_material(&dcid, conn.is_server)?;
+            let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &dcid,
+                initial_version,
+                conn.is_server,
+            )?;
 
             conn.dcid.extend_from_slice(&dcid);
 
@@ -713,6 +1251,43 @@ impl Connection {
     ///
     /// [`Done`]: enum.Error.html#variant.Done
     pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.recv_at(buf, time::Instant::now())
+    }
+
+    /// Processes QUIC packets received from the peer at the given time.
+    ///
+    /// This is the same as [`recv()`], except that `now` is used as the
+    /// current time instead of reading the system clock, which allows driving
+    /// the connection with simulated time.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    pub fn recv_at(
+        &mut self, buf: &mut [u8], now: time::Instant,
+    ) -> Result<usize> {
+        self.recv_with_ecn_at(buf, Ecn::NotEct, now)
+    }
+
+    /// Processes QUIC packets received from the peer with the given ECN
+    /// codepoint.
+    ///
+    /// This is the same as [`recv()`], but `ecn` is the ECN codepoint of the
+    /// IP packet that carried `buf`, which is reported back to the peer.
+    ///
+    /// [`recv()`]: struct.Connection.html#method.recv
+    pub fn recv_with_ecn(&mut self, buf: &mut [u8], ecn: Ecn) -> Result<usize> {
+        self.recv_with_ecn_at(buf, ecn, time::Instant::now())
+    }
+
+    /// Processes QUIC packets received from the peer with the given ECN
+    /// codepoint, at the given time.
+    ///
+    /// See [`recv_with_ecn()`] and [`recv_at()`].
+    ///
+    /// [`recv_with_ecn()`]: struct.Connection.html#method.recv_with_ecn
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn recv_with_ecn_at(
+        &mut self, buf: &mut [u8], ecn: Ecn, now: time::Instant,
+    ) -> Result<usize> {
         let len = buf.len();
 
         let mut done = 0;
@@ -720,7 +1295,9 @@ impl Connection {
 
         // Process coalesced packets.
         while left > 0 {
-            let read = self.recv_single(&mut buf[len - left..len])?;
+            let read = self.recv_single(&mut buf[len - left..len], ecn, now)?;
+
+            self.recv_bytes += read as u64;
 
             done += read;
             left -= read;
@@ -730,9 +1307,9 @@ impl Connection {
     }
 
     /// Processes a single QUIC packet received from the peer.
-    fn recv_single(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let now = time::Instant::now();
-
+    fn recv_single(
+        &mut self, buf: &mut [u8], ecn: Ecn, now: time::Instant,
+    ) -> Result<usize> {
         if buf.is_empty() {
             return Err(Error::BufferTooShort);
         }
@@ -741,7 +1318,7 @@ impl Connection {
             return Err(Error::Done);
         }
 
-        self.do_handshake()?;
+        self.do_handshake(now)?;
 
         let is_closing = self.error.is_some() || self.app_error.is_some();
 
@@ -779,24 +1356,34 @@ impl Connection {
                 None => return Err(Error::InvalidPacket),
             };
 
-            let mut new_version = 0;
-            for v in versions.iter() {
-                if *v == VERSION_DRAFT17 {
-                    new_version = *v;
-                }
-            }
+            // Pick our most preferred version among the ones offered.
+            let new_version =
+                match self.versions.iter().find(|v| versions.contains(v)) {
+                    Some(v) => *v,
 
-            // We don't support any of the versions offfered.
-            if new_version == 0 {
-                return Err(Error::UnknownVersion);
-            }
+                    // We don't support any of the versions offered.
+                    None => return Err(Error::UnknownVersion),
+                };
 
             self.version = new_version;
             self.did_version_negotiation = true;
 
+            // Derive Initial secrets for the new version.
+            let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &self.dcid,
+                self.version,
+                self.is_server,
+            )?;
+
+            self.initial.crypto_open = Some(aead_open);
+            self.initial.crypto_seal = Some(aead_seal);
+
             // Reset connection state to force sending another Initial packet.
             self.got_peer_conn_id = false;
-            self.recovery.drop_unacked_data(&mut self.initial.flight);
+            self.recovery.drop_unacked_data(
+                packet::EPOCH_INITIAL,
+                &mut self.initial.flight,
+            );
             self.initial.clear();
             self.tls_state.clear().map_err(|_| Error::TlsFail)?;
 
@@ -830,15 +1417,21 @@ impl Connection {
             self.dcid.copy_from_slice(&hdr.scid);
 
             // Derive Initial secrets using the new connection ID.
-            let (aead_open, aead_seal) =
-                crypto::derive_initial_key_material(&hdr.scid, self.is_server)?;
+            let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &hdr.scid,
+                self.version,
+                self.is_server,
+            )?;
 
             self.initial.crypto_open = Some(aead_open);
             self.initial.crypto_seal = Some(aead_seal);
 
             // Reset connection state to force sending another Initial packet.
             self.got_peer_conn_id = false;
-            self.recovery.drop_unacked_data(&mut self.initial.flight);
+            self.recovery.drop_unacked_data(
+                packet::EPOCH_INITIAL,
+                &mut self.initial.flight,
+            );
             self.initial.clear();
             self.tls_state.clear().map_err(|_| Error::TlsFail)?;
 
@@ -846,7 +1439,20 @@ impl Connection {
         }
 
         if hdr.ty != packet::Type::Application && hdr.version != self.version {
-            return Err(Error::UnknownVersion);
+            // Servers use the version of the client's first Initial packet,
+            // as long as it's one of the supported ones.
+            let can_switch = self.is_server &&
+                hdr.ty == packet::Type::Initial &&
+                !self.derived_initial_secrets &&
+                self.versions.contains(&hdr.version);
+
+            if !can_switch {
+                return Err(Error::UnknownVersion);
+            }
+
+            self.version = hdr.version;
+
+            self.set_transport_params()?;
         }
 
         // Long header packets have an explicit payload length, but short
@@ -872,8 +1478,11 @@ impl Connection {
 
         // Derive initial secrets on the server.
         if !self.derived_initial_secrets {
-            let (aead_open, aead_seal) =
-                crypto::derive_initial_key_material(&hdr.dcid, self.is_server)?;
+            let (aead_open, aead_seal) = crypto::derive_initial_key_material(
+                &hdr.dcid,
+                self.version,
+                self.is_server,
+            )?;
 
             self.initial.crypto_open = Some(aead_open);
             self.initial.crypto_seal = Some(aead_seal);
@@ -884,6 +1493,8 @@ impl Connection {
             self.got_peer_conn_id = true;
         }
 
+        let epoch = hdr.ty.to_epoch()?;
+
         // Select packet number space context based on the input packet type.
         let space = match hdr.ty {
             packet::Type::Initial => &mut self.initial,
@@ -963,12 +1574,18 @@ impl Connection {
         // ACK.
         let mut do_ack = false;
 
+        let mut qlog_frames = Vec::new();
+
         // Process packet payload.
         while payload.cap() > 0 {
             let frame = frame::Frame::from_bytes(&mut payload, hdr.ty)?;
 
             trace!("{} rx frm {:?}", self.trace_id, frame);
 
+            if self.qlog.is_some() {
+                qlog_frames.push(qlog::frame_data(&frame));
+            }
+
             match frame {
                 frame::Frame::Padding { .. } => (),
 
@@ -976,19 +1593,53 @@ impl Connection {
                     do_ack = true;
                 },
 
-                frame::Frame::ACK { ranges, ack_delay } => {
+                frame::Frame::ACK {
+                    ranges,
+                    ack_delay,
+                    ecn_counts,
+                } => {
                     let ack_delay = ack_delay *
                         2_u64.pow(
                             self.peer_transport_params.ack_delay_exponent as u32,
                         );
 
+                    if self.ecn_enabled &&
+                        !self.ecn_failed &&
+                        !self.recovery.on_ecn_counts(
+                            &ranges,
+                            ecn_counts,
+                            &mut space.peer_ecn_counts,
+                            &space.flight,
+                            now,
+                            &self.trace_id,
+                        )
+                    {
+                        trace!("{} ECN validation failed", self.trace_id);
+
+                        self.ecn_failed = true;
+                    }
+
                     self.recovery.on_ack_received(
                         &ranges,
                         ack_delay,
+                        epoch,
                         &mut space.flight,
                         now,
                         &self.trace_id,
                     );
+
+                    if let Some(o) = self.observer.as_mut() {
+                        for pkt_num in space.flight.lost_pkts.drain(..) {
+                            o.on_packet_lost(hdr.ty, pkt_num);
+                        }
+                    }
+
+                    if hdr.ty == packet::Type::Application && self.pmtud.enabled()
+                    {
+                        self.pmtud.on_ack_received(&ranges, &space.flight);
+
+                        self.recovery.set_max_datagram_size(self.pmtud.mtu());
+                    }
                 },
 
                 frame::Frame::ResetStream {
@@ -1003,6 +1654,12 @@ impl Connection {
                         return Err(Error::InvalidStreamState);
                     }
 
+                    // Ignore frames for streams that were already closed.
+                    if self.streams.is_collected(stream_id) {
+                        do_ack = true;
+                        continue;
+                    }
+
                     let max_rx_data = self
                         .local_transport_params
                         .initial_max_stream_data_bidi_remote
@@ -1012,6 +1669,8 @@ impl Connection {
                         .initial_max_stream_data_bidi_local
                         as usize;
 
+                    let is_new = self.streams.get_mut(stream_id).is_none();
+
                     // Get existing stream or create a new one.
                     let stream = self.streams.get_or_create(
                         stream_id,
@@ -1021,12 +1680,30 @@ impl Connection {
                         self.is_server,
                     )?;
 
+                    if is_new {
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_stream_opened(stream_id);
+                        }
+                    }
+
                     self.rx_data += stream.recv_reset(final_size as usize)?;
 
+                    let is_complete = stream.is_complete();
+
                     if self.rx_data > self.max_rx_data {
                         return Err(Error::FlowControl);
                     }
 
+                    // The receive side of reset streams is complete, so
+                    // collect them if the send side is done as well.
+                    if is_complete {
+                        self.streams.collect(stream_id, self.is_server);
+
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_stream_closed(stream_id);
+                        }
+                    }
+
                     do_ack = true;
                 },
 
@@ -1060,6 +1737,14 @@ impl Connection {
                             .map_err(|_| Error::TlsFail)?;
                     }
 
+                    // Messages received after the handshake, such as session
+                    // tickets, are not handled by `do_handshake()`.
+                    if self.handshake_completed {
+                        self.tls_state
+                            .process_post_handshake()
+                            .map_err(|_| Error::TlsFail)?;
+                    }
+
                     do_ack = true;
                 },
 
@@ -1076,6 +1761,12 @@ impl Connection {
                         return Err(Error::InvalidStreamState);
                     }
 
+                    // Ignore frames for streams that were already closed.
+                    if self.streams.is_collected(stream_id) {
+                        do_ack = true;
+                        continue;
+                    }
+
                     let max_rx_data = self
                         .local_transport_params
                         .initial_max_stream_data_bidi_remote
@@ -1085,6 +1776,8 @@ impl Connection {
                         .initial_max_stream_data_bidi_local
                         as usize;
 
+                    let is_new = self.streams.get_mut(stream_id).is_none();
+
                     // Get existing stream or create a new one.
                     let stream = self.streams.get_or_create(
                         stream_id,
@@ -1094,6 +1787,12 @@ impl Connection {
                         self.is_server,
                     )?;
 
+                    if is_new {
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_stream_opened(stream_id);
+                        }
+                    }
+
                     self.rx_data += data.len();
 
                     if self.rx_data > self.max_rx_data {
@@ -1112,6 +1811,12 @@ impl Connection {
                 },
 
                 frame::Frame::MaxStreamData { stream_id, max } => {
+                    // Ignore frames for streams that were already closed.
+                    if self.streams.is_collected(stream_id) {
+                        do_ack = true;
+                        continue;
+                    }
+
                     let max_rx_data = self
                         .local_transport_params
                         .initial_max_stream_data_bidi_remote
@@ -1121,6 +1826,8 @@ impl Connection {
                         .initial_max_stream_data_bidi_local
                         as usize;
 
+                    let is_new = self.streams.get_mut(stream_id).is_none();
+
                     // Get existing stream or create a new one.
                     let stream = self.streams.get_or_create(
                         stream_id,
@@ -1130,6 +1837,12 @@ impl Connection {
                         self.is_server,
                     )?;
 
+                    if is_new {
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_stream_opened(stream_id);
+                        }
+                    }
+
                     stream.update_max_tx_data(max as usize);
 
                     do_ack = true;
@@ -1147,6 +1860,22 @@ impl Connection {
                     do_ack = true;
                 },
 
+                frame::Frame::DataBlocked { .. } => {
+                    do_ack = true;
+                },
+
+                frame::Frame::StreamDataBlocked { .. } => {
+                    do_ack = true;
+                },
+
+                frame::Frame::StreamsBlockedBidi { .. } => {
+                    do_ack = true;
+                },
+
+                frame::Frame::StreamsBlockedUni { .. } => {
+                    do_ack = true;
+                },
+
                 // TODO: implement connection migration
                 frame::Frame::NewConnectionId { .. } => {
                     do_ack = true;
@@ -1167,14 +1896,50 @@ impl Connection {
                     do_ack = true;
                 },
 
-                frame::Frame::ConnectionClose { .. } => {
+                frame::Frame::ConnectionClose {
+                    error_code, reason, ..
+                } => {
                     self.draining = true;
                     self.draining_timer = Some(now + DRAINING_TIMEOUT);
+
+                    if let Some(o) = self.observer.as_mut() {
+                        o.on_close_received(false, error_code, &reason);
+                    }
                 },
 
-                frame::Frame::ApplicationClose { .. } => {
+                frame::Frame::ApplicationClose { error_code, reason } => {
                     self.draining = true;
                     self.draining_timer = Some(now + DRAINING_TIMEOUT);
+
+                    if let Some(o) = self.observer.as_mut() {
+                        o.on_close_received(true, error_code, &reason);
+                    }
+                },
+
+                frame::Frame::Datagram { data } => {
+                    let max_frame_size =
+                        match self.local_transport_params.max_datagram_frame_size
+                        {
+                            Some(v) => v as usize,
+
+                            // DATAGRAM frames were not enabled locally.
+                            None => return Err(Error::InvalidFrame),
+                        };
+
+                    if data.len() > max_frame_size {
+                        return Err(Error::InvalidFrame);
+                    }
+
+                    // Drop the oldest datagram when the application is not
+                    // reading them fast enough.
+                    if self.dgram_recv_queue.is_full() {
+                        self.dgram_recv_queue.pop();
+                    }
+
+                    // The queue can still be full if its length is zero.
+                    self.dgram_recv_queue.push(data).ok();
+
+                    do_ack = true;
                 },
             }
         }
@@ -1190,6 +1955,26 @@ impl Connection {
                     space.recv_pkt_need_ack.remove_until(largest_acked);
                 },
 
+                frame::Frame::Stream { stream_id, data } => {
+                    let stream = match self.streams.get_mut(stream_id) {
+                        Some(v) => v,
+
+                        None => continue,
+                    };
+
+                    stream.send_ack(&data);
+
+                    // Garbage collect the stream once all of its data has
+                    // been acknowledged by the peer.
+                    if stream.is_complete() {
+                        self.streams.collect(stream_id, self.is_server);
+
+                        if let Some(o) = self.observer.as_mut() {
+                            o.on_stream_closed(stream_id);
+                        }
+                    }
+                },
+
                 // This does nothing. It's here to avoid a warning.
                 frame::Frame::Ping => (),
 
@@ -1200,13 +1985,47 @@ impl Connection {
         // We only record the time of arrival of the largest packet number
         // that still needs to be ACK'd, to be used for ACK delay calculation.
         if space.recv_pkt_need_ack.largest() < Some(pn) {
-            space.largest_rx_pkt_time = now;
+            space.largest_rx_pkt_time = Some(now);
         }
 
         space.recv_pkt_num.insert(pn);
 
+        // Packets arriving out of order, or after a gap, are acknowledged
+        // immediately to speed up loss detection on the peer.
+        let largest = space.largest_rx_pkt_num;
+        let is_reordered = pn < largest || pn > largest + 1;
+
         space.recv_pkt_need_ack.push_item(pn);
-        space.do_ack = cmp::max(space.do_ack, do_ack);
+
+        match ecn {
+            Ecn::Ect0 => space.ecn_counts.ect0_count += 1,
+
+            Ecn::Ect1 => space.ecn_counts.ect1_count += 1,
+
+            Ecn::Ce => space.ecn_counts.ecn_ce_count += 1,
+
+            Ecn::NotEct => (),
+        }
+
+        if do_ack {
+            space.ack_eliciting_count += 1;
+
+            // Only ACKs for Application packets are delayed, to avoid slowing
+            // down the handshake.
+            if hdr.ty != packet::Type::Application ||
+                is_reordered ||
+                ecn == Ecn::Ce ||
+                space.ack_eliciting_count >= self.ack_frequency
+            {
+                space.do_ack = true;
+            } else if space.ack_timer.is_none() {
+                let max_ack_delay = time::Duration::from_millis(
+                    self.local_transport_params.max_ack_delay,
+                );
+
+                space.ack_timer = Some(now + max_ack_delay);
+            }
+        }
 
         space.largest_rx_pkt_num = cmp::max(space.largest_rx_pkt_num, pn);
 
@@ -1218,6 +2037,25 @@ impl Connection {
 
         let read = b.off() + aead.alg().tag_len();
 
+        self.recv_count += 1;
+
+        if let Some(qlog) = self.qlog.as_mut() {
+            let data = qlog::packet_data(&hdr, pn, read, &qlog_frames);
+
+            qlog.add_event(now, "transport", "packet_received", &data);
+            qlog.add_recovery_events(now, &self.recovery);
+        }
+
+        // Receiving an Handshake packet proves that the peer received our
+        // Initial packets, which validates the path.
+        if hdr.ty == packet::Type::Handshake && !self.path_validated {
+            self.path_validated = true;
+
+            if let Some(o) = self.observer.as_mut() {
+                o.on_path_validated();
+            }
+        }
+
         // On the server, drop initial state after receiving and successfully
         // processing an Handshake packet.
         if self.is_server && hdr.ty == packet::Type::Handshake {
@@ -1234,8 +2072,111 @@ impl Connection {
     ///
     /// [`Done`]: enum.Error.html#variant.Done
     pub fn send(&mut self, out: &mut [u8]) -> Result<usize> {
-        let now = time::Instant::now();
+        self.send_at(out, time::Instant::now())
+    }
+
+    /// Writes a single QUIC packet to be sent to the peer at the given time.
+    ///
+    /// This is the same as [`send()`], except that `now` is used as the
+    /// current time instead of reading the system clock.
+    ///
+    /// [`send()`]: struct.Connection.html#method.send
+    pub fn send_at(
+        &mut self, out: &mut [u8], now: time::Instant,
+    ) -> Result<usize> {
+        self.send_single(out, now, false)
+    }
+
+    /// Writes multiple QUIC packets of the same size contiguously into the
+    /// provided buffer.
+    ///
+    /// This allows the application to hand the whole buffer to the kernel in
+    /// a single system call, e.g. using UDP generic segmentation offload or
+    /// `sendmmsg()`. At most `max_packets` packets are written.
+    ///
+    /// On success the size of each packet and the number of packets written
+    /// are returned as a tuple, or [`Done`] if there is nothing to send. The
+    /// packets are stored back to back at the start of `out`, so the total
+    /// amount of data written is the product of the two.
+    ///
+    /// Packets are padded to the largest packet size that can currently be
+    /// sent. Long header packets can't be padded, so they are returned on
+    /// their own, and the batch is cut short when the congestion window
+    /// doesn't allow more full-sized packets.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    pub fn send_batch(
+        &mut self, out: &mut [u8], max_packets: usize,
+    ) -> Result<(usize, usize)> {
+        self.send_batch_at(out, max_packets, time::Instant::now())
+    }
+
+    /// Writes multiple QUIC packets of the same size at the given time.
+    ///
+    /// This is the same as [`send_batch()`], except that `now` is used as the
+    /// current time instead of reading the system clock.
+    ///
+    /// [`send_batch()`]: struct.Connection.html#method.send_batch
+    pub fn send_batch_at(
+        &mut self, out: &mut [u8], max_packets: usize, now: time::Instant,
+    ) -> Result<(usize, usize)> {
+        if max_packets == 0 {
+            return Err(Error::Done);
+        }
+
+        let segment_size = cmp::min(self.max_send_pkt_len(), out.len());
+
+        // Long header packets can't be padded, and PMTU probes are larger
+        // than the segment size, so those are sent on their own.
+        let written = match self.send_single(&mut out[..segment_size], now, true)
+        {
+            Ok(v) => v,
+
+            Err(Error::Done) => {
+                let written = self.send_single(out, now, false)?;
+
+                return Ok((written, 1));
+            },
+
+            Err(e) => return Err(e),
+        };
+
+        if written != segment_size {
+            return Ok((written, 1));
+        }
 
+        let mut count = 1;
+        let mut off = segment_size;
+
+        // Note that `cwnd()` is the part of the congestion window not used by
+        // bytes in flight, so it shrinks with each packet sent. Loss probes
+        // ignore the congestion window, so are never batched.
+        while count < max_packets &&
+            out.len() - off >= segment_size &&
+            self.recovery.probe_epoch().is_none() &&
+            self.recovery.cwnd() >= segment_size
+        {
+            let out = &mut out[off..off + segment_size];
+
+            match self.send_single(out, now, true) {
+                Ok(written) => off += written,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            }
+
+            count += 1;
+        }
+
+        Ok((segment_size, count))
+    }
+
+    /// Writes a single QUIC packet, padding it to the size of `out` if `pad`
+    /// is set.
+    fn send_single(
+        &mut self, out: &mut [u8], now: time::Instant, pad: bool,
+    ) -> Result<usize> {
         if out.is_empty() {
             return Err(Error::BufferTooShort);
         }
@@ -1247,20 +2188,34 @@ impl Connection {
         let is_closing = self.error.is_some() || self.app_error.is_some();
 
         if !is_closing {
-            self.do_handshake()?;
+            self.do_handshake(now)?;
         }
 
-        // Use max_packet_size as sent by the peer, except during the handshake
-        // when we haven't parsed transport parameters yet, so use a default
-        // value then.
-        let max_pkt_len = if self.handshake_completed {
-            // We cap the maximum packet size to 16KB or so, so that it can be
-            // always encoded with a 2-byte varint.
-            cmp::min(16383, self.peer_transport_params.max_packet_size) as usize
+        let pkt_type = self.select_egress_pkt_type()?;
+
+        let epoch = pkt_type.to_epoch()?;
+
+        // Only short header packets can be padded to an exact size, as the
+        // length field of long header packets would change with the padding.
+        if pad && pkt_type != packet::Type::Application {
+            return Err(Error::Done);
+        }
+
+        // Send a PMTU probe, unless the congestion window or the output
+        // buffer don't allow a full-sized one.
+        let is_pmtud_probe = pkt_type == packet::Type::Application &&
+            !pad &&
+            !is_closing &&
+            self.handshake_completed &&
+            self.recovery.probe_epoch().is_none() &&
+            self.pmtud.should_probe() &&
+            self.recovery.cwnd() >= self.pmtud.probe_size() &&
+            out.len() >= self.pmtud.probe_size();
+
+        let max_pkt_len = if is_pmtud_probe {
+            self.pmtud.probe_size()
         } else {
-            // Allow for 1200 bytes (minimum QUIC packet size) during the
-            // handshake.
-            1200
+            self.max_send_pkt_len()
         };
 
         // Cap output buffer to respect peer's max_packet_size limit.
@@ -1268,7 +2223,9 @@ impl Connection {
 
         let mut b = octets::Octets::with_slice(&mut out[..avail]);
 
-        let pkt_type = self.select_egress_pkt_type()?;
+        let ecn_marked = self.ecn_marking() == Ecn::Ect0;
+
+        let dgram_max_len = self.dgram_max_writable_len();
 
         let space = match pkt_type {
             packet::Type::Initial => &mut self.initial,
@@ -1284,6 +2241,8 @@ impl Connection {
         for lost in space.flight.lost.drain(..) {
             match lost {
                 frame::Frame::Crypto { data } => {
+                    self.retrans_bytes += data.len() as u64;
+
                     space.crypto_stream.send_push_front(data)?;
                 },
 
@@ -1293,6 +2252,8 @@ impl Connection {
                         None => continue,
                     };
 
+                    self.retrans_bytes += data.len() as u64;
+
                     self.tx_data -= data.len();
 
                     stream.send_push_front(data)?;
@@ -1302,6 +2263,9 @@ impl Connection {
                     space.do_ack = true;
                 },
 
+                // DATAGRAM frames are never retransmitted.
+                frame::Frame::Datagram { .. } => (),
+
                 _ => (),
             }
         }
@@ -1346,9 +2310,14 @@ impl Connection {
 
         let mut payload_len = 0;
 
-        // Create ACK frame.
-        if space.do_ack {
-            let ack_delay = space.largest_rx_pkt_time.elapsed();
+        // Create ACK frame, also when the ACK is being delayed but a packet is
+        // being sent anyway.
+        if space.do_ack || space.ack_eliciting_count > 0 {
+            let ack_delay = match space.largest_rx_pkt_time {
+                Some(v) => now.duration_since(v),
+
+                None => time::Duration::new(0, 0),
+            };
 
             let ack_delay = ack_delay.as_secs() * 1_000_000 +
                 u64::from(ack_delay.subsec_micros());
@@ -1357,16 +2326,43 @@ impl Connection {
                 2_u64
                     .pow(self.local_transport_params.ack_delay_exponent as u32);
 
-            let frame = frame::Frame::ACK {
+            // Only report ECN counts once ECN-marked packets were received.
+            let ecn_counts = if space.ecn_counts != frame::EcnCounts::default() {
+                Some(space.ecn_counts)
+            } else {
+                None
+            };
+
+            let mut frame = frame::Frame::ACK {
                 ack_delay,
                 ranges: space.recv_pkt_need_ack.clone(),
+                ecn_counts,
             };
 
-            if frame.wire_len() <= left {
+            let mut frame_len = frame.wire_len();
+
+            // Drop the smallest ranges until the frame fits in the packet.
+            while frame_len > left {
+                match frame {
+                    frame::Frame::ACK { ref mut ranges, .. }
+                        if ranges.len() > 1 =>
+                    {
+                        ranges.pop_front();
+                    },
+
+                    _ => break,
+                }
+
+                frame_len = frame.wire_len();
+            }
+
+            if frame_len <= left {
                 space.do_ack = false;
+                space.ack_eliciting_count = 0;
+                space.ack_timer = None;
 
-                payload_len += frame.wire_len();
-                left -= frame.wire_len();
+                payload_len += frame_len;
+                left -= frame_len;
 
                 frames.push(frame);
             }
@@ -1379,12 +2375,33 @@ impl Connection {
             (self.new_max_rx_data != self.max_rx_data &&
                 self.new_max_rx_data / 2 > self.max_rx_data - self.rx_data)
         {
+            // Grow the receive window if the previous update was sent less
+            // than two round-trips ago, as the application is reading data
+            // faster than the current window allows the peer to send it. The
+            // new window only takes effect once the frame is written.
+            let mut window = self.rx_window;
+
+            if let Some(last_rx_update) = self.last_rx_update {
+                if now - last_rx_update < self.recovery.rtt() * 2 {
+                    window = cmp::min(
+                        self.rx_window.saturating_mul(2),
+                        self.max_rx_window,
+                    );
+                }
+            }
+
+            let max_rx_data = self.new_max_rx_data + window - self.rx_window;
+
             let frame = frame::Frame::MaxData {
-                max: self.new_max_rx_data as u64,
+                max: max_rx_data as u64,
             };
 
             if frame.wire_len() <= left {
+                self.new_max_rx_data = max_rx_data;
+                self.rx_window = window;
+
                 self.max_rx_data = self.new_max_rx_data;
+                self.last_rx_update = Some(now);
 
                 payload_len += frame.wire_len();
                 left -= frame.wire_len();
@@ -1397,18 +2414,24 @@ impl Connection {
 
         // Create MAX_STREAM_DATA frames as needed.
         if pkt_type == packet::Type::Application && !is_closing {
+            let rtt = self.recovery.rtt();
+
             for (id, stream) in
                 self.streams.iter_mut().filter(|(_, s)| s.more_credit())
             {
+                let max_rx_data = stream.autotune_max_rx_data(now, rtt);
+
                 let frame = frame::Frame::MaxStreamData {
                     stream_id: *id,
-                    max: stream.update_max_rx_data() as u64,
+                    max: max_rx_data as u64,
                 };
 
                 if frame.wire_len() > left {
                     break;
                 }
 
+                stream.update_max_rx_data(max_rx_data, now);
+
                 payload_len += frame.wire_len();
                 left -= frame.wire_len();
 
@@ -1418,83 +2441,252 @@ impl Connection {
             }
         }
 
-        // Create PING and PADDING for TLP.
-        if self.recovery.probes > 0 && left >= 1 {
-            let frame = frame::Frame::Ping;
+        // Create MAX_STREAMS_BIDI frame, when the peer is running out of
+        // bidirectional streams it can open.
+        if pkt_type == packet::Type::Application &&
+            !is_closing &&
+            self.streams.should_update_max_streams_bidi()
+        {
+            let frame = frame::Frame::MaxStreamsBidi {
+                max: self.streams.max_streams_bidi_next() as u64,
+            };
 
-            payload_len += frame.wire_len();
-            left -= frame.wire_len();
+            if frame.wire_len() <= left {
+                self.streams.update_max_streams_bidi();
 
-            frames.push(frame);
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
 
-            self.recovery.probes -= 1;
+                frames.push(frame);
 
-            ack_eliciting = true;
+                ack_eliciting = true;
+            }
         }
 
-        // Create CONNECTION_CLOSE frame.
-        if let Some(err) = self.error {
-            let frame = frame::Frame::ConnectionClose {
-                error_code: err,
-                frame_type: 0,
-                reason: Vec::new(),
+        // Create MAX_STREAMS_UNI frame, when the peer is running out of
+        // unidirectional streams it can open.
+        if pkt_type == packet::Type::Application &&
+            !is_closing &&
+            self.streams.should_update_max_streams_uni()
+        {
+            let frame = frame::Frame::MaxStreamsUni {
+                max: self.streams.max_streams_uni_next() as u64,
             };
 
-            payload_len += frame.wire_len();
-            left -= frame.wire_len();
+            if frame.wire_len() <= left {
+                self.streams.update_max_streams_uni();
 
-            frames.push(frame);
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
 
-            self.draining = true;
-            self.draining_timer = Some(now + DRAINING_TIMEOUT);
+                frames.push(frame);
+
+                ack_eliciting = true;
+            }
         }
 
-        // Create APPLICAtiON_CLOSE frame.
-        if let Some(err) = self.app_error {
-            let frame = frame::Frame::ApplicationClose {
-                error_code: err,
-                reason: self.app_reason.clone(),
+        // Create DATA_BLOCKED frame, when the peer's connection-level flow
+        // control limit prevents us from sending more stream data.
+        if pkt_type == packet::Type::Application &&
+            !is_closing &&
+            self.tx_data >= self.max_tx_data &&
+            self.blocked_limit != Some(self.max_tx_data) &&
+            self.streams.has_writable()
+        {
+            let frame = frame::Frame::DataBlocked {
+                limit: self.max_tx_data as u64,
             };
 
-            payload_len += frame.wire_len();
-            left -= frame.wire_len();
+            if frame.wire_len() <= left {
+                self.blocked_limit = Some(self.max_tx_data);
+                self.data_blocked_count += 1;
 
-            frames.push(frame);
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
 
-            self.draining = true;
-            self.draining_timer = Some(now + DRAINING_TIMEOUT);
+                frames.push(frame);
+
+                ack_eliciting = true;
+            }
         }
 
-        // Create PATH_RESPONSE frame.
-        if let Some(ref challenge) = self.challenge {
-            let frame = frame::Frame::PathResponse {
-                data: challenge.clone(),
-            };
+        // Create STREAM_DATA_BLOCKED frames as needed.
+        if pkt_type == packet::Type::Application && !is_closing {
+            for (id, stream) in
+                self.streams.iter_mut().filter(|(_, s)| s.blocked())
+            {
+                let frame = frame::Frame::StreamDataBlocked {
+                    stream_id: *id,
+                    limit: stream.max_tx_data() as u64,
+                };
 
-            payload_len += frame.wire_len();
-            left -= frame.wire_len();
+                if frame.wire_len() > left {
+                    break;
+                }
 
-            frames.push(frame);
+                stream.update_blocked();
+                self.stream_data_blocked_count += 1;
 
-            self.challenge = None;
-        }
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
 
-        // Create CRYPTO frame.
-        if space.crypto_stream.writable() && !is_closing {
-            let crypto_len = left - frame::MAX_CRYPTO_OVERHEAD;
-            let crypto_buf = space.crypto_stream.send_pop(crypto_len)?;
+                frames.push(frame);
 
-            let frame = frame::Frame::Crypto { data: crypto_buf };
+                ack_eliciting = true;
+            }
+        }
 
-            payload_len += frame.wire_len();
-            left -= frame.wire_len();
+        // Create STREAMS_BLOCKED_BIDI frame, when the peer's stream limit
+        // prevented the application from opening a new stream.
+        if let Some(limit) = self.streams.streams_blocked_bidi() {
+            let frame = frame::Frame::StreamsBlockedBidi {
+                limit: limit as u64,
+            };
 
-            frames.push(frame);
+            if pkt_type == packet::Type::Application &&
+                !is_closing &&
+                frame.wire_len() <= left
+            {
+                self.streams.reset_streams_blocked_bidi();
+
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
+
+                frames.push(frame);
+
+                ack_eliciting = true;
+            }
+        }
+
+        // Create STREAMS_BLOCKED_UNI frame, when the peer's stream limit
+        // prevented the application from opening a new stream.
+        if let Some(limit) = self.streams.streams_blocked_uni() {
+            let frame = frame::Frame::StreamsBlockedUni {
+                limit: limit as u64,
+            };
+
+            if pkt_type == packet::Type::Application &&
+                !is_closing &&
+                frame.wire_len() <= left
+            {
+                self.streams.reset_streams_blocked_uni();
+
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
+
+                frames.push(frame);
+
+                ack_eliciting = true;
+            }
+        }
+
+        // Create PING for PTO probe.
+        if self.recovery.loss_probes[epoch] > 0 && left >= 1 {
+            let frame = frame::Frame::Ping;
+
+            payload_len += frame.wire_len();
+            left -= frame.wire_len();
+
+            frames.push(frame);
+
+            self.recovery.loss_probes[epoch] -= 1;
+
+            ack_eliciting = true;
+        }
+
+        // Create CONNECTION_CLOSE frame.
+        if let Some(err) = self.error {
+            let frame = frame::Frame::ConnectionClose {
+                error_code: err,
+                frame_type: 0,
+                reason: Vec::new(),
+            };
+
+            payload_len += frame.wire_len();
+            left -= frame.wire_len();
+
+            frames.push(frame);
+
+            self.draining = true;
+            self.draining_timer = Some(now + DRAINING_TIMEOUT);
+        }
+
+        // Create APPLICAtiON_CLOSE frame.
+        if let Some(err) = self.app_error {
+            let frame = frame::Frame::ApplicationClose {
+                error_code: err,
+                reason: self.app_reason.clone(),
+            };
+
+            payload_len += frame.wire_len();
+            left -= frame.wire_len();
+
+            frames.push(frame);
+
+            self.draining = true;
+            self.draining_timer = Some(now + DRAINING_TIMEOUT);
+        }
+
+        // Create PATH_RESPONSE frame.
+        if let Some(ref challenge) = self.challenge {
+            let frame = frame::Frame::PathResponse {
+                data: challenge.clone(),
+            };
+
+            payload_len += frame.wire_len();
+            left -= frame.wire_len();
+
+            frames.push(frame);
+
+            self.challenge = None;
+        }
+
+        // Create CRYPTO frame.
+        if space.crypto_stream.writable() && !is_closing {
+            let crypto_len = left - frame::MAX_CRYPTO_OVERHEAD;
+            let crypto_buf = space.crypto_stream.send_pop(crypto_len)?;
+
+            let frame = frame::Frame::Crypto { data: crypto_buf };
+
+            payload_len += frame.wire_len();
+            left -= frame.wire_len();
+
+            frames.push(frame);
 
             ack_eliciting = true;
             is_crypto = true;
         }
 
+        // Create DATAGRAM frames for as many queued datagrams as can fit.
+        if pkt_type == packet::Type::Application && !is_closing {
+            while let Some(len) = self.dgram_send_queue.peek_front_len() {
+                // Drop datagrams that can't fit even in an empty packet, e.g.
+                // after the path MTU shrank, so they don't block the queue.
+                if dgram_max_len.map_or(true, |max| len > max) {
+                    self.dgram_send_queue.pop();
+                    continue;
+                }
+
+                if 1 + octets::varint_len(len as u64) + len > left {
+                    break;
+                }
+
+                let data = match self.dgram_send_queue.pop() {
+                    Some(v) => v,
+                    None => break,
+                };
+
+                let frame = frame::Frame::Datagram { data };
+
+                payload_len += frame.wire_len();
+                left -= frame.wire_len();
+
+                frames.push(frame);
+
+                ack_eliciting = true;
+            }
+        }
+
         // Create a single STREAM frame for the first stream that is writable.
         if pkt_type == packet::Type::Application &&
             !is_closing &&
@@ -1534,10 +2726,36 @@ impl Connection {
             }
         }
 
+        // Make sure PMTU probes are ACK-eliciting.
+        if is_pmtud_probe {
+            let frame = frame::Frame::Ping;
+
+            payload_len += frame.wire_len();
+
+            frames.push(frame);
+
+            ack_eliciting = true;
+        }
+
         if frames.is_empty() {
             return Err(Error::Done);
         }
 
+        // Pad PMTU probes and batched packets to the size of the buffer.
+        if is_pmtud_probe || pad {
+            let pkt_len = pn_len + payload_len + space.overhead();
+
+            if b.cap() > pkt_len {
+                let frame = frame::Frame::Padding {
+                    len: b.cap() - pkt_len,
+                };
+
+                payload_len += frame.wire_len();
+
+                frames.push(frame);
+            }
+        }
+
         // Pad the client's initial packet.
         if !self.is_server && pkt_type == packet::Type::Initial {
             let pkt_len = pn_len + payload_len + space.overhead();
@@ -1603,17 +2821,36 @@ impl Connection {
             aead,
         )?;
 
-        let sent_pkt = recovery::Sent::new(
+        if let Some(qlog) = self.qlog.as_mut() {
+            let frames: Vec<String> =
+                frames.iter().map(qlog::frame_data).collect();
+
+            let data = qlog::packet_data(&hdr, pn, written, &frames);
+
+            qlog.add_event(now, "transport", "packet_sent", &data);
+        }
+
+        let mut sent_pkt = recovery::Sent::new(
             pn,
             frames,
             written,
             ack_eliciting,
             is_crypto,
+            ecn_marked,
             now,
         );
 
+        if is_pmtud_probe {
+            trace!("{} tx pmtud probe size={}", self.trace_id, written);
+
+            sent_pkt.pmtud_probe = true;
+
+            self.pmtud.on_probe_sent(pn);
+        }
+
         self.recovery.on_packet_sent(
             sent_pkt,
+            epoch,
             &mut space.flight,
             now,
             &self.trace_id,
@@ -1622,6 +2859,11 @@ impl Connection {
         space.next_pkt_num += 1;
 
         self.sent_count += 1;
+        self.sent_bytes += written as u64;
+
+        if let Some(qlog) = self.qlog.as_mut() {
+            qlog.add_recovery_events(now, &self.recovery);
+        }
 
         // On the client, drop initial state after sending an Handshake packet.
         if !self.is_server && hdr.ty == packet::Type::Handshake {
@@ -1656,7 +2898,15 @@ impl Connection {
 
         let (read, fin) = stream.recv_pop(out)?;
 
-        self.new_max_rx_data = self.max_rx_data + read;
+        self.new_max_rx_data += read;
+
+        if stream.is_complete() {
+            self.streams.collect(stream_id, self.is_server);
+
+            if let Some(o) = self.observer.as_mut() {
+                o.on_stream_closed(stream_id);
+            }
+        }
 
         Ok((read, fin))
     }
@@ -1666,6 +2916,21 @@ impl Connection {
     /// On success the number of bytes written is returned.
     pub fn stream_send(
         &mut self, stream_id: u64, buf: &[u8], fin: bool,
+    ) -> Result<usize> {
+        self.stream_send_owned(stream_id, buf.to_vec(), fin)
+    }
+
+    /// Writes data to a stream, taking ownership of the given buffer.
+    ///
+    /// Unlike [`stream_send()`], the data is not copied: the buffer is kept
+    /// as-is until it's fully acknowledged by the peer, and outgoing STREAM
+    /// frames point directly into it.
+    ///
+    /// On success the number of bytes written is returned.
+    ///
+    /// [`stream_send()`]: struct.Connection.html#method.stream_send
+    pub fn stream_send_owned(
+        &mut self, stream_id: u64, buf: Vec<u8>, fin: bool,
     ) -> Result<usize> {
         // We can't write on the peer's unidirectional streams.
         if !stream::is_bidi(stream_id) &&
@@ -1681,6 +2946,8 @@ impl Connection {
             self.peer_transport_params
                 .initial_max_stream_data_bidi_remote as usize;
 
+        let is_new = self.streams.get_mut(stream_id).is_none();
+
         // Get existing stream or create a new one.
         let stream = self.streams.get_or_create(
             stream_id,
@@ -1690,11 +2957,117 @@ impl Connection {
             self.is_server,
         )?;
 
+        if is_new {
+            if let Some(o) = self.observer.as_mut() {
+                o.on_stream_opened(stream_id);
+            }
+        }
+
         // TODO: implement backpressure based on peer's flow control
 
-        stream.send_push(buf, fin)?;
+        let len = buf.len();
+
+        stream.send_push_owned(buf, fin)?;
+
+        Ok(len)
+    }
+
+    /// Returns the ECN codepoint outgoing packets should be marked with.
+    ///
+    /// This is [`Ect0`] when ECN is enabled in the configuration, unless the
+    /// peer's feedback shows that the path doesn't support ECN, in which case
+    /// [`NotEct`] is returned.
+    ///
+    /// [`Ect0`]: enum.Ecn.html#variant.Ect0
+    /// [`NotEct`]: enum.Ecn.html#variant.NotEct
+    pub fn ecn_marking(&self) -> Ecn {
+        if self.ecn_enabled && !self.ecn_failed {
+            Ecn::Ect0
+        } else {
+            Ecn::NotEct
+        }
+    }
+
+    /// Reads the first received DATAGRAM.
+    ///
+    /// On success the DATAGRAM's payload is copied into `buf` and its length
+    /// is returned. [`Done`] is returned if there are no received datagrams
+    /// to read, and [`BufferTooShort`] if `buf` can't hold the first one.
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
+    pub fn dgram_recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = match self.dgram_recv_queue.peek_front_len() {
+            Some(v) => v,
+
+            None => return Err(Error::Done),
+        };
+
+        if len > buf.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        let data = self.dgram_recv_queue.pop().ok_or(Error::Done)?;
+
+        buf[..len].copy_from_slice(&data);
+
+        Ok(len)
+    }
+
+    /// Queues `buf` to be sent in a DATAGRAM frame.
+    ///
+    /// DATAGRAM frames are sent unreliably, and are never retransmitted if
+    /// lost. [`Done`] is returned if the send queue is full, [`InvalidState`]
+    /// if the peer doesn't support DATAGRAM frames, and [`BufferTooShort`] if
+    /// `buf` is larger than [`dgram_max_writable_len()`].
+    ///
+    /// [`Done`]: enum.Error.html#variant.Done
+    /// [`InvalidState`]: enum.Error.html#variant.InvalidState
+    /// [`BufferTooShort`]: enum.Error.html#variant.BufferTooShort
+    /// [`dgram_max_writable_len()`]: struct.Connection.html#method.dgram_max_writable_len
+    pub fn dgram_send(&mut self, buf: &[u8]) -> Result<()> {
+        let max_len = match self.dgram_max_writable_len() {
+            Some(v) => v,
+
+            None => return Err(Error::InvalidState),
+        };
+
+        if buf.len() > max_len {
+            return Err(Error::BufferTooShort);
+        }
+
+        self.dgram_send_queue.push(buf.to_vec())
+    }
+
+    /// Returns the maximum DATAGRAM payload size that can be sent.
+    ///
+    /// This is limited both by the peer's advertised maximum DATAGRAM frame
+    /// size and by the size of packets that can be sent on the connection.
+    ///
+    /// `None` is returned if the peer hasn't advertised support for DATAGRAM
+    /// frames, or if the handshake hasn't completed yet.
+    pub fn dgram_max_writable_len(&self) -> Option<usize> {
+        if !self.handshake_completed {
+            return None;
+        }
+
+        // Account for the short header, the packet number and the AEAD
+        // overhead, as well as the payload length reserved by `send()`.
+        let pkt_overhead = 1 +
+            self.dcid.len() +
+            packet::MAX_PKT_NUM_LEN +
+            2 +
+            self.application.overhead();
+
+        let max_frame_size = cmp::min(
+            self.peer_transport_params.max_datagram_frame_size? as usize,
+            self.max_send_pkt_len().saturating_sub(pkt_overhead),
+        );
+
+        // Account for the frame type and length fields.
+        let overhead = 1 + octets::varint_len(max_frame_size as u64);
 
-        Ok(buf.len())
+        Some(max_frame_size.saturating_sub(overhead))
     }
 
     /// Creates an iterator over streams that have outstanding data to read.
@@ -1709,23 +3082,37 @@ impl Connection {
     ///
     /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
     pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.timeout_at(time::Instant::now())
+    }
+
+    /// Returns the amount of time from `now` until the next timeout event.
+    ///
+    /// This is the same as [`timeout()`], except that `now` is used as the
+    /// current time instead of reading the system clock. The returned timeout
+    /// should then be handled with [`on_timeout_at()`].
+    ///
+    /// [`timeout()`]: struct.Connection.html#method.timeout
+    /// [`on_timeout_at()`]: struct.Connection.html#method.on_timeout_at
+    pub fn timeout_at(&self, now: time::Instant) -> Option<std::time::Duration> {
         if self.closed {
             return None;
         }
 
         let timeout = if self.draining {
             self.draining_timer
-        } else if self.recovery.loss_detection_timer().is_some() {
-            self.recovery.loss_detection_timer()
-        } else if self.idle_timer.is_some() {
-            self.idle_timer
         } else {
-            None
+            // Use the closest expiring timer.
+            [
+                self.recovery.loss_detection_timer(),
+                self.idle_timer,
+                self.application.ack_timer,
+            ]
+            .iter()
+            .filter_map(|&t| t)
+            .min()
         };
 
         if let Some(timeout) = timeout {
-            let now = time::Instant::now();
-
             if timeout <= now {
                 return Some(std::time::Duration::new(0, 0));
             }
@@ -1740,8 +3127,16 @@ impl Connection {
     ///
     /// If no timeout has occurred it does nothing.
     pub fn on_timeout(&mut self) {
-        let now = time::Instant::now();
+        self.on_timeout_at(time::Instant::now())
+    }
 
+    /// Processes a timeout event at the given time.
+    ///
+    /// This is the same as [`on_timeout()`], except that `now` is used as the
+    /// current time instead of reading the system clock.
+    ///
+    /// [`on_timeout()`]: struct.Connection.html#method.on_timeout
+    pub fn on_timeout_at(&mut self, now: time::Instant) {
         if self.draining {
             if self.draining_timer.is_some() &&
                 self.draining_timer.unwrap() <= now
@@ -1761,19 +3156,52 @@ impl Connection {
             return;
         }
 
+        if self.application.ack_timer.is_some() &&
+            self.application.ack_timer.unwrap() <= now
+        {
+            trace!("{} ack timeout expired", self.trace_id);
+
+            self.application.ack_timer = None;
+            self.application.do_ack = true;
+        }
+
         if self.recovery.loss_detection_timer().is_some() &&
             self.recovery.loss_detection_timer().unwrap() <= now
         {
             trace!("{} loss detection timeout expired", self.trace_id);
 
             self.recovery.on_loss_detection_timer(
-                &mut self.initial.flight,
-                &mut self.handshake.flight,
-                &mut self.application.flight,
+                [
+                    &mut self.initial.flight,
+                    &mut self.handshake.flight,
+                    &mut self.application.flight,
+                ],
                 now,
                 &self.trace_id,
             );
 
+            if let Some(o) = self.observer.as_mut() {
+                for (ty, space) in [
+                    (Type::Initial, &mut self.initial),
+                    (Type::Handshake, &mut self.handshake),
+                    (Type::Application, &mut self.application),
+                ]
+                .iter_mut()
+                {
+                    for pkt_num in space.flight.lost_pkts.drain(..) {
+                        o.on_packet_lost(*ty, pkt_num);
+                    }
+                }
+            }
+
+            if self.pmtud.enabled() {
+                self.pmtud.on_loss_detection(&self.application.flight);
+            }
+
+            if let Some(qlog) = self.qlog.as_mut() {
+                qlog.add_recovery_events(now, &self.recovery);
+            }
+
             return;
         }
     }
@@ -1828,6 +3256,57 @@ impl Connection {
         self.tls_state.get_alpn_protocol()
     }
 
+    /// Returns the server name requested by the client.
+    ///
+    /// On the server this is the name sent by the client in the SNI
+    /// extension, while on the client it's the name passed to [`connect()`].
+    ///
+    /// [`connect()`]: fn.connect.html
+    pub fn server_name(&self) -> Option<&str> {
+        self.tls_state.get_server_name()
+    }
+
+    /// Returns the peer's leaf certificate, DER-encoded.
+    ///
+    /// This is only available once the peer's certificate has been received
+    /// during the handshake.
+    pub fn peer_cert(&self) -> Option<&[u8]> {
+        self.tls_state
+            .get_peer_cert_chain()
+            .and_then(|chain| chain.first().cloned())
+    }
+
+    /// Returns the peer's certificate chain, DER-encoded and starting with the
+    /// leaf certificate.
+    pub fn peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+        self.tls_state.get_peer_cert_chain()
+    }
+
+    /// Returns true if the peer presented a certificate which was
+    /// successfully verified.
+    ///
+    /// On servers using [`set_client_auth()`], this tells whether the
+    /// identity in the client's [`peer_cert()`] can be trusted.
+    ///
+    /// [`set_client_auth()`]: struct.Config.html#method.set_client_auth
+    /// [`peer_cert()`]: struct.Connection.html#method.peer_cert
+    pub fn is_peer_verified(&self) -> bool {
+        self.tls_state.is_peer_verified()
+    }
+
+    /// Returns the IANA name of the negotiated TLS cipher suite.
+    ///
+    /// This is only available once the handshake has progressed far enough
+    /// to negotiate it.
+    pub fn cipher_suite(&self) -> Option<&'static str> {
+        self.tls_state.cipher().ok().map(|alg| alg.cipher_suite())
+    }
+
+    /// Returns the name of the negotiated key exchange group.
+    pub fn group(&self) -> Option<&'static str> {
+        self.tls_state.group()
+    }
+
     /// Returns true if the connection handshake is complete.
     pub fn is_established(&self) -> bool {
         self.handshake_completed
@@ -1845,24 +3324,141 @@ impl Connection {
         self.closed
     }
 
-    /// Collects and returns statistics about the connection.
-    pub fn stats(&self) -> Stats {
-        Stats {
-            sent: self.sent_count,
-            lost: self.lost_count,
-            rtt: self.recovery.rtt(),
-        }
+    /// Enables qlog logging of the connection's events to the given writer.
+    ///
+    /// Events are serialized as JSON as they happen, following the qlog
+    /// draft-01 schema, and the document is completed when the connection is
+    /// dropped. The `title` parameter is used as the trace's title.
+    pub fn set_qlog(
+        &mut self, writer: Box<dyn std::io::Write + Send + Sync>, title: &str,
+    ) {
+        self.set_qlog_at(writer, title, time::Instant::now())
     }
 
-    /// Continues the handshake.
+    /// Enables qlog logging of the connection's events to the given writer.
     ///
-    /// If the connection is already established, it does nothing.
-    fn do_handshake(&mut self) -> Result<()> {
-        if !self.handshake_completed {
-            match self.tls_state.do_handshake() {
+    /// This is the same as [`set_qlog()`], except that `now` is used as the
+    /// current time instead of reading the system clock, so that event times
+    /// are consistent with the ones passed to [`send_at()`] and
+    /// [`recv_at()`].
+    ///
+    /// [`set_qlog()`]: struct.Connection.html#method.set_qlog
+    /// [`send_at()`]: struct.Connection.html#method.send_at
+    /// [`recv_at()`]: struct.Connection.html#method.recv_at
+    pub fn set_qlog_at(
+        &mut self, writer: Box<dyn std::io::Write + Send + Sync>, title: &str,
+        now: time::Instant,
+    ) {
+        let vantage_point = if self.is_server { "server" } else { "client" };
+
+        let start_time = *self.start_time.get_or_insert(now);
+
+        let mut qlog = qlog::QlogStreamer::new(
+            writer,
+            title,
+            vantage_point,
+            &self.trace_id,
+            start_time,
+        );
+
+        let data =
+            qlog::transport_params_data("local", &self.local_transport_params);
+
+        qlog.add_event(now, "transport", "parameters_set", &data);
+
+        self.qlog = Some(qlog);
+    }
+
+    /// Sets the writer the connection's secrets are logged to.
+    ///
+    /// Each secret is written as a single line in the [keylog] format. This
+    /// replaces the writer set with [`log_keys()`] on the `Config` used to
+    /// create the connection, if any.
+    ///
+    /// [`log_keys()`]: struct.Config.html#method.log_keys
+    /// [keylog]: https://developer.mozilla.org/en-US/docs/Mozilla/Projects/NSS/Key_Log_Format
+    pub fn set_keylog(&mut self, writer: Box<dyn std::io::Write + Send>) {
+        self.keylog = Some(Arc::new(Mutex::new(writer)));
+    }
+
+    /// Installs an observer that receives the connection's events.
+    ///
+    /// Any previously installed observer is replaced.
+    pub fn set_observer(&mut self, observer: Box<dyn ConnectionObserver>) {
+        self.observer = Some(observer);
+
+        // Lost packet numbers are only recorded when they can be reported.
+        self.initial.flight.track_lost_pkts = true;
+        self.handshake.flight.track_lost_pkts = true;
+        self.application.flight.track_lost_pkts = true;
+    }
+
+    /// Collects and returns statistics about the connection.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            sent: self.sent_count,
+            recv: self.recv_count,
+            lost: self.lost_count,
+            sent_bytes: self.sent_bytes,
+            recv_bytes: self.recv_bytes,
+            retrans_bytes: self.retrans_bytes,
+            rtt: self.recovery.rtt(),
+            min_rtt: self.recovery.min_rtt(),
+            rttvar: self.recovery.rttvar(),
+            cwnd: self.recovery.congestion_window(),
+            bytes_in_flight: self.recovery.bytes_in_flight(),
+            pto_count: self.recovery.pto_count(),
+            pmtu: self.pmtud.mtu(),
+            local_streams: self.streams.local_opened_streams(),
+            peer_streams: self.streams.peer_opened_streams(),
+            data_blocked: self.data_blocked_count,
+            stream_data_blocked: self.stream_data_blocked_count,
+            handshake_duration: self.handshake_duration,
+        }
+    }
+
+    /// Continues the handshake.
+    ///
+    /// If the connection is already established, it does nothing.
+    fn do_handshake(&mut self, now: time::Instant) -> Result<()> {
+        if !self.handshake_completed {
+            let start_time = *self.start_time.get_or_insert(now);
+
+            let had_handshake_keys = self.handshake.crypto_open.is_some();
+            let had_application_keys = self.application.crypto_open.is_some();
+
+            let res = self.tls_state.do_handshake();
+
+            if let Some(qlog) = self.qlog.as_mut() {
+                if !had_handshake_keys && self.handshake.crypto_open.is_some() {
+                    for key_type in &["client", "server"] {
+                        let data = qlog::key_updated_data(&format!(
+                            "{}_handshake_secret",
+                            key_type
+                        ));
+
+                        qlog.add_event(now, "security", "key_updated", &data);
+                    }
+                }
+
+                if !had_application_keys && self.application.crypto_open.is_some()
+                {
+                    for key_type in &["client", "server"] {
+                        let data = qlog::key_updated_data(&format!(
+                            "{}_1rtt_secret",
+                            key_type
+                        ));
+
+                        qlog.add_event(now, "security", "key_updated", &data);
+                    }
+                }
+            }
+
+            match res {
                 Ok(_) => {
                     // Handshake is complete!
                     self.handshake_completed = true;
+                    self.handshake_duration = Some(now - start_time);
 
                     let mut raw_params =
                         self.tls_state.get_quic_transport_params().to_vec();
@@ -1888,15 +3484,30 @@ impl Connection {
 
                     self.recovery.max_ack_delay =
                         time::Duration::from_millis(peer_params.max_ack_delay);
+                    self.recovery.handshake_completed = true;
+
+                    if let Some(qlog) = self.qlog.as_mut() {
+                        let data =
+                            qlog::transport_params_data("remote", &peer_params);
+
+                        qlog.add_event(now, "transport", "parameters_set", &data);
+                    }
 
                     self.peer_transport_params = peer_params;
 
+                    // Never probe beyond the packet size the peer accepts.
+                    self.pmtud.set_max_mtu(self.peer_max_pkt_len());
+
                     trace!("{} connection established: cipher={:?} proto={:?} resumed={} {:?}",
                            &self.trace_id,
                            self.tls_state.cipher(),
                            std::str::from_utf8(self.application_proto()),
                            self.is_resumed(),
                            self.peer_transport_params);
+
+                    if let Some(o) = self.observer.as_mut() {
+                        o.on_handshake_completed();
+                    }
                 },
 
                 Err(tls::Error::TlsFail) => {
@@ -1916,28 +3527,61 @@ impl Connection {
         Ok(())
     }
 
+    /// Encodes the local transport parameters for the current version, and
+    /// passes them on to the TLS stack.
+    fn set_transport_params(&mut self) -> Result<()> {
+        let mut raw_params = [0; 256];
+
+        let raw_params = TransportParams::encode(
+            &self.local_transport_params,
+            self.version,
+            &self.versions,
+            self.is_server,
+            &mut raw_params,
+        )?;
+
+        self.tls_state
+            .set_quic_transport_params(raw_params)
+            .map_err(|_| Error::TlsFail)
+    }
+
     /// Selects the type for the outgoing packet depending on whether there is
     /// handshake data to send, whether there are packets to ACK, or whether
     /// there are streams that can be written or that needs to increase flow
     /// control credit.
     fn select_egress_pkt_type(&self) -> Result<Type> {
         let ty =
-            // On error or probe, send packet in the latest space available.
-            if self.error.is_some() || self.recovery.probes > 0 {
+            // On error, send packet in the latest space available.
+            if self.error.is_some() {
                 match self.tls_state.get_write_level() {
                     crypto::Level::Initial     => Type::Initial,
                     crypto::Level::ZeroRTT     => unreachable!(),
                     crypto::Level::Handshake   => Type::Handshake,
                     crypto::Level::Application => Type::Application,
                 }
+            } else if let Some(epoch) = self.recovery.probe_epoch() {
+                // On PTO, send probe in the space the timer expired for.
+                match epoch {
+                    packet::EPOCH_INITIAL   => Type::Initial,
+                    packet::EPOCH_HANDSHAKE => Type::Handshake,
+                    _                       => Type::Application,
+                }
             } else if self.initial.ready() {
                 Type::Initial
             } else if self.handshake.ready() {
                 Type::Handshake
             } else if self.handshake_completed &&
                       (self.application.ready() ||
+                       self.app_error.is_some() ||
+                       self.challenge.is_some() ||
                        self.streams.has_writable() ||
-                       self.streams.has_out_of_credit()) {
+                       self.should_update_max_data() ||
+                       self.streams.has_out_of_credit() ||
+                       self.streams.should_update_max_streams_bidi() ||
+                       self.streams.should_update_max_streams_uni() ||
+                       self.streams.has_blocked() ||
+                       self.dgram_send_queue.has_pending() ||
+                       self.pmtud.should_probe()) {
                 Type::Application
             } else {
                 return Err(Error::Done);
@@ -1946,13 +3590,49 @@ impl Connection {
         Ok(ty)
     }
 
+    /// Returns true if a MAX_DATA frame should be sent.
+    fn should_update_max_data(&self) -> bool {
+        self.new_max_rx_data != self.max_rx_data &&
+            self.new_max_rx_data / 2 > self.max_rx_data - self.rx_data
+    }
+
+    /// Returns the largest packet size allowed by the peer.
+    fn peer_max_pkt_len(&self) -> usize {
+        // We cap the maximum packet size to 16KB or so, so that it can be
+        // always encoded with a 2-byte varint.
+        cmp::min(
+            MAX_SEND_PKT_LEN,
+            self.peer_transport_params.max_packet_size as usize,
+        )
+    }
+
+    /// Returns the largest size of packets, except PMTU probes, that can
+    /// currently be sent.
+    fn max_send_pkt_len(&self) -> usize {
+        // Use max_packet_size as sent by the peer, except during the handshake
+        // when we haven't parsed transport parameters yet, so use a default
+        // value then.
+        if !self.handshake_completed {
+            // Allow for 1200 bytes (minimum QUIC packet size) during the
+            // handshake.
+            return 1200;
+        }
+
+        if self.pmtud.enabled() {
+            return self.pmtud.mtu();
+        }
+
+        self.peer_max_pkt_len()
+    }
+
     /// Drops the initial keys and recovery state.
     fn drop_initial_state(&mut self) {
         if self.initial.crypto_open.is_none() {
             return;
         }
 
-        self.recovery.drop_unacked_data(&mut self.initial.flight);
+        self.recovery
+            .drop_unacked_data(packet::EPOCH_INITIAL, &mut self.initial.flight);
         self.initial.crypto_open = None;
         self.initial.crypto_seal = None;
         self.initial.clear();
@@ -1961,6 +3641,39 @@ impl Connection {
     }
 }
 
+/// Receives events about a connection as they happen.
+///
+/// An observer can be installed on a connection using the [`set_observer()`]
+/// method. All methods have an empty default implementation, so only the
+/// events of interest need to be implemented.
+///
+/// [`set_observer()`]: struct.Connection.html#method.set_observer
+pub trait ConnectionObserver: Send {
+    /// Called when the handshake is completed.
+    fn on_handshake_completed(&mut self) {}
+
+    /// Called when the packet `pkt_num` of type `ty` is declared lost.
+    fn on_packet_lost(&mut self, _ty: Type, _pkt_num: u64) {}
+
+    /// Called when a stream is opened, either locally or by the peer.
+    fn on_stream_opened(&mut self, _stream_id: u64) {}
+
+    /// Called when a stream is closed and its state is released.
+    fn on_stream_closed(&mut self, _stream_id: u64) {}
+
+    /// Called when the path to the peer is validated.
+    fn on_path_validated(&mut self) {}
+
+    /// Called when the peer closes the connection.
+    ///
+    /// The `app` parameter specifies whether the peer sent an application
+    /// close rather than a transport close.
+    fn on_close_received(
+        &mut self, _app: bool, _error_code: u16, _reason: &[u8],
+    ) {
+    }
+}
+
 /// Statistics about the connection.
 ///
 /// A connections's statistics can be collected using the [`stats()`] method.
@@ -1971,20 +3684,94 @@ pub struct Stats {
     /// The number of QUIC packets sent on this connection.
     pub sent: usize,
 
+    /// The number of QUIC packets received on this connection.
+    pub recv: usize,
+
     /// The number of QUIC packets that were lost.
     pub lost: usize,
 
-    /// The estimated round-trip time of the connection.
+    /// The number of bytes sent on this connection.
+    pub sent_bytes: u64,
+
+    /// The number of bytes received on this connection.
+    pub recv_bytes: u64,
+
+    /// The number of stream and crypto data bytes that were retransmitted.
+    pub retrans_bytes: u64,
+
+    /// The estimated round-trip time of the connection, i.e. the smoothed RTT.
     pub rtt: time::Duration,
+
+    /// The minimum round-trip time observed, if any.
+    pub min_rtt: Option<time::Duration>,
+
+    /// The estimated round-trip time variation.
+    pub rttvar: time::Duration,
+
+    /// The size of the congestion window in bytes.
+    pub cwnd: usize,
+
+    /// The number of bytes in flight.
+    pub bytes_in_flight: usize,
+
+    /// The number of consecutive PTO expirations.
+    pub pto_count: u32,
+
+    /// The path MTU validated by path MTU discovery, or the minimum QUIC
+    /// packet size when discovery is disabled.
+    pub pmtu: usize,
+
+    /// The number of streams opened by the local endpoint.
+    pub local_streams: usize,
+
+    /// The number of streams opened by the peer.
+    pub peer_streams: usize,
+
+    /// The number of times connection-level flow control blocked sending,
+    /// i.e. the number of DATA_BLOCKED frames sent.
+    pub data_blocked: usize,
+
+    /// The number of times stream-level flow control blocked sending, i.e.
+    /// the number of STREAM_DATA_BLOCKED frames sent.
+    pub stream_data_blocked: usize,
+
+    /// The time it took to complete the handshake, if it did.
+    pub handshake_duration: Option<time::Duration>,
 }
 
 impl std::fmt::Debug for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "sent={} lost={} rtt={:?}",
-            self.sent, self.lost, self.rtt
-        )
+            "sent={} recv={} lost={} ",
+            self.sent, self.recv, self.lost
+        )?;
+        write!(
+            f,
+            "sent_bytes={} recv_bytes={} retrans_bytes={} ",
+            self.sent_bytes, self.recv_bytes, self.retrans_bytes
+        )?;
+        write!(
+            f,
+            "rtt={:?} min_rtt={:?} rttvar={:?} ",
+            self.rtt, self.min_rtt, self.rttvar
+        )?;
+        write!(
+            f,
+            "cwnd={} bytes_in_flight={} pto_count={} pmtu={} ",
+            self.cwnd, self.bytes_in_flight, self.pto_count, self.pmtu
+        )?;
+        write!(
+            f,
+            "local_streams={} peer_streams={} ",
+            self.local_streams, self.peer_streams
+        )?;
+        write!(
+            f,
+            "data_blocked={} stream_data_blocked={} ",
+            self.data_blocked, self.stream_data_blocked
+        )?;
+        write!(f, "handshake_duration={:?}", self.handshake_duration)
     }
 }
 
@@ -2004,6 +3791,7 @@ struct TransportParams {
     pub max_ack_delay: u64,
     pub disable_migration: bool,
     // pub preferred_address: ...
+    pub max_datagram_frame_size: Option<u64>,
 }
 
 impl Default for TransportParams {
@@ -2022,6 +3810,7 @@ impl Default for TransportParams {
             ack_delay_exponent: 3,
             max_ack_delay: 25,
             disable_migration: false,
+            max_datagram_frame_size: None,
         }
     }
 }
@@ -2120,6 +3909,10 @@ impl TransportParams {
                     // TODO: decode preferred_address
                 },
 
+                0x0020 => {
+                    tp.max_datagram_frame_size = Some(val.get_varint()?);
+                },
+
                 // Ignore unknown parameters.
                 _ => (),
             }
@@ -2129,7 +3922,8 @@ impl TransportParams {
     }
 
     fn encode<'a>(
-        tp: &TransportParams, version: u32, is_server: bool, out: &'a mut [u8],
+        tp: &TransportParams, version: u32, versions: &[u32], is_server: bool,
+        out: &'a mut [u8],
     ) -> Result<&'a mut [u8]> {
         let mut params = [0; 128];
 
@@ -2225,6 +4019,12 @@ impl TransportParams {
 
             // TODO: encode preferred_address
 
+            if let Some(max_datagram_frame_size) = tp.max_datagram_frame_size {
+                b.put_u16(0x0020)?;
+                b.put_u16(octets::varint_len(max_datagram_frame_size) as u16)?;
+                b.put_varint(max_datagram_frame_size)?;
+            }
+
             b.off()
         };
 
@@ -2234,8 +4034,11 @@ impl TransportParams {
             b.put_u32(version)?;
 
             if is_server {
-                b.put_u8(mem::size_of::<u32>() as u8)?;
-                b.put_u32(version)?;
+                b.put_u8((versions.len() * mem::size_of::<u32>()) as u8)?;
+
+                for v in versions {
+                    b.put_u32(*v)?;
+                }
             };
 
             b.put_u16(params_len as u16)?;
@@ -2279,7 +4082,12 @@ impl std::fmt::Debug for TransportParams {
             self.initial_max_streams_uni
         )?;
         write!(f, "ack_delay_exponent={} ", self.ack_delay_exponent)?;
-        write!(f, "disable_migration={}", self.disable_migration)?;
+        write!(f, "disable_migration={} ", self.disable_migration)?;
+        write!(
+            f,
+            "max_datagram_frame_size={:?}",
+            self.max_datagram_frame_size
+        )?;
 
         Ok(())
     }
@@ -2296,49 +4104,42 @@ mod tests {
 
     impl Pipe {
         fn new() -> Result<Pipe> {
+            Pipe::with_config(&mut default_config()?)
+        }
+
+        fn with_config(config: &mut Config) -> Result<Pipe> {
             let mut client_scid = [0; 16];
             rand::rand_bytes(&mut client_scid[..]);
 
             let mut server_scid = [0; 16];
             rand::rand_bytes(&mut server_scid[..]);
 
-            let mut config = Config::new(crate::VERSION_DRAFT17)?;
-            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-            config.load_priv_key_from_pem_file("examples/cert.key")?;
-            config.set_application_protos(&[b"proto1", b"proto2"])?;
-            config.set_initial_max_data(30);
-            config.set_initial_max_stream_data_bidi_local(15);
-            config.set_initial_max_stream_data_bidi_remote(15);
-            config.set_initial_max_streams_bidi(3);
-            config.set_initial_max_streams_uni(3);
-            config.verify_peer(false);
-
             Ok(Pipe {
-                client: connect(Some("quic.tech"), &client_scid, &mut config)?,
-                server: accept(&server_scid, None, &mut config)?,
+                client: connect(Some("quic.tech"), &client_scid, config)?,
+                server: accept(&server_scid, None, config)?,
             })
         }
 
         fn with_client_config(client_config: &mut Config) -> Result<Pipe> {
+            Pipe::with_configs(client_config, &mut default_config()?)
+        }
+
+        fn with_server_config(server_config: &mut Config) -> Result<Pipe> {
+            Pipe::with_configs(&mut default_config()?, server_config)
+        }
+
+        fn with_configs(
+            client_config: &mut Config, server_config: &mut Config,
+        ) -> Result<Pipe> {
             let mut client_scid = [0; 16];
             rand::rand_bytes(&mut client_scid[..]);
 
             let mut server_scid = [0; 16];
             rand::rand_bytes(&mut server_scid[..]);
 
-            let mut config = Config::new(crate::VERSION_DRAFT17)?;
-            config.load_cert_chain_from_pem_file("examples/cert.crt")?;
-            config.load_priv_key_from_pem_file("examples/cert.key")?;
-            config.set_application_protos(&[b"proto1", b"proto2"])?;
-            config.set_initial_max_data(30);
-            config.set_initial_max_stream_data_bidi_local(15);
-            config.set_initial_max_stream_data_bidi_remote(15);
-            config.set_initial_max_streams_bidi(3);
-            config.set_initial_max_streams_uni(3);
-
             Ok(Pipe {
                 client: connect(Some("quic.tech"), &client_scid, client_config)?,
-                server: accept(&server_scid, None, &mut config)?,
+                server: accept(&server_scid, None, server_config)?,
             })
         }
 
@@ -2372,6 +4173,22 @@ mod tests {
             Ok(())
         }
 
+        /// Like `advance()`, but delivers each packet in its own datagram,
+        /// as short header packets can't be coalesced.
+        fn advance_each(&mut self, buf: &mut [u8]) -> Result<()> {
+            let mut client_done = false;
+            let mut server_done = false;
+
+            while !client_done || !server_done {
+                client_done =
+                    deliver_each(&mut self.client, &mut self.server, buf)? == 0;
+                server_done =
+                    deliver_each(&mut self.server, &mut self.client, buf)? == 0;
+            }
+
+            Ok(())
+        }
+
         fn send_pkt_to_server(
             &mut self, pkt_type: packet::Type, frames: &[frame::Frame],
             buf: &mut [u8],
@@ -2381,6 +4198,45 @@ mod tests {
         }
     }
 
+    fn deliver_each(
+        from: &mut Connection, to: &mut Connection, buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        loop {
+            let len = match from.send(buf) {
+                Ok(v) => v,
+
+                Err(Error::Done) => break,
+
+                Err(e) => return Err(e),
+            };
+
+            to.recv(&mut buf[..len])?;
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Returns the configuration used by `Pipe::new()`, which works for both
+    /// the client and the server.
+    fn default_config() -> Result<Config> {
+        let mut config = Config::new(crate::VERSION_DRAFT17)?;
+        config.load_cert_chain_from_pem_file("examples/cert.crt")?;
+        config.load_priv_key_from_pem_file("examples/cert.key")?;
+        config.set_application_protos(&[b"proto1", b"proto2"])?;
+        config.set_initial_max_data(30);
+        config.set_initial_max_stream_data_bidi_local(15);
+        config.set_initial_max_stream_data_bidi_remote(15);
+        config.set_initial_max_streams_bidi(3);
+        config.set_initial_max_streams_uni(3);
+        config.verify_peer(false);
+
+        Ok(config)
+    }
+
     fn recv_send(
         conn: &mut Connection, buf: &mut [u8], len: usize,
     ) -> Result<usize> {
@@ -2413,6 +4269,28 @@ mod tests {
         Ok(off)
     }
 
+    /// A writer appending to a buffer that can be inspected after the writer
+    /// is moved.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
     fn encode_pkt(
         conn: &mut Connection, pkt_type: packet::Type, frames: &[frame::Frame],
         buf: &mut [u8],
@@ -2447,8 +4325,21 @@ mod tests {
 
         hdr.to_bytes(&mut b)?;
 
-        let payload_len =
-            frames.iter().fold(0, |acc, x| acc + x.wire_len()) + space.overhead();
+        let frames_len = frames.iter().fold(0, |acc, x| acc + x.wire_len());
+
+        // Pad payload so that it's always at least 4 bytes, as the header
+        // protection sample would be out of bounds otherwise.
+        let padding = if frames_len < PAYLOAD_MIN_LEN {
+            Some(frame::Frame::Padding {
+                len: PAYLOAD_MIN_LEN - frames_len,
+            })
+        } else {
+            None
+        };
+
+        let payload_len = frames_len +
+            padding.as_ref().map_or(0, |f| f.wire_len()) +
+            space.overhead();
 
         if pkt_type != packet::Type::Application {
             let len = pn_len + payload_len;
@@ -2459,7 +4350,7 @@ mod tests {
 
         let payload_offset = b.off();
 
-        for frame in frames {
+        for frame in frames.iter().chain(padding.iter()) {
             frame.to_bytes(&mut b)?;
         }
 
@@ -2531,13 +4422,19 @@ mod tests {
             ack_delay_exponent: 123,
             max_ack_delay: 1234,
             disable_migration: true,
+            max_datagram_frame_size: Some(32),
         };
 
         let mut raw_params = [42; 256];
-        let mut raw_params =
-            TransportParams::encode(&tp, VERSION_DRAFT17, true, &mut raw_params)
-                .unwrap();
-        assert_eq!(raw_params.len(), 106);
+        let mut raw_params = TransportParams::encode(
+            &tp,
+            VERSION_DRAFT17,
+            &[VERSION_DRAFT17],
+            true,
+            &mut raw_params,
+        )
+        .unwrap();
+        assert_eq!(raw_params.len(), 111);
 
         let new_tp =
             TransportParams::decode(&mut raw_params, VERSION_DRAFT17, false)
@@ -2570,7 +4467,13 @@ mod tests {
         let mut len = pipe.client.send(&mut buf).unwrap();
 
         let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
-        len = crate::negotiate_version(&hdr.scid, &hdr.dcid, &mut buf).unwrap();
+        len = crate::negotiate_version(
+            &hdr.scid,
+            &hdr.dcid,
+            &default_config().unwrap(),
+            &mut buf,
+        )
+        .unwrap();
 
         assert_eq!(pipe.client.recv(&mut buf[..len]), Err(Error::Done));
 
@@ -2578,276 +4481,1580 @@ mod tests {
     }
 
     #[test]
-    fn handshake() {
+    fn version_negotiation_preference() {
         let mut buf = [0; 65535];
 
-        let mut pipe = Pipe::new().unwrap();
+        let mut config = Config::new(0xbabababa).unwrap();
+        config.verify_peer(false);
 
-        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
 
-        assert_eq!(
-            pipe.client.application_proto(),
-            pipe.server.application_proto()
-        );
+        let mut len = pipe.client.send(&mut buf).unwrap();
+
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        len = packet::negotiate_version(
+            &hdr.scid,
+            &hdr.dcid,
+            &[0x1a2a_3a4a, VERSION_DRAFT17],
+            &mut buf,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.client.recv(&mut buf[..len]), Err(Error::Done));
+
+        // The client picks the only version it shares with the server.
+        assert_eq!(pipe.client.version, VERSION_DRAFT17);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.server.version, VERSION_DRAFT17);
     }
 
     #[test]
-    fn handshake_alpn_mismatch() {
+    fn version_negotiation_no_common_version() {
         let mut buf = [0; 65535];
 
-        let mut config = Config::new(VERSION_DRAFT17).unwrap();
-        config
-            .set_application_protos(&[b"proto3", b"proto4"])
-            .unwrap();
+        let mut config = Config::new(0xbabababa).unwrap();
         config.verify_peer(false);
 
         let mut pipe = Pipe::with_client_config(&mut config).unwrap();
 
-        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        let mut len = pipe.client.send(&mut buf).unwrap();
 
-        assert_eq!(pipe.client.application_proto(), b"");
-        assert_eq!(pipe.server.application_proto(), b"");
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        len = packet::negotiate_version(
+            &hdr.scid,
+            &hdr.dcid,
+            &[0x1a2a_3a4a],
+            &mut buf,
+        )
+        .unwrap();
+
+        assert_eq!(
+            pipe.client.recv(&mut buf[..len]),
+            Err(Error::UnknownVersion)
+        );
     }
 
     #[test]
-    fn stream() {
+    fn version_negotiation_switch() {
         let mut buf = [0; 65535];
 
-        let mut pipe = Pipe::new().unwrap();
+        let mut client_config = Config::new(VERSION_DRAFT18).unwrap();
+        client_config.verify_peer(false);
 
-        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        let mut server_config = default_config().unwrap();
+        server_config.set_versions(&[VERSION_DRAFT17]).unwrap();
 
-        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+        let mut pipe =
+            Pipe::with_configs(&mut client_config, &mut server_config).unwrap();
 
-        assert_eq!(pipe.advance(&mut buf), Ok(()));
+        let mut len = pipe.client.send(&mut buf).unwrap();
 
-        let mut r = pipe.server.readable();
-        assert_eq!(r.next(), Some(4));
-        assert_eq!(r.next(), None);
+        // The server doesn't accept draft-18, so it asks the client to use
+        // one of its versions instead.
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.version, VERSION_DRAFT18);
+        assert!(!crate::version_is_supported(&server_config, hdr.version));
+
+        len = crate::negotiate_version(
+            &hdr.scid,
+            &hdr.dcid,
+            &server_config,
+            &mut buf,
+        )
+        .unwrap();
 
-        let mut b = [0; 15];
-        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
-        assert_eq!(&b[..12], b"hello, world");
+        assert_eq!(pipe.client.recv(&mut buf[..len]), Err(Error::Done));
+        assert_eq!(pipe.client.version, VERSION_DRAFT17);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.server.version, VERSION_DRAFT17);
     }
 
     #[test]
-    fn flow_control() {
+    fn server_multiple_versions() {
         let mut buf = [0; 65535];
 
-        let mut pipe = Pipe::new().unwrap();
-
-        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        // The pipe's server config starts with draft-17, but accepts both.
+        for &version in &[VERSION_DRAFT17, VERSION_DRAFT18] {
+            let mut config = Config::new(version).unwrap();
+            config.verify_peer(false);
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+            let mut pipe = Pipe::with_client_config(&mut config).unwrap();
 
-        let pkt_type = packet::Type::Application;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+            assert_eq!(pipe.handshake(&mut buf), Ok(()));
+            assert_eq!(pipe.client.version, version);
+            assert_eq!(pipe.server.version, version);
+        }
     }
 
     #[test]
-    fn stream_flow_control() {
+    fn negotiate_version_config() {
+        let mut buf = [0; 65535];
+
+        let config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert!(crate::version_is_supported(&config, VERSION_DRAFT17));
+        assert!(!crate::version_is_supported(&config, 0xbabababa));
+
+        let len =
+            crate::negotiate_version(&[0xba; 8], &[0xab; 8], &config, &mut buf)
+                .unwrap();
+
+        let hdr = packet::Header::from_slice(&mut buf[..len], 0).unwrap();
+        assert_eq!(hdr.ty, packet::Type::VersionNegotiation);
+        assert_eq!(hdr.versions, Some(vec![VERSION_DRAFT18, VERSION_DRAFT17]));
+    }
+
+    #[test]
+    fn set_versions() {
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert_eq!(config.set_versions(&[]), Err(Error::UnknownVersion));
+        assert_eq!(
+            config.set_versions(&[VERSION_DRAFT17, 0xbabababa]),
+            Err(Error::UnknownVersion)
+        );
+        assert_eq!(config.set_versions(&[VERSION_DRAFT17]), Ok(()));
+        assert_eq!(
+            config.set_versions(&[VERSION_DRAFT17, VERSION_DRAFT18]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(
+            pipe.client.application_proto(),
+            pipe.server.application_proto()
+        );
+    }
+
+    #[test]
+    fn handshake_alpn_mismatch() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config
+            .set_application_protos(&[b"proto3", b"proto4"])
+            .unwrap();
+        config.verify_peer(false);
+
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.application_proto(), b"");
+        assert_eq!(pipe.server.application_proto(), b"");
+    }
+
+    #[test]
+    fn handshake_in_memory_credentials() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert_eq!(config.load_cert_chain_from_pem(b""), Err(Error::TlsFail));
+        assert_eq!(config.load_cert_chain_from_der(&[]), Err(Error::TlsFail));
+        assert_eq!(config.load_priv_key_from_pem(b""), Err(Error::TlsFail));
+        assert_eq!(config.load_priv_key_from_der(b""), Err(Error::TlsFail));
+
+        config
+            .load_cert_chain_from_pem(include_bytes!("../examples/cert.crt"))
+            .unwrap();
+        config
+            .load_priv_key_from_pem(include_bytes!("../examples/cert.key"))
+            .unwrap();
+        config
+            .load_verify_locations_from_pem(include_bytes!(
+                "../examples/cert.crt"
+            ))
+            .unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert!(pipe.client.is_established());
+        assert!(pipe.server.is_established());
+    }
+
+    #[test]
+    fn handshake_cipher_suites_and_groups() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+
+        assert_eq!(config.set_cipher_suites(&[]), Err(Error::TlsFail));
+        assert_eq!(
+            config.set_cipher_suites(&["TLS_AES_128_CCM_SHA256"]),
+            Err(Error::TlsFail)
+        );
+
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config
+            .set_cipher_suites(&[
+                "TLS_AES_128_GCM_SHA256",
+                "TLS_CHACHA20_POLY1305_SHA256",
+            ])
+            .unwrap();
+
+        #[cfg(not(feature = "no_bssl"))]
+        config.set_groups(&["X25519"]).unwrap();
+
+        // rustls doesn't allow configuring key exchange groups.
+        #[cfg(feature = "no_bssl")]
+        assert_eq!(config.set_groups(&["X25519"]), Err(Error::TlsFail));
+
+        config.verify_peer(false);
+
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+        assert_eq!(pipe.client.cipher_suite(), None);
+        assert_eq!(pipe.client.group(), None);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_ne!(pipe.client.cipher_suite(), Some("TLS_AES_256_GCM_SHA384"));
+        assert_eq!(pipe.client.cipher_suite(), pipe.server.cipher_suite());
+
+        #[cfg(not(feature = "no_bssl"))]
+        {
+            assert_eq!(pipe.client.group(), Some("X25519"));
+            assert_eq!(pipe.server.group(), Some("X25519"));
+        }
+    }
+
+    #[test]
+    fn handshake_cipher_suites_aes256() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config
+            .set_cipher_suites(&["TLS_AES_256_GCM_SHA384"])
+            .unwrap();
+
+        // BoringSSL servers never prefer AES-256, so the client aborts the
+        // handshake when it sees the suite picked by the server.
+        #[cfg(not(feature = "no_bssl"))]
+        {
+            let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+            let len = pipe.client.send(&mut buf).unwrap();
+            let len = recv_send(&mut pipe.server, &mut buf, len).unwrap();
+
+            pipe.client.recv(&mut buf[..len]).ok();
+            pipe.client.send(&mut buf).ok();
+
+            assert!(!pipe.client.is_established());
+            assert_eq!(pipe.client.error, Some(0x100 + 40));
+        }
+
+        #[cfg(feature = "no_bssl")]
+        {
+            let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+            assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+            assert_eq!(
+                pipe.client.cipher_suite(),
+                Some("TLS_AES_256_GCM_SHA384")
+            );
+            assert_eq!(
+                pipe.server.cipher_suite(),
+                Some("TLS_AES_256_GCM_SHA384")
+            );
+        }
+    }
+
+    #[test]
+    fn handshake_ticket_keys() {
+        let mut buf = [0; 65535];
+
+        let current = [1; 48];
+        let next = [2; 48];
+
+        let mut config = default_config().unwrap();
+
+        assert_eq!(config.set_ticket_keys(&[]), Err(Error::TlsFail));
+        assert_eq!(config.set_ticket_keys(&[&[0; 47]]), Err(Error::TlsFail));
+        assert_eq!(
+            config.set_ticket_keys(&[&current, &next[..16]]),
+            Err(Error::TlsFail)
+        );
+
+        // Unlike `Pipe::handshake()`, this also delivers the server's last
+        // flight to the client, as it carries the session ticket.
+        fn connect(config: &mut Config, buf: &mut [u8]) -> Pipe {
+            let mut pipe = Pipe::with_config(config).unwrap();
+
+            let mut len = pipe.client.send(buf).unwrap();
+
+            while !pipe.client.is_established() || !pipe.server.is_established() {
+                len = recv_send(&mut pipe.server, buf, len).unwrap();
+                len = recv_send(&mut pipe.client, buf, len).unwrap();
+            }
+
+            pipe
+        }
+
+        assert_eq!(config.set_ticket_keys(&[&current]), Ok(()));
+
+        // A ticket is issued with the current key.
+        let pipe = connect(&mut config, &mut buf);
+        assert!(!pipe.client.is_resumed());
+        assert!(!pipe.server.is_resumed());
+
+        // BoringSSL clients can't resume sessions yet.
+        #[cfg(feature = "no_bssl")]
+        {
+            let pipe = connect(&mut config, &mut buf);
+            assert!(pipe.client.is_resumed());
+            assert!(pipe.server.is_resumed());
+
+            // After rotation the ticket is decrypted with the previous key,
+            // now at index 1, and renewed with the new current one.
+            assert_eq!(config.set_ticket_keys(&[&next, &current]), Ok(()));
+
+            let pipe = connect(&mut config, &mut buf);
+            assert!(pipe.client.is_resumed());
+            assert!(pipe.server.is_resumed());
+
+            assert_eq!(config.set_ticket_keys(&[&next]), Ok(()));
+
+            let pipe = connect(&mut config, &mut buf);
+            assert!(pipe.client.is_resumed());
+            assert!(pipe.server.is_resumed());
+
+            // Tickets encrypted with unknown keys cause a full handshake.
+            assert_eq!(config.set_ticket_keys(&[&[3; 48]]), Ok(()));
+
+            let pipe = connect(&mut config, &mut buf);
+            assert!(!pipe.client.is_resumed());
+            assert!(!pipe.server.is_resumed());
+
+            // No ticket is issued when tickets are disabled.
+            let mut config = default_config().unwrap();
+            config.enable_session_tickets(false);
+
+            let pipe = connect(&mut config, &mut buf);
+            assert!(!pipe.client.is_resumed());
+
+            let pipe = connect(&mut config, &mut buf);
+            assert!(!pipe.client.is_resumed());
+            assert!(!pipe.server.is_resumed());
+        }
+    }
+
+    #[test]
+    fn handshake_server_name_callback() {
+        let mut buf = [0; 65535];
+
+        let mut cert_config = Config::new(VERSION_DRAFT17).unwrap();
+        cert_config
+            .load_cert_chain_from_pem_file("examples/cert.crt")
+            .unwrap();
+        cert_config
+            .load_priv_key_from_pem_file("examples/cert.key")
+            .unwrap();
+
+        let cert_config = Arc::new(cert_config);
+
+        // The server has no certificate of its own.
+        let mut server_config = Config::new(VERSION_DRAFT17).unwrap();
+        server_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        server_config.set_server_name_callback(move |name| {
+            if name == "quic.tech" {
+                Some(Arc::clone(&cert_config))
+            } else {
+                None
+            }
+        });
+
+        let mut pipe = Pipe::with_server_config(&mut server_config).unwrap();
+
+        assert_eq!(pipe.server.server_name(), None);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.server_name(), Some("quic.tech"));
+        assert_eq!(pipe.server.server_name(), Some("quic.tech"));
+    }
+
+    #[test]
+    fn handshake_verify_callback() {
+        let mut buf = [0; 65535];
+
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_verify_callback(|chain, name| {
+            !chain.is_empty() && name == Some("quic.tech")
+        });
+
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let cert = pipe.client.peer_cert().unwrap();
+        assert!(!cert.is_empty());
+        assert_eq!(pipe.client.peer_cert_chain().unwrap()[0], cert);
+
+        assert_eq!(pipe.server.peer_cert(), None);
+
+        // Reject the server's certificate.
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.set_verify_callback(|_, _| false);
+
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+        let len = pipe.client.send(&mut buf).unwrap();
+        let len = recv_send(&mut pipe.server, &mut buf, len).unwrap();
+
+        // The client closes the connection with a bad_certificate alert.
+        pipe.client.recv(&mut buf[..len]).ok();
+        pipe.client.send(&mut buf).ok();
+
+        assert!(!pipe.client.is_established());
+        assert_eq!(pipe.client.error, Some(0x100 + 42));
+    }
+
+    #[test]
+    fn handshake_client_auth() {
+        let mut buf = [0; 65535];
+
+        let mut server_config = default_config().unwrap();
+        server_config.set_client_auth(ClientAuth::Require);
+        server_config.set_verify_callback(|chain, _| !chain.is_empty());
+
+        // The default config has a certificate for the client to send.
+        let mut pipe = Pipe::with_server_config(&mut server_config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert!(pipe.server.peer_cert().is_some());
+        assert!(pipe.server.is_peer_verified());
+
+        // The client has no certificate to send.
+        let mut client_config = Config::new(VERSION_DRAFT17).unwrap();
+        client_config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        client_config.verify_peer(false);
+
+        let mut pipe =
+            Pipe::with_configs(&mut client_config, &mut server_config).unwrap();
+
+        let len = pipe.client.send(&mut buf).unwrap();
+        let len = recv_send(&mut pipe.server, &mut buf, len).unwrap();
+        let len = recv_send(&mut pipe.client, &mut buf, len).unwrap();
+
+        pipe.server.recv(&mut buf[..len]).ok();
+        pipe.server.send(&mut buf).ok();
+
+        assert!(!pipe.server.is_established());
+        assert!(pipe.server.error.is_some());
+        assert_eq!(pipe.server.peer_cert(), None);
+
+        // The callback is kept when client authentication is configured after
+        // it. The certificate is self-signed, so it's only accepted by the
+        // callback.
+        let mut server_config = default_config().unwrap();
+        server_config.set_verify_callback(|chain, _| !chain.is_empty());
+        server_config.set_client_auth(ClientAuth::Require);
+
+        let mut pipe = Pipe::with_server_config(&mut server_config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert!(pipe.server.peer_cert().is_some());
+        assert!(pipe.server.is_peer_verified());
+    }
+
+    #[test]
+    fn stream() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, b"hello, world", true), Ok(12));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let mut r = pipe.server.readable();
+        assert_eq!(r.next(), Some(4));
+        assert_eq!(r.next(), None);
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((12, true)));
+        assert_eq!(&b[..12], b"hello, world");
+    }
+
+    #[test]
+    fn flow_control() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn stream_flow_control() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
+        }];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn stream_limit_bidi() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 12,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 16,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 20,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 24,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 28,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit),
+        );
+    }
+
+    #[test]
+    fn stream_limit_uni() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 2,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 6,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 10,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 14,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 18,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 22,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 26,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::StreamLimit),
+        );
+    }
+
+    #[test]
+    fn stream_limit_update() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", true), Ok(1));
+        assert_eq!(
+            pipe.client.stream_send(12, b"a", true),
+            Err(Error::StreamLimit)
+        );
+
+        assert_eq!(pipe.advance_each(&mut buf), Ok(()));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.stream_recv(0, &mut b), Ok((1, true)));
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((1, true)));
+
+        assert_eq!(pipe.server.stream_send(0, b"a", true), Ok(1));
+        assert_eq!(pipe.server.stream_send(4, b"a", true), Ok(1));
+
+        // Once the server's responses are acknowledged the streams are
+        // collected and the client is given credit for new streams.
+        assert_eq!(pipe.advance_each(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(12, b"a", true), Ok(1));
+        assert_eq!(pipe.client.stream_send(16, b"a", true), Ok(1));
+        assert_eq!(
+            pipe.client.stream_send(20, b"a", true),
+            Err(Error::StreamLimit)
+        );
+    }
+
+    #[test]
+    fn stream_limit_update_reset() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_ack_frequency(1);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.server.stream_send(0, b"a", true), Ok(1));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        // Reset streams are collected without their data being read.
+        let frames = [
+            frame::Frame::ResetStream {
+                stream_id: 0,
+                error_code: 0,
+                final_size: 5,
+            },
+            frame::Frame::ResetStream {
+                stream_id: 2,
+                error_code: 0,
+                final_size: 5,
+            },
+            frame::Frame::ResetStream {
+                stream_id: 6,
+                error_code: 0,
+                final_size: 5,
+            },
+        ];
+
+        let pkt_type = packet::Type::Application;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        assert!(pipe.server.streams.is_collected(0));
+        assert!(pipe.server.streams.is_collected(2));
+        assert!(pipe.server.streams.is_collected(6));
+
+        assert_eq!(pipe.server.streams.max_streams_bidi_next(), 4);
+
+        let frames = decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        assert!(frames
+            .iter()
+            .any(|f| *f == frame::Frame::MaxStreamsUni { max: 5 }));
+    }
+
+    #[test]
+    fn streams_blocked() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(4, b"a", false), Ok(1));
+        assert_eq!(pipe.client.stream_send(8, b"a", false), Ok(1));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(12, b"a", false),
+            Err(Error::StreamLimit)
+        );
+
+        let len = pipe.client.send(&mut buf).unwrap();
+
+        let frames = decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert!(frames
+            .iter()
+            .any(|f| *f == frame::Frame::StreamsBlockedBidi { limit: 3 }));
+    }
+
+    #[test]
+    fn data_blocked() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.stream_send(4, b"aaaaaaaaaa", false), Ok(10));
+        assert_eq!(pipe.client.stream_send(8, b"aaaaaaaaaa", false), Ok(10));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+
+        let len = pipe.client.send(&mut buf).unwrap();
+
+        let frames = decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert!(frames
+            .iter()
+            .any(|f| *f == frame::Frame::DataBlocked { limit: 30 }));
+
+        // The frame is only sent once per limit.
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn stream_data_blocked() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(
+            pipe.client.stream_send(0, b"aaaaaaaaaaaaaaaa", false),
+            Ok(16)
+        );
+
+        // The first packet carries the stream data up to the limit.
+        let len = pipe.client.send(&mut buf).unwrap();
+        assert_eq!(pipe.server.recv(&mut buf[..len]), Ok(len));
+
+        let len = pipe.client.send(&mut buf).unwrap();
+
+        let frames = decode_pkt(&mut pipe.server, &mut buf, len).unwrap();
+
+        assert!(frames.iter().any(|f| *f ==
+            frame::Frame::StreamDataBlocked {
+                stream_id: 0,
+                limit: 15,
+            }));
+
+        // The frame is only sent once per limit.
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn connection_window_autotune() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_initial_max_stream_data_bidi_local(1000);
+        config.set_initial_max_stream_data_bidi_remote(1000);
+        config.set_max_connection_window(60);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let now = time::Instant::now();
+
+        let mut r = [0; 30];
+
+        // The first update doesn't grow the window, the second one follows
+        // it closely so the window doubles, and the third one is capped to
+        // the maximum window.
+        for (i, max) in [60, 120, 150].iter().enumerate() {
+            let frames = [frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(&[0; 30], i * 30, false),
+            }];
+
+            let pkt_type = packet::Type::Application;
+            assert!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf).is_ok());
+
+            assert_eq!(pipe.server.stream_recv(0, &mut r), Ok((30, false)));
+
+            let len = pipe.server.send_at(&mut buf, now).unwrap();
+
+            let frames = decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+            assert!(frames
+                .iter()
+                .any(|f| *f == frame::Frame::MaxData { max: *max as u64 }));
+        }
+    }
+
+    #[test]
+    fn reset_stream_flow_control() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [
+            frame::Frame::Stream {
+                stream_id: 0,
+                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
+            },
+            frame::Frame::Stream {
+                stream_id: 4,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+            frame::Frame::ResetStream {
+                stream_id: 4,
+                error_code: 0,
+                final_size: 15,
+            },
+            frame::Frame::Stream {
+                stream_id: 8,
+                data: stream::RangeBuf::from(b"a", 0, false),
+            },
+        ];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::FlowControl),
+        );
+    }
+
+    #[test]
+    fn path_challenge() {
+        let mut buf = [0; 65535];
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let frames = [frame::Frame::PathChallenge {
+            data: vec![0xba; 8],
+        }];
+
+        let pkt_type = packet::Type::Application;
+        let len = pipe
+            .send_pkt_to_server(pkt_type, &frames, &mut buf)
+            .unwrap();
+
+        let frames = decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+        let mut iter = frames.iter();
+
+        iter.next().unwrap();
+
+        assert_eq!(
+            iter.next(),
+            Some(&frame::Frame::PathResponse {
+                data: vec![0xba; 8],
+            })
+        );
+    }
+
+    #[test]
+    fn seeded_rng() {
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config.set_rng(SeededRng::new(42));
+
+        let a = connect(None, &[0xba; 16], &mut config).unwrap();
+
+        config.set_rng(SeededRng::new(42));
+
+        let b = connect(None, &[0xba; 16], &mut config).unwrap();
+
+        // The same seed generates the same destination connection ID.
+        assert_eq!(a.dcid, b.dcid);
+
+        let c = connect(None, &[0xba; 16], &mut config).unwrap();
+
+        // The RNG is shared by connections using the same config.
+        assert_ne!(b.dcid, c.dcid);
+    }
+
+    #[test]
+    fn dgram_send_recv() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.enable_dgram(true, 2, 2);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.client.dgram_send(b"hello"), Err(Error::InvalidState));
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.dgram_send(b"hello"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"world!"), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"again"), Err(Error::Done));
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let mut b = [0; 5];
+        assert_eq!(pipe.server.dgram_recv(&mut b), Ok(5));
+        assert_eq!(&b, b"hello");
+
+        assert_eq!(pipe.server.dgram_recv(&mut b), Err(Error::BufferTooShort));
+
+        let mut b = [0; 15];
+        assert_eq!(pipe.server.dgram_recv(&mut b), Ok(6));
+        assert_eq!(&b[..6], b"world!");
+
+        assert_eq!(pipe.server.dgram_recv(&mut b), Err(Error::Done));
+    }
+
+    #[test]
+    fn dgram_not_enabled() {
         let mut buf = [0; 65535];
 
         let mut pipe = Pipe::new().unwrap();
 
         assert_eq!(pipe.handshake(&mut buf), Ok(()));
 
-        let frames = [frame::Frame::Stream {
-            stream_id: 4,
-            data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaaa", 0, true),
-        }];
+        assert_eq!(pipe.client.dgram_max_writable_len(), None);
+        assert_eq!(pipe.client.dgram_send(b"hello"), Err(Error::InvalidState));
+
+        let frames = [frame::Frame::Datagram {
+            data: b"hello".to_vec(),
+        }];
+
+        let pkt_type = packet::Type::Application;
+        assert_eq!(
+            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
+            Err(Error::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn dgram_oversized() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_max_packet_size(1350);
+        config.enable_dgram(true, 2, 2);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // The DATAGRAM frame size advertised by the peer is larger than the
+        // packet size, so the latter limits the payload size.
+        let max_len = pipe.client.dgram_max_writable_len().unwrap();
+        assert!(max_len < pipe.client.max_send_pkt_len());
+
+        let data = vec![0xba; max_len + 1];
+        assert_eq!(pipe.client.dgram_send(&data), Err(Error::BufferTooShort));
+
+        assert_eq!(pipe.client.dgram_send(&data[..max_len]), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let mut b = [0; 65535];
+        assert_eq!(pipe.server.dgram_recv(&mut b), Ok(max_len));
+
+        // A queued datagram that no longer fits in a packet is dropped
+        // instead of blocking the ones behind it.
+        assert_eq!(pipe.client.dgram_send(&data[..max_len]), Ok(()));
+        assert_eq!(pipe.client.dgram_send(b"hello"), Ok(()));
+
+        pipe.client.peer_transport_params.max_packet_size = 1200;
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.server.dgram_recv(&mut b), Ok(5));
+        assert_eq!(&b[..5], b"hello");
+
+        assert_eq!(pipe.server.dgram_recv(&mut b), Err(Error::Done));
+        assert_eq!(pipe.client.send(&mut buf), Err(Error::Done));
+    }
+
+    #[test]
+    fn ecn() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.enable_ecn(true);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.client.ecn_marking(), Ecn::Ect0);
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        // The server never reported ECN counts for the client's marked
+        // packets, so validation fails and marking is disabled.
+        assert_eq!(pipe.client.ecn_marking(), Ecn::NotEct);
+
+        let frames = [frame::Frame::Ping];
+
+        let len = encode_pkt(
+            &mut pipe.client,
+            packet::Type::Application,
+            &frames,
+            &mut buf,
+        )
+        .unwrap();
+
+        assert_eq!(pipe.server.recv_with_ecn(&mut buf[..len], Ecn::Ce), Ok(len));
+
+        // A CE-marked packet is acknowledged immediately.
+        let len = pipe.server.send(&mut buf).unwrap();
+
+        let frames = decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
+
+        let ecn_counts = frames.iter().find_map(|f| match f {
+            frame::Frame::ACK { ecn_counts, .. } => *ecn_counts,
+
+            _ => None,
+        });
+
+        assert_eq!(
+            ecn_counts,
+            Some(frame::EcnCounts {
+                ect0_count: 0,
+                ect1_count: 0,
+                ecn_ce_count: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn pmtud() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_initial_max_data(10000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_max_packet_size(1500);
+        config.set_ack_frequency(1);
+        config.enable_pmtud(true);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        // The probe is sent along with the client's last handshake flight,
+        // so use advance() to make sure its ACK is delivered as well.
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+        assert!(pipe.client.is_established());
+
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+        assert_eq!(pipe.client.stats().lost, 0);
+
+        assert_eq!(pipe.client.stream_send(4, &[0; 2000], true), Ok(2000));
+
+        let len = pipe.client.send(&mut buf).unwrap();
+        assert!(len > 1200 && len <= 1500);
+    }
+
+    #[test]
+    fn pmtud_short_buffer() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_initial_max_data(10000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_max_packet_size(1500);
+        config.set_ack_frequency(1);
+        config.enable_pmtud(true);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+
+        assert_eq!(pipe.client.stream_send(4, &[0; 4000], true), Ok(4000));
+
+        // A short output buffer only limits the packet being sent.
+        let len = pipe.client.send(&mut buf[..1300]).unwrap();
+        assert!(len > 1200 && len <= 1300);
+        assert_eq!(pipe.client.stats().pmtu, 1500);
+
+        let len = pipe.client.send(&mut buf).unwrap();
+        assert!(len > 1300 && len <= 1500);
+    }
+
+    #[test]
+    fn send_batch() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_initial_max_data(10000);
+        config.set_initial_max_stream_data_bidi_local(10000);
+        config.set_initial_max_stream_data_bidi_remote(10000);
+        config.set_max_packet_size(1350);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, &[1; 5000], true), Ok(5000));
 
-        let pkt_type = packet::Type::Application;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
+        let (segment_size, count) = pipe.client.send_batch(&mut buf, 10).unwrap();
+        assert_eq!(segment_size, 1350);
+        assert!(count > 1 && count < 10);
+
+        for i in 0..count {
+            let pkt = &mut buf[i * segment_size..(i + 1) * segment_size];
+            assert_eq!(pipe.server.recv(pkt), Ok(segment_size));
+        }
+
+        assert_eq!(pipe.client.send_batch(&mut buf, 10), Err(Error::Done));
+
+        let mut b = [0; 5000];
+        assert_eq!(pipe.server.stream_recv(4, &mut b), Ok((5000, true)));
+    }
+
+    #[test]
+    fn send_batch_cwnd() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_initial_max_data(100000);
+        config.set_initial_max_stream_data_bidi_local(100000);
+        config.set_initial_max_stream_data_bidi_remote(100000);
+        config.set_max_packet_size(1350);
+
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(pipe.client.stream_send(4, &[1; 50000], true), Ok(50000));
+
+        // The batch stops once the packets in flight fill the congestion
+        // window, even though more data and buffer space are available.
+        let (segment_size, count) = pipe.client.send_batch(&mut buf, 40).unwrap();
+        assert_eq!(segment_size, 1350);
+        assert!(count > 1 && count < 40);
+
+        assert!(
+            pipe.client.recovery.bytes_in_flight() <=
+                pipe.client.recovery.congestion_window()
         );
     }
 
     #[test]
-    fn stream_limit_bidi() {
+    fn stats() {
         let mut buf = [0; 65535];
 
         let mut pipe = Pipe::new().unwrap();
 
+        assert_eq!(pipe.client.stats().handshake_duration, None);
+
         assert_eq!(pipe.handshake(&mut buf), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 16,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 20,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 24,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 28,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        assert_eq!(pipe.client.stream_send(0, b"hello, world", true), Ok(12));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
 
-        let pkt_type = packet::Type::Application;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
-        );
+        let stats = pipe.client.stats();
+        assert!(stats.sent > 0);
+        assert!(stats.recv > 0);
+        assert!(stats.sent_bytes > 0);
+        assert!(stats.recv_bytes > 0);
+        assert_eq!(stats.retrans_bytes, 0);
+        assert!(stats.min_rtt.is_some());
+        assert!(stats.handshake_duration.is_some());
+        assert_eq!(stats.local_streams, 1);
+        assert_eq!(stats.peer_streams, 0);
+
+        let stats = pipe.server.stats();
+        assert!(stats.sent_bytes > 0);
+        assert_eq!(stats.local_streams, 0);
+        assert_eq!(stats.peer_streams, 1);
     }
 
     #[test]
-    fn stream_limit_uni() {
+    fn qlog() {
         let mut buf = [0; 65535];
 
+        let log = SharedBuf::default();
+
         let mut pipe = Pipe::new().unwrap();
 
+        pipe.client.set_qlog(Box::new(log.clone()), "client trace");
+
         assert_eq!(pipe.handshake(&mut buf), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 2,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 6,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 10,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 14,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 18,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 22,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 26,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
 
-        let pkt_type = packet::Type::Application;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::StreamLimit),
-        );
+        drop(pipe);
+
+        let out = log.contents();
+
+        assert!(out.starts_with("{\"qlog_version\":\"draft-01\""));
+        assert!(out.ends_with("]}]}"));
+
+        assert!(out.contains("\"vantage_point\":{\"type\":\"client\"}"));
+        assert!(out.contains("\"parameters_set\",{\"owner\":\"local\""));
+        assert!(out.contains("\"parameters_set\",{\"owner\":\"remote\""));
+        assert!(out.contains("\"key_updated\""));
+        assert!(out.contains("\"packet_sent\",{\"packet_type\":\"initial\""));
+        assert!(out.contains("\"packet_received\""));
+        assert!(out.contains("\"frame_type\":\"stream\",\"stream_id\":4"));
+        assert!(out.contains("\"metrics_updated\""));
     }
 
     #[test]
-    fn reset_stream_flow_control() {
+    fn qlog_virtual_time() {
+        let mut buf = [0; 65535];
+
+        let log = SharedBuf::default();
+
+        let mut pipe = Pipe::new().unwrap();
+
+        let now = time::Instant::now() + time::Duration::from_secs(10);
+
+        pipe.client
+            .set_qlog_at(Box::new(log.clone()), "client trace", now);
+
+        let now = now + time::Duration::from_millis(5);
+
+        assert!(pipe.client.send_at(&mut buf, now).is_ok());
+
+        drop(pipe);
+
+        let out = log.contents();
+
+        // Event times are relative to the injected clock, not the system one.
+        assert!(out.contains("[\"0.000\",\"transport\",\"parameters_set\""));
+        assert!(out.contains("[\"5.000\",\"transport\",\"packet_sent\""));
+    }
+
+    #[test]
+    fn keylog() {
         let mut buf = [0; 65535];
 
+        let mut config = Config::new(VERSION_DRAFT17).unwrap();
+        config
+            .set_application_protos(&[b"proto1", b"proto2"])
+            .unwrap();
+        config.verify_peer(false);
+
+        let log = SharedBuf::default();
+
+        config.log_keys(Box::new(log.clone()));
+
+        let mut pipe = Pipe::with_client_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let out = log.contents();
+
+        assert!(!out.is_empty());
+        assert!(out.lines().all(|l| l.starts_with("QUIC_")));
+        assert!(out.contains("QUIC_CLIENT_HANDSHAKE_TRAFFIC_SECRET "));
+        assert!(out.contains("QUIC_SERVER_TRAFFIC_SECRET_0 "));
+
+        // A connection's own writer works without a config-wide one.
+        let log = SharedBuf::default();
+
         let mut pipe = Pipe::new().unwrap();
 
+        pipe.client.set_keylog(Box::new(log.clone()));
+
         assert_eq!(pipe.handshake(&mut buf), Ok(()));
 
-        let frames = [
-            frame::Frame::Stream {
-                stream_id: 4,
-                data: stream::RangeBuf::from(b"aaaaaaaaaaaaaaa", 0, false),
-            },
-            frame::Frame::Stream {
-                stream_id: 8,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-            frame::Frame::ResetStream {
-                stream_id: 8,
-                error_code: 0,
-                final_size: 15,
-            },
-            frame::Frame::Stream {
-                stream_id: 12,
-                data: stream::RangeBuf::from(b"a", 0, false),
-            },
-        ];
+        let out = log.contents();
 
-        let pkt_type = packet::Type::Application;
-        assert_eq!(
-            pipe.send_pkt_to_server(pkt_type, &frames, &mut buf),
-            Err(Error::FlowControl),
-        );
+        assert!(out.contains("QUIC_CLIENT_HANDSHAKE_TRAFFIC_SECRET "));
+        assert!(out.contains("QUIC_SERVER_TRAFFIC_SECRET_0 "));
     }
 
     #[test]
-    fn path_challenge() {
+    fn observer() {
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        struct Recorder(Arc<Mutex<Vec<String>>>);
+
+        impl ConnectionObserver for Recorder {
+            fn on_handshake_completed(&mut self) {
+                self.0.lock().unwrap().push("handshake".to_string());
+            }
+
+            fn on_stream_opened(&mut self, stream_id: u64) {
+                self.0.lock().unwrap().push(format!("open {}", stream_id));
+            }
+
+            fn on_stream_closed(&mut self, stream_id: u64) {
+                self.0.lock().unwrap().push(format!("closed {}", stream_id));
+            }
+
+            fn on_path_validated(&mut self) {
+                self.0.lock().unwrap().push("validated".to_string());
+            }
+
+            fn on_close_received(
+                &mut self, app: bool, error_code: u16, reason: &[u8],
+            ) {
+                self.0.lock().unwrap().push(format!(
+                    "close {} {} {:?}",
+                    app,
+                    error_code,
+                    std::str::from_utf8(reason).unwrap()
+                ));
+            }
+        }
+
         let mut buf = [0; 65535];
 
+        let events = Arc::new(Mutex::new(Vec::new()));
+
         let mut pipe = Pipe::new().unwrap();
 
+        pipe.server
+            .set_observer(Box::new(Recorder(Arc::clone(&events))));
+
         assert_eq!(pipe.handshake(&mut buf), Ok(()));
 
-        let frames = [frame::Frame::PathChallenge {
-            data: vec![0xba; 8],
-        }];
+        assert_eq!(pipe.client.stream_send(2, b"hello", true), Ok(5));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
 
+        assert_eq!(pipe.server.stream_recv(2, &mut buf), Ok((5, true)));
+
+        assert_eq!(pipe.client.close(true, 42, b"bye"), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        assert_eq!(*events.lock().unwrap(), [
+            "validated",
+            "handshake",
+            "open 2",
+            "closed 2",
+            "close true 42 \"bye\"",
+        ]);
+    }
+
+    #[test]
+    fn observer_packet_lost() {
+        use std::sync::Arc;
+        use std::sync::Mutex;
+
+        struct Recorder(Arc<Mutex<Vec<(Type, u64)>>>);
+
+        impl ConnectionObserver for Recorder {
+            fn on_packet_lost(&mut self, ty: Type, pkt_num: u64) {
+                self.0.lock().unwrap().push((ty, pkt_num));
+            }
+        }
+
+        let mut buf = [0; 65535];
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let mut pipe = Pipe::new().unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        pipe.client
+            .set_observer(Box::new(Recorder(Arc::clone(&events))));
+
+        // The first packet is never delivered to the server.
+        assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+        assert!(pipe.client.send(&mut buf).is_ok());
+
+        let lost_pkt_num = pipe.client.application.next_pkt_num - 1;
+
+        for _ in 0..3 {
+            assert_eq!(pipe.client.stream_send(0, b"a", false), Ok(1));
+
+            let len = pipe.client.send(&mut buf).unwrap();
+            assert_eq!(pipe.server.recv(&mut buf[..len]), Ok(len));
+        }
+
+        let len = pipe.server.send(&mut buf).unwrap();
+        assert_eq!(pipe.client.recv(&mut buf[..len]), Ok(len));
+
+        // The loss is reported as soon as the ACK is processed, without the
+        // client having to send anything.
+        assert_eq!(*events.lock().unwrap(), [(Type::Application, lost_pkt_num)]);
+    }
+
+    #[test]
+    fn loss_detection_virtual_time() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_idle_timeout(180);
+
+        // The idle timer needs to be set, as it would expire as soon as the
+        // clock is moved forward otherwise.
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+
+        let now = time::Instant::now();
+
+        assert_eq!(pipe.client.stream_send(4, b"hello", true), Ok(5));
+
+        // The packet is never delivered to the server.
+        assert!(pipe.client.send_at(&mut buf, now).is_ok());
+        assert_eq!(pipe.client.send_at(&mut buf, now), Err(Error::Done));
+
+        let timeout = pipe.client.timeout_at(now).unwrap();
+        assert!(timeout > time::Duration::new(0, 0));
+
+        // Nothing happens until the timer expires.
+        pipe.client.on_timeout_at(now);
+        assert_eq!(pipe.client.send_at(&mut buf, now), Err(Error::Done));
+
+        let now = now + timeout;
+        assert_eq!(pipe.client.timeout_at(now), Some(time::Duration::new(0, 0)));
+
+        pipe.client.on_timeout_at(now);
+        assert!(pipe.client.send_at(&mut buf, now).is_ok());
+    }
+
+    #[test]
+    fn delayed_ack() {
+        let mut buf = [0; 65535];
+
+        let mut config = default_config().unwrap();
+        config.set_idle_timeout(180);
+
+        // The idle timer needs to be set, as it would expire as soon as the
+        // clock is moved forward otherwise.
+        let mut pipe = Pipe::with_config(&mut config).unwrap();
+
+        assert_eq!(pipe.handshake(&mut buf), Ok(()));
+        assert_eq!(pipe.advance(&mut buf), Ok(()));
+
+        let frames = [frame::Frame::Ping];
         let pkt_type = packet::Type::Application;
+
+        // A single ack-eliciting packet doesn't trigger an ACK right away.
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
+
+        let timeout = pipe.server.timeout().unwrap();
+        assert!(timeout <= time::Duration::from_millis(25));
+
+        // The second one does.
         let len = pipe
             .send_pkt_to_server(pkt_type, &frames, &mut buf)
             .unwrap();
+        assert!(len > 0);
 
         let frames = decode_pkt(&mut pipe.client, &mut buf, len).unwrap();
-        let mut iter = frames.iter();
+        assert!(frames.iter().any(|f| match f {
+            frame::Frame::ACK { .. } => true,
+            _ => false,
+        }));
 
-        iter.next().unwrap();
+        // The ACK is sent once the ACK timer expires.
+        let frames = [frame::Frame::Ping];
+        assert_eq!(pipe.send_pkt_to_server(pkt_type, &frames, &mut buf), Ok(0));
 
-        assert_eq!(
-            iter.next(),
-            Some(&frame::Frame::PathResponse {
-                data: vec![0xba; 8],
-            })
-        );
+        let now = time::Instant::now() + time::Duration::from_millis(25);
+        pipe.server.on_timeout_at(now);
+
+        assert!(pipe.server.send_at(&mut buf, now).is_ok());
     }
 }
 
 pub use crate::packet::Header;
 pub use crate::packet::Type;
+pub use crate::rand::Rng;
+pub use crate::rand::SeededRng;
 pub use crate::stream::Readable;
 
 mod crypto;
+mod dgram;
 mod ffi;
 mod frame;
 pub mod h3;
 mod octets;
 mod packet;
+mod pmtud;
+mod qlog;
 mod rand;
 mod ranges;
 mod recovery;
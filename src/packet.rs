@@ -31,6 +31,7 @@ use crate::Error;
 use crate::Result;
 
 use crate::crypto;
+use crate::frame;
 use crate::octets;
 use crate::rand;
 use crate::ranges;
@@ -46,9 +47,13 @@ const PKT_NUM_MASK: u8 = 0x03;
 
 pub const MAX_CID_LEN: u8 = 18;
 
-const MAX_PKT_NUM_LEN: usize = 4;
+pub const MAX_PKT_NUM_LEN: usize = 4;
 const SAMPLE_LEN: usize = 16;
 
+// The maximum number of packet number ranges tracked for acknowledgement, so
+// that a peer skipping packet numbers can't make it grow unbounded.
+const MAX_ACK_RANGES: usize = 68;
+
 /// QUIC packet type.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Type {
@@ -60,6 +65,29 @@ pub enum Type {
     Application,
 }
 
+/// Index of a packet number space.
+pub type Epoch = usize;
+
+pub const EPOCH_INITIAL: Epoch = 0;
+pub const EPOCH_HANDSHAKE: Epoch = 1;
+pub const EPOCH_APPLICATION: Epoch = 2;
+pub const EPOCH_COUNT: usize = 3;
+
+impl Type {
+    /// Returns the packet number space of the packet type.
+    pub(crate) fn to_epoch(self) -> Result<Epoch> {
+        match self {
+            Type::Initial => Ok(EPOCH_INITIAL),
+
+            Type::Handshake => Ok(EPOCH_HANDSHAKE),
+
+            Type::Application => Ok(EPOCH_APPLICATION),
+
+            _ => Err(Error::InvalidPacket),
+        }
+    }
+}
+
 /// A QUIC packet's header.
 #[derive(Clone, PartialEq)]
 pub struct Header {
@@ -536,7 +564,7 @@ pub fn encode_pkt_num(pn: u64, b: &mut octets::Octets) -> Result<()> {
 }
 
 pub fn negotiate_version(
-    scid: &[u8], dcid: &[u8], out: &mut [u8],
+    scid: &[u8], dcid: &[u8], versions: &[u32], out: &mut [u8],
 ) -> Result<usize> {
     let mut b = octets::Octets::with_slice(out);
 
@@ -558,7 +586,10 @@ pub fn negotiate_version(
     b.put_u8(cil)?;
     b.put_bytes(&scid)?;
     b.put_bytes(&dcid)?;
-    b.put_u32(crate::VERSION_DRAFT17)?;
+
+    for v in versions {
+        b.put_u32(*v)?;
+    }
 
     Ok(b.off())
 }
@@ -589,7 +620,7 @@ pub fn retry(
 pub struct PktNumSpace {
     pub largest_rx_pkt_num: u64,
 
-    pub largest_rx_pkt_time: time::Instant,
+    pub largest_rx_pkt_time: Option<time::Instant>,
 
     pub next_pkt_num: u64,
 
@@ -601,6 +632,14 @@ pub struct PktNumSpace {
 
     pub do_ack: bool,
 
+    pub ack_eliciting_count: usize,
+
+    pub ack_timer: Option<time::Instant>,
+
+    pub ecn_counts: frame::EcnCounts,
+
+    pub peer_ecn_counts: frame::EcnCounts,
+
     pub crypto_level: crypto::Level,
 
     pub crypto_open: Option<crypto::Open>,
@@ -614,11 +653,11 @@ impl PktNumSpace {
         PktNumSpace {
             largest_rx_pkt_num: 0,
 
-            largest_rx_pkt_time: time::Instant::now(),
+            largest_rx_pkt_time: None,
 
             next_pkt_num: 0,
 
-            recv_pkt_need_ack: ranges::RangeSet::default(),
+            recv_pkt_need_ack: ranges::RangeSet::with_capacity(MAX_ACK_RANGES),
 
             recv_pkt_num: PktNumWindow::default(),
 
@@ -626,19 +665,32 @@ impl PktNumSpace {
 
             do_ack: false,
 
+            ack_eliciting_count: 0,
+
+            ack_timer: None,
+
+            ecn_counts: frame::EcnCounts::default(),
+
+            peer_ecn_counts: frame::EcnCounts::default(),
+
             crypto_level,
 
             crypto_open: None,
             crypto_seal: None,
 
-            crypto_stream: stream::Stream::new(std::usize::MAX, std::usize::MAX),
+            crypto_stream: stream::Stream::new(
+                std::usize::MAX,
+                std::usize::MAX,
+                true,
+                false,
+            ),
         }
     }
 
     pub fn clear(&mut self) {
         self.flight = recovery::InFlight::default();
         self.crypto_stream =
-            stream::Stream::new(std::usize::MAX, std::usize::MAX);
+            stream::Stream::new(std::usize::MAX, std::usize::MAX, true, false);
     }
 
     pub fn overhead(&self) -> usize {
@@ -926,7 +978,8 @@ mod tests {
         let payload_len = b.get_varint().unwrap() as usize;
 
         let (aead, _) =
-            crypto::derive_initial_key_material(dcid, is_server).unwrap();
+            crypto::derive_initial_key_material(dcid, hdr.version, is_server)
+                .unwrap();
 
         decrypt_hdr(&mut b, &mut hdr, &aead).unwrap();
         let pn = decode_pkt_num(0, hdr.pkt_num, hdr.pkt_num_len);
@@ -0,0 +1,254 @@
+// Copyright (C) 2019, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::ranges;
+use crate::recovery;
+
+/// The minimum packet size every QUIC path is required to support.
+pub const BASE_PLPMTU: usize = 1200;
+
+// The maximum number of consecutive probes of the same size that can be lost
+// before the size is considered unsupported by the path.
+const MAX_PROBES: usize = 3;
+
+// The search is stopped once the gap between the validated size and the upper
+// bound gets smaller than this.
+const SEARCH_THRESHOLD: usize = 20;
+
+/// Datagram Packetization Layer Path MTU Discovery state.
+///
+/// The search starts from the minimum QUIC packet size and probes the largest
+/// allowed size first. When a probe is lost `MAX_PROBES` times in a row the
+/// upper bound is lowered, and the search continues with the midpoint between
+/// the largest validated size and the new bound.
+pub struct Pmtud {
+    enabled: bool,
+
+    mtu: usize,
+
+    max_mtu: usize,
+
+    probe_size: usize,
+
+    probe_pkt_num: Option<u64>,
+
+    probe_count: usize,
+}
+
+impl Pmtud {
+    pub fn new(enabled: bool, max_mtu: usize) -> Pmtud {
+        let max_mtu = std::cmp::max(max_mtu, BASE_PLPMTU);
+
+        Pmtud {
+            enabled,
+
+            mtu: BASE_PLPMTU,
+
+            max_mtu,
+
+            probe_size: max_mtu,
+
+            probe_pkt_num: None,
+
+            probe_count: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the largest packet size validated on the path.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Returns the size of the next probe.
+    pub fn probe_size(&self) -> usize {
+        self.probe_size
+    }
+
+    /// Lowers the upper bound of the search to `v`.
+    pub fn set_max_mtu(&mut self, v: usize) {
+        let v = std::cmp::max(v, BASE_PLPMTU);
+
+        if v >= self.max_mtu {
+            return;
+        }
+
+        self.max_mtu = v;
+        self.mtu = std::cmp::min(self.mtu, v);
+        self.probe_size = std::cmp::min(self.probe_size, v);
+    }
+
+    /// Returns true if a probe should be sent.
+    pub fn should_probe(&self) -> bool {
+        self.enabled &&
+            self.probe_pkt_num.is_none() &&
+            self.max_mtu - self.mtu >= SEARCH_THRESHOLD
+    }
+
+    pub fn on_probe_sent(&mut self, pkt_num: u64) {
+        self.probe_pkt_num = Some(pkt_num);
+    }
+
+    /// Checks whether the outstanding probe was acknowledged by `ranges`, or
+    /// was declared lost by loss detection and removed from `flight`.
+    pub fn on_ack_received(
+        &mut self, ranges: &ranges::RangeSet, flight: &recovery::InFlight,
+    ) {
+        let pkt_num = match self.probe_pkt_num {
+            Some(v) => v,
+            None => return,
+        };
+
+        if ranges.flatten().any(|pn| pn == pkt_num) {
+            self.on_probe_acked();
+        } else {
+            self.on_loss_detection(flight);
+        }
+    }
+
+    /// Checks whether the outstanding probe was declared lost.
+    pub fn on_loss_detection(&mut self, flight: &recovery::InFlight) {
+        let pkt_num = match self.probe_pkt_num {
+            Some(v) => v,
+            None => return,
+        };
+
+        if !flight.sent.contains_key(&pkt_num) {
+            self.on_probe_lost();
+        }
+    }
+
+    fn on_probe_acked(&mut self) {
+        self.probe_pkt_num = None;
+        self.probe_count = 0;
+
+        self.mtu = self.probe_size;
+        self.probe_size = self.next_probe_size();
+    }
+
+    fn on_probe_lost(&mut self) {
+        self.probe_pkt_num = None;
+        self.probe_count += 1;
+
+        if self.probe_count < MAX_PROBES {
+            return;
+        }
+
+        self.probe_count = 0;
+
+        self.max_mtu = self.probe_size - 1;
+        self.probe_size = self.next_probe_size();
+    }
+
+    fn next_probe_size(&self) -> usize {
+        self.mtu + (self.max_mtu - self.mtu + 1) / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(p: &mut Pmtud, pkt_num: u64, acked: bool) -> usize {
+        let size = p.probe_size();
+
+        let flight = recovery::InFlight::default();
+        let mut ranges = ranges::RangeSet::default();
+
+        p.on_probe_sent(pkt_num);
+
+        if acked {
+            ranges.insert(pkt_num..pkt_num + 1);
+        }
+
+        p.on_ack_received(&ranges, &flight);
+
+        size
+    }
+
+    #[test]
+    fn max_size_acked() {
+        let mut p = Pmtud::new(true, 1500);
+
+        assert_eq!(p.mtu(), BASE_PLPMTU);
+        assert!(p.should_probe());
+
+        assert_eq!(probe(&mut p, 0, true), 1500);
+
+        assert_eq!(p.mtu(), 1500);
+        assert!(!p.should_probe());
+    }
+
+    #[test]
+    fn search() {
+        let mut p = Pmtud::new(true, 1500);
+
+        for pn in 0..MAX_PROBES as u64 {
+            assert_eq!(probe(&mut p, pn, false), 1500);
+        }
+
+        assert_eq!(p.mtu(), BASE_PLPMTU);
+
+        // The upper bound was lowered and the midpoint is tried next.
+        assert_eq!(probe(&mut p, 3, true), 1350);
+        assert_eq!(p.mtu(), 1350);
+
+        assert_eq!(probe(&mut p, 4, true), 1425);
+        assert_eq!(p.mtu(), 1425);
+
+        for pn in 5..5 + MAX_PROBES as u64 {
+            assert_eq!(probe(&mut p, pn, false), 1462);
+        }
+
+        assert_eq!(probe(&mut p, 8, true), 1443);
+        assert_eq!(p.mtu(), 1443);
+
+        assert!(!p.should_probe());
+    }
+
+    #[test]
+    fn disabled() {
+        let p = Pmtud::new(false, 1500);
+
+        assert_eq!(p.mtu(), BASE_PLPMTU);
+        assert!(!p.should_probe());
+    }
+
+    #[test]
+    fn lower_max() {
+        let mut p = Pmtud::new(true, 16383);
+
+        p.set_max_mtu(1452);
+        assert_eq!(p.probe_size(), 1452);
+
+        p.set_max_mtu(1000);
+        assert_eq!(p.probe_size(), BASE_PLPMTU);
+        assert!(!p.should_probe());
+    }
+}
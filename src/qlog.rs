@@ -0,0 +1,563 @@
+// Copyright (C) 2019, Cloudflare, Inc.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::io;
+use std::time;
+
+use std::io::Write;
+
+use crate::frame;
+use crate::packet;
+use crate::recovery;
+
+use crate::TransportParams;
+
+const QLOG_VERSION: &str = "draft-01";
+
+/// Streams qlog events, serialized as JSON, to a writer.
+///
+/// The qlog header is written as soon as the streamer is created, and events
+/// are appended as they happen. The JSON document is completed when the
+/// streamer is dropped. Errors are ignored, as logging is best effort.
+pub struct QlogStreamer {
+    writer: Box<dyn io::Write + Send + Sync>,
+
+    start_time: time::Instant,
+
+    first_event: bool,
+
+    last_metrics: String,
+
+    last_congestion_state: &'static str,
+}
+
+impl QlogStreamer {
+    pub fn new(
+        mut writer: Box<dyn io::Write + Send + Sync>, title: &str,
+        vantage_point: &str, group_id: &str, start_time: time::Instant,
+    ) -> QlogStreamer {
+        let _ = write!(
+            writer,
+            "{{\"qlog_version\":\"{}\",\"title\":{},\"traces\":[{{\
+             \"vantage_point\":{{\"type\":\"{}\"}},\
+             \"common_fields\":{{\"group_id\":\"{}\",\"reference_time\":0}},\
+             \"event_fields\":[\"relative_time\",\"category\",\"event\",\
+             \"data\"],\"events\":[",
+            QLOG_VERSION,
+            json_string(title),
+            vantage_point,
+            group_id,
+        );
+
+        QlogStreamer {
+            writer,
+            start_time,
+            first_event: true,
+            last_metrics: String::new(),
+            last_congestion_state: "",
+        }
+    }
+
+    /// Appends an event with the given `data` JSON object.
+    pub fn add_event(
+        &mut self, now: time::Instant, category: &str, event: &str, data: &str,
+    ) {
+        let relative_time = if now > self.start_time {
+            now - self.start_time
+        } else {
+            time::Duration::new(0, 0)
+        };
+
+        let relative_time = relative_time.as_secs() * 1_000_000 +
+            u64::from(relative_time.subsec_micros());
+
+        let _ = write!(
+            self.writer,
+            "{}[\"{}.{:03}\",\"{}\",\"{}\",{}]",
+            if self.first_event { "" } else { "," },
+            relative_time / 1000,
+            relative_time % 1000,
+            category,
+            event,
+            data,
+        );
+
+        self.first_event = false;
+    }
+
+    /// Appends `recovery:metrics_updated` and
+    /// `recovery:congestion_state_updated` events, if the recovery state
+    /// changed since they were last logged.
+    pub fn add_recovery_events(
+        &mut self, now: time::Instant, recovery: &recovery::Recovery,
+    ) {
+        let metrics = recovery_metrics(recovery);
+
+        if metrics != self.last_metrics {
+            self.add_event(now, "recovery", "metrics_updated", &metrics);
+
+            self.last_metrics = metrics;
+        }
+
+        let state = recovery.congestion_state();
+
+        if state != self.last_congestion_state {
+            let data = format!(
+                "{{\"old\":\"{}\",\"new\":\"{}\"}}",
+                self.last_congestion_state, state
+            );
+
+            self.add_event(now, "recovery", "congestion_state_updated", &data);
+
+            self.last_congestion_state = state;
+        }
+    }
+}
+
+impl Drop for QlogStreamer {
+    fn drop(&mut self) {
+        let _ = write!(self.writer, "]}}]}}");
+        let _ = self.writer.flush();
+    }
+}
+
+/// Returns the data of a `transport:packet_sent` or
+/// `transport:packet_received` event.
+pub fn packet_data(
+    hdr: &packet::Header, pkt_num: u64, pkt_len: usize, frames: &[String],
+) -> String {
+    let packet_type = match hdr.ty {
+        packet::Type::Initial => "initial",
+
+        packet::Type::Retry => "retry",
+
+        packet::Type::Handshake => "handshake",
+
+        packet::Type::ZeroRTT => "0RTT",
+
+        packet::Type::VersionNegotiation => "version_negotiation",
+
+        packet::Type::Application => "1RTT",
+    };
+
+    format!(
+        "{{\"packet_type\":\"{}\",\"header\":{{\"packet_number\":{},\
+         \"packet_size\":{},\"dcid\":\"{}\",\"scid\":\"{}\"}},\
+         \"frames\":[{}]}}",
+        packet_type,
+        pkt_num,
+        pkt_len,
+        hex(&hdr.dcid),
+        hex(&hdr.scid),
+        frames.join(","),
+    )
+}
+
+/// Returns the JSON representation of a frame.
+pub fn frame_data(frame: &frame::Frame) -> String {
+    match frame {
+        frame::Frame::Padding { .. } =>
+            "{\"frame_type\":\"padding\"}".to_string(),
+
+        frame::Frame::Ping => "{\"frame_type\":\"ping\"}".to_string(),
+
+        frame::Frame::ACK {
+            ack_delay,
+            ranges,
+            ecn_counts,
+        } => {
+            let ranges: Vec<String> = ranges
+                .iter()
+                .map(|r| format!("[{},{}]", r.start, r.end - 1))
+                .collect();
+
+            let mut s = format!(
+                "{{\"frame_type\":\"ack\",\"ack_delay\":{},\
+                 \"acked_ranges\":[{}]",
+                ack_delay,
+                ranges.join(",")
+            );
+
+            if let Some(ecn) = ecn_counts {
+                s.push_str(&format!(
+                    ",\"ect0\":{},\"ect1\":{},\"ce\":{}",
+                    ecn.ect0_count, ecn.ect1_count, ecn.ecn_ce_count
+                ));
+            }
+
+            s.push('}');
+
+            s
+        },
+
+        frame::Frame::ResetStream {
+            stream_id,
+            error_code,
+            final_size,
+        } => format!(
+            "{{\"frame_type\":\"reset_stream\",\"stream_id\":{},\
+             \"error_code\":{},\"final_size\":{}}}",
+            stream_id, error_code, final_size
+        ),
+
+        frame::Frame::StopSending {
+            stream_id,
+            error_code,
+        } => format!(
+            "{{\"frame_type\":\"stop_sending\",\"stream_id\":{},\
+             \"error_code\":{}}}",
+            stream_id, error_code
+        ),
+
+        frame::Frame::Crypto { data } => format!(
+            "{{\"frame_type\":\"crypto\",\"offset\":{},\"length\":{}}}",
+            data.off(),
+            data.len()
+        ),
+
+        frame::Frame::NewToken { token } => format!(
+            "{{\"frame_type\":\"new_token\",\"length\":{},\"token\":\"{}\"}}",
+            token.len(),
+            hex(token)
+        ),
+
+        frame::Frame::Stream { stream_id, data } => format!(
+            "{{\"frame_type\":\"stream\",\"stream_id\":{},\"offset\":{},\
+             \"length\":{},\"fin\":{}}}",
+            stream_id,
+            data.off(),
+            data.len(),
+            data.fin()
+        ),
+
+        frame::Frame::MaxData { max } =>
+            format!("{{\"frame_type\":\"max_data\",\"maximum\":{}}}", max),
+
+        frame::Frame::MaxStreamData { stream_id, max } => format!(
+            "{{\"frame_type\":\"max_stream_data\",\"stream_id\":{},\
+             \"maximum\":{}}}",
+            stream_id, max
+        ),
+
+        frame::Frame::MaxStreamsBidi { max } => format!(
+            "{{\"frame_type\":\"max_streams\",\
+             \"stream_type\":\"bidirectional\",\"maximum\":{}}}",
+            max
+        ),
+
+        frame::Frame::MaxStreamsUni { max } => format!(
+            "{{\"frame_type\":\"max_streams\",\
+             \"stream_type\":\"unidirectional\",\"maximum\":{}}}",
+            max
+        ),
+
+        frame::Frame::DataBlocked { limit } =>
+            format!("{{\"frame_type\":\"data_blocked\",\"limit\":{}}}", limit),
+
+        frame::Frame::StreamDataBlocked { stream_id, limit } => format!(
+            "{{\"frame_type\":\"stream_data_blocked\",\"stream_id\":{},\
+             \"limit\":{}}}",
+            stream_id, limit
+        ),
+
+        frame::Frame::StreamsBlockedBidi { limit } => format!(
+            "{{\"frame_type\":\"streams_blocked\",\
+             \"stream_type\":\"bidirectional\",\"limit\":{}}}",
+            limit
+        ),
+
+        frame::Frame::StreamsBlockedUni { limit } => format!(
+            "{{\"frame_type\":\"streams_blocked\",\
+             \"stream_type\":\"unidirectional\",\"limit\":{}}}",
+            limit
+        ),
+
+        frame::Frame::NewConnectionId {
+            seq_num,
+            conn_id,
+            reset_token,
+        } => format!(
+            "{{\"frame_type\":\"new_connection_id\",\"sequence_number\":{},\
+             \"connection_id\":\"{}\",\"reset_token\":\"{}\"}}",
+            seq_num,
+            hex(conn_id),
+            hex(reset_token)
+        ),
+
+        frame::Frame::RetireConnectionId { seq_num } => format!(
+            "{{\"frame_type\":\"retire_connection_id\",\
+             \"sequence_number\":{}}}",
+            seq_num
+        ),
+
+        frame::Frame::PathChallenge { data } => format!(
+            "{{\"frame_type\":\"path_challenge\",\"data\":\"{}\"}}",
+            hex(data)
+        ),
+
+        frame::Frame::PathResponse { data } => format!(
+            "{{\"frame_type\":\"path_response\",\"data\":\"{}\"}}",
+            hex(data)
+        ),
+
+        frame::Frame::ConnectionClose {
+            error_code,
+            frame_type,
+            reason,
+        } => format!(
+            "{{\"frame_type\":\"connection_close\",\
+             \"error_space\":\"transport\",\"error_code\":{},\
+             \"reason\":{},\"trigger_frame_type\":{}}}",
+            error_code,
+            json_string(&String::from_utf8_lossy(reason)),
+            frame_type
+        ),
+
+        frame::Frame::ApplicationClose { error_code, reason } => format!(
+            "{{\"frame_type\":\"connection_close\",\
+             \"error_space\":\"application\",\"error_code\":{},\
+             \"reason\":{}}}",
+            error_code,
+            json_string(&String::from_utf8_lossy(reason))
+        ),
+
+        frame::Frame::Datagram { data } =>
+            format!("{{\"frame_type\":\"datagram\",\"length\":{}}}", data.len()),
+    }
+}
+
+/// Returns the data of a `transport:parameters_set` event.
+pub fn transport_params_data(owner: &str, tp: &TransportParams) -> String {
+    let mut s = format!(
+        "{{\"owner\":\"{}\",\"idle_timeout\":{},\"max_packet_size\":{},\
+         \"initial_max_data\":{},\
+         \"initial_max_stream_data_bidi_local\":{},\
+         \"initial_max_stream_data_bidi_remote\":{},\
+         \"initial_max_stream_data_uni\":{},\
+         \"initial_max_streams_bidi\":{},\"initial_max_streams_uni\":{},\
+         \"ack_delay_exponent\":{},\"max_ack_delay\":{},\
+         \"disable_active_migration\":{}",
+        owner,
+        tp.idle_timeout,
+        tp.max_packet_size,
+        tp.initial_max_data,
+        tp.initial_max_stream_data_bidi_local,
+        tp.initial_max_stream_data_bidi_remote,
+        tp.initial_max_stream_data_uni,
+        tp.initial_max_streams_bidi,
+        tp.initial_max_streams_uni,
+        tp.ack_delay_exponent,
+        tp.max_ack_delay,
+        tp.disable_migration,
+    );
+
+    if let Some(ref v) = tp.original_connection_id {
+        s.push_str(&format!(",\"original_connection_id\":\"{}\"", hex(v)));
+    }
+
+    if let Some(ref v) = tp.stateless_reset_token {
+        s.push_str(&format!(",\"stateless_reset_token\":\"{}\"", hex(v)));
+    }
+
+    if let Some(v) = tp.max_datagram_frame_size {
+        s.push_str(&format!(",\"max_datagram_frame_size\":{}", v));
+    }
+
+    s.push('}');
+
+    s
+}
+
+/// Returns the data of a `security:key_updated` event.
+pub fn key_updated_data(key_type: &str) -> String {
+    format!("{{\"key_type\":\"{}\",\"trigger\":\"tls\"}}", key_type)
+}
+
+fn recovery_metrics(recovery: &recovery::Recovery) -> String {
+    let mut s = format!(
+        "{{\"cwnd\":{},\"bytes_in_flight\":{},\"smoothed_rtt\":{},\
+         \"latest_rtt\":{},\"rtt_variance\":{},\"pto_count\":{}",
+        recovery.congestion_window(),
+        recovery.bytes_in_flight(),
+        as_millis(recovery.rtt()),
+        as_millis(recovery.latest_rtt()),
+        as_millis(recovery.rttvar()),
+        recovery.pto_count(),
+    );
+
+    if let Some(v) = recovery.min_rtt() {
+        s.push_str(&format!(",\"min_rtt\":{}", as_millis(v)));
+    }
+
+    s.push('}');
+
+    s
+}
+
+fn as_millis(d: time::Duration) -> String {
+    let micros = d.as_secs() * 1_000_000 + u64::from(d.subsec_micros());
+
+    format!("{}.{:03}", micros / 1000, micros % 1000)
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+
+            '\\' => out.push_str("\\\\"),
+
+            '\n' => out.push_str("\\n"),
+
+            '\r' => out.push_str("\\r"),
+
+            '\t' => out.push_str("\\t"),
+
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            },
+
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use crate::ranges;
+    use crate::stream;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let mut ranges = ranges::RangeSet::default();
+        ranges.insert(1..4);
+        ranges.insert(7..8);
+
+        let ack = frame::Frame::ACK {
+            ack_delay: 10,
+            ranges,
+            ecn_counts: None,
+        };
+
+        assert_eq!(
+            frame_data(&ack),
+            "{\"frame_type\":\"ack\",\"ack_delay\":10,\
+             \"acked_ranges\":[[1,3],[7,7]]}"
+        );
+
+        let stream = frame::Frame::Stream {
+            stream_id: 4,
+            data: stream::RangeBuf::from(b"hello", 10, true),
+        };
+
+        assert_eq!(
+            frame_data(&stream),
+            "{\"frame_type\":\"stream\",\"stream_id\":4,\"offset\":10,\
+             \"length\":5,\"fin\":true}"
+        );
+
+        let close = frame::Frame::ApplicationClose {
+            error_code: 1,
+            reason: b"say \"bye\"".to_vec(),
+        };
+
+        assert_eq!(
+            frame_data(&close),
+            "{\"frame_type\":\"connection_close\",\
+             \"error_space\":\"application\",\"error_code\":1,\
+             \"reason\":\"say \\\"bye\\\"\"}"
+        );
+    }
+
+    #[test]
+    fn streamer() {
+        let buf = SharedBuf::default();
+
+        let now = time::Instant::now();
+
+        let mut q = QlogStreamer::new(
+            Box::new(buf.clone()),
+            "test \"trace\"",
+            "client",
+            "abcd",
+            now,
+        );
+
+        q.add_event(now, "transport", "packet_sent", "{}");
+        q.add_event(
+            now + time::Duration::from_micros(1500),
+            "transport",
+            "packet_received",
+            "{}",
+        );
+
+        drop(q);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+
+        assert_eq!(
+            out,
+            "{\"qlog_version\":\"draft-01\",\"title\":\"test \\\"trace\\\"\",\
+             \"traces\":[{\"vantage_point\":{\"type\":\"client\"},\
+             \"common_fields\":{\"group_id\":\"abcd\",\"reference_time\":0},\
+             \"event_fields\":[\"relative_time\",\"category\",\"event\",\
+             \"data\"],\"events\":[\
+             [\"0.000\",\"transport\",\"packet_sent\",{}],\
+             [\"1.500\",\"transport\",\"packet_received\",{}]]}]}"
+        );
+    }
+}
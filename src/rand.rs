@@ -25,12 +25,83 @@
 // NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
 // SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-pub fn rand_bytes(buf: &mut [u8]) {
-    unsafe {
-        RAND_bytes(buf.as_mut_ptr(), buf.len());
+use ring::rand::SecureRandom;
+
+/// A source of random bytes used by connections.
+///
+/// Connections use the configured RNG to generate values that are visible on
+/// the wire, such as the client's initial destination connection ID. Keys
+/// and other secrets are always generated by the TLS stack instead.
+pub trait Rng: Send {
+    /// Fills `buf` with random bytes.
+    fn fill(&mut self, buf: &mut [u8]);
+}
+
+/// The default RNG, backed by the operating system's secure random number
+/// generator.
+pub struct SystemRng(ring::rand::SystemRandom);
+
+impl Default for SystemRng {
+    fn default() -> SystemRng {
+        SystemRng(ring::rand::SystemRandom::new())
+    }
+}
+
+impl Rng for SystemRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        rand_bytes_from(&self.0, buf);
+    }
+}
+
+/// A deterministic RNG initialized from a fixed seed.
+///
+/// Two RNGs created with the same seed produce the same sequence of bytes,
+/// which makes connections reproducible in tests, fuzzing and simulations.
+///
+/// This is **not** cryptographically secure, and must not be used in
+/// production.
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    /// Creates a new RNG from the given seed.
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng { state: seed }
+    }
+
+    // SplitMix64, see http://prng.di.unimi.it/splitmix64.c
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let v = self.next_u64().to_be_bytes();
+
+            chunk.copy_from_slice(&v[..chunk.len()]);
+        }
     }
 }
 
+/// Fills `buf` with random bytes from the system's secure RNG.
+pub fn rand_bytes(buf: &mut [u8]) {
+    rand_bytes_from(&ring::rand::SystemRandom::new(), buf);
+}
+
+fn rand_bytes_from(rng: &ring::rand::SystemRandom, buf: &mut [u8]) {
+    // There's no sensible way to recover from the system's RNG failing, and
+    // carrying on with predictable values would be much worse.
+    rng.fill(buf).expect("failed to generate random bytes");
+}
+
 pub fn rand_u8() -> u8 {
     let mut buf = [0; 1];
 
@@ -39,6 +110,36 @@ pub fn rand_u8() -> u8 {
     buf[0]
 }
 
-extern {
-    fn RAND_bytes(buf: *mut u8, len: libc::size_t) -> libc::c_int;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let mut a = [0; 20];
+        SeededRng::new(42).fill(&mut a);
+
+        let mut b = [0; 20];
+        SeededRng::new(42).fill(&mut b);
+
+        assert_eq!(a, b);
+
+        let mut c = [0; 20];
+        SeededRng::new(43).fill(&mut c);
+
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn seeded_partial_chunks() {
+        let mut rng = SeededRng::new(42);
+
+        let mut a = [0; 3];
+        rng.fill(&mut a);
+
+        let mut b = [0; 8];
+        SeededRng::new(42).fill(&mut b);
+
+        assert_eq!(a, b[..3]);
+    }
 }
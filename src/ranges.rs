@@ -31,12 +31,37 @@ use std::collections::btree_map;
 use std::collections::BTreeMap;
 use std::collections::Bound;
 
-#[derive(Clone, Default, PartialEq, PartialOrd)]
+#[derive(Clone)]
 pub struct RangeSet {
     inner: BTreeMap<u64, u64>,
+
+    capacity: usize,
+}
+
+impl Default for RangeSet {
+    fn default() -> RangeSet {
+        RangeSet::with_capacity(std::usize::MAX)
+    }
+}
+
+impl PartialEq for RangeSet {
+    fn eq(&self, other: &RangeSet) -> bool {
+        self.inner == other.inner
+    }
 }
 
 impl RangeSet {
+    /// Creates a set that holds at most `capacity` ranges.
+    ///
+    /// When inserting a new range would exceed the capacity, the smallest
+    /// ranges are dropped.
+    pub fn with_capacity(capacity: usize) -> RangeSet {
+        RangeSet {
+            inner: BTreeMap::new(),
+            capacity,
+        }
+    }
+
     // TODO: use RangeInclusive
     pub fn insert(&mut self, item: Range<u64>) {
         let mut start = item.start;
@@ -74,6 +99,29 @@ impl RangeSet {
         }
 
         self.inner.insert(start, end);
+
+        while self.inner.len() > self.capacity {
+            self.pop_front();
+        }
+    }
+
+    /// Removes the smallest range from the set.
+    pub fn pop_front(&mut self) -> Option<Range<u64>> {
+        let start = *self.inner.keys().next()?;
+        let end = self.inner.remove(&start)?;
+
+        Some(start..end)
+    }
+
+    /// Returns the number of ranges in the set.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns true if the set contains no ranges.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
     }
 
     pub fn remove_until(&mut self, largest: u64) {
@@ -482,4 +530,58 @@ mod tests {
         r.remove_until(20);
         assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[]);
     }
+
+    #[test]
+    fn pop_front() {
+        let mut r = RangeSet::default();
+        assert_eq!(r.pop_front(), None);
+        assert!(r.is_empty());
+
+        r.insert(3..6);
+        r.insert(9..11);
+        r.insert(13..14);
+        assert_eq!(r.len(), 3);
+
+        assert_eq!(r.pop_front(), Some(3..6));
+        assert_eq!(r.pop_front(), Some(9..11));
+        assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[13]);
+        assert_eq!(r.len(), 1);
+        assert!(!r.is_empty());
+
+        assert_eq!(r.pop_front(), Some(13..14));
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut r = RangeSet::with_capacity(3);
+
+        r.insert(19..20);
+        r.insert(10..11);
+        r.insert(17..18);
+        r.insert(13..14);
+        assert_eq!(r.len(), 3);
+        assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[13, 17, 19]);
+
+        // Merging ranges frees up space.
+        r.insert(14..17);
+        assert_eq!(r.len(), 2);
+
+        r.insert(1..2);
+        assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[
+            1, 13, 14, 15, 16, 17, 19
+        ]);
+
+        // The smallest range is dropped to make room for the new one.
+        r.insert(7..8);
+        assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[
+            7, 13, 14, 15, 16, 17, 19
+        ]);
+
+        // Unless the new range is the smallest.
+        r.insert(2..3);
+        assert_eq!(&r.flatten().collect::<Vec<u64>>(), &[
+            7, 13, 14, 15, 16, 17, 19
+        ]);
+    }
 }
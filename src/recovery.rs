@@ -33,6 +33,7 @@ use std::time::Instant;
 use std::collections::BTreeMap;
 
 use crate::frame;
+use crate::packet;
 use crate::ranges;
 
 // Loss Recovery
@@ -42,13 +43,14 @@ const GRANULARITY: Duration = Duration::from_millis(1);
 
 const INITIAL_RTT: Duration = Duration::from_millis(100);
 
+const MAX_PTO_PROBES: usize = 2;
+
 // Congestion Control
 const MAX_DATAGRAM_SIZE: usize = 1452;
 
 const INITIAL_WINDOW: usize = 10 * MAX_DATAGRAM_SIZE;
-const MINIMUM_WINDOW: usize = 2 * MAX_DATAGRAM_SIZE;
 
-const PERSISTENT_CONGESTION_THRESHOLD: u32 = 2;
+const PERSISTENT_CONGESTION_THRESHOLD: u32 = 3;
 
 #[derive(Debug)]
 pub struct Sent {
@@ -63,12 +65,16 @@ pub struct Sent {
     ack_eliciting: bool,
 
     is_crypto: bool,
+
+    ecn_marked: bool,
+
+    pub pmtud_probe: bool,
 }
 
 impl Sent {
     pub fn new(
         pkt_num: u64, frames: Vec<frame::Frame>, sent_bytes: usize,
-        ack_eliciting: bool, is_crypto: bool, now: Instant,
+        ack_eliciting: bool, is_crypto: bool, ecn_marked: bool, now: Instant,
     ) -> Sent {
         let sent_bytes = if ack_eliciting { sent_bytes } else { 0 };
 
@@ -79,6 +85,8 @@ impl Sent {
             size: sent_bytes,
             ack_eliciting,
             is_crypto,
+            ecn_marked,
+            pmtud_probe: false,
         }
     }
 }
@@ -89,6 +97,11 @@ pub struct InFlight {
     pub acked: Vec<frame::Frame>,
 
     pub lost_count: usize,
+
+    /// Packet numbers of lost packets, only recorded when `track_lost_pkts`
+    /// is set.
+    pub lost_pkts: Vec<u64>,
+    pub track_lost_pkts: bool,
 }
 
 impl Default for InFlight {
@@ -99,69 +112,69 @@ impl Default for InFlight {
             acked: Vec::new(),
 
             lost_count: 0,
+            lost_pkts: Vec::new(),
+            track_lost_pkts: false,
         }
     }
 }
 
 impl InFlight {
-    pub fn retransmit_unacked_crypto(&mut self, trace_id: &str) -> usize {
-        let mut unacked_bytes = 0;
-
-        for p in &mut self.sent.values_mut().filter(|p| p.is_crypto) {
-            p.frames.retain(|f| match f {
-                frame::Frame::Crypto { .. } => true,
-
-                _ => false,
-            });
+    /// Returns the number of ECN-marked packets in `ranges` that haven't been
+    /// acknowledged yet.
+    pub fn ecn_marked_count(&self, ranges: &ranges::RangeSet) -> u64 {
+        ranges
+            .iter()
+            .map(|r| self.sent.range(r).filter(|(_, p)| p.ecn_marked).count())
+            .sum::<usize>() as u64
+    }
 
-            trace!("{} crypto packet lost {}", trace_id, p.pkt_num);
+    /// Schedules the retransmission of the CRYPTO frames of unacknowledged
+    /// packets, without declaring the packets lost.
+    ///
+    /// The CRYPTO frames are moved out of the packets, so that they are only
+    /// scheduled once even if the packets stay unacknowledged across PTOs.
+    pub fn retransmit_unacked_crypto(&mut self, trace_id: &str) {
+        for p in self.sent.values_mut().filter(|p| p.is_crypto) {
+            for frame in std::mem::replace(&mut p.frames, Vec::new()) {
+                match frame {
+                    frame::Frame::Crypto { .. } => self.lost.push(frame),
+
+                    _ => p.frames.push(frame),
+                }
+            }
 
-            unacked_bytes += p.size;
+            p.is_crypto = false;
 
-            self.lost.append(&mut p.frames);
+            trace!("{} crypto packet retransmitted {}", trace_id, p.pkt_num);
         }
-
-        self.lost_count += self.sent.len();
-
-        self.sent.clear();
-
-        unacked_bytes
     }
 
-    pub fn drop_unacked_data(&mut self) -> (usize, usize) {
-        let mut unacked_bytes = 0;
-        let mut crypto_unacked_bytes = 0;
-
-        for p in self.sent.values_mut().filter(|p| p.ack_eliciting) {
-            unacked_bytes += p.size;
-
-            if p.is_crypto {
-                crypto_unacked_bytes += p.size;
-            }
-        }
+    pub fn drop_unacked_data(&mut self) -> usize {
+        let unacked_bytes = self
+            .sent
+            .values()
+            .filter(|p| p.ack_eliciting)
+            .map(|p| p.size)
+            .sum();
 
         self.lost_count += self.sent.len();
 
         self.sent.clear();
 
-        (crypto_unacked_bytes, unacked_bytes)
+        unacked_bytes
     }
 }
 
 pub struct Recovery {
     loss_detection_timer: Option<Instant>,
 
-    crypto_count: u32,
-
     pto_count: u32,
 
-    time_of_last_sent_ack_eliciting_pkt: Instant,
+    time_of_last_sent_ack_eliciting_pkt: [Option<Instant>; packet::EPOCH_COUNT],
 
-    time_of_last_sent_crypto_pkt: Instant,
+    largest_acked_pkt: [Option<u64>; packet::EPOCH_COUNT],
 
-    largest_sent_pkt: u64,
-
-    largest_acked_pkt: u64,
+    ack_eliciting_in_flight: [usize; packet::EPOCH_COUNT],
 
     latest_rtt: Duration,
 
@@ -171,41 +184,41 @@ pub struct Recovery {
 
     min_rtt: Duration,
 
+    first_rtt_sample: Option<Instant>,
+
     pub max_ack_delay: Duration,
 
-    loss_time: Option<Instant>,
+    loss_time: [Option<Instant>; packet::EPOCH_COUNT],
 
     bytes_in_flight: usize,
 
-    crypto_bytes_in_flight: usize,
-
     cwnd: usize,
 
     recovery_start_time: Option<Instant>,
 
     ssthresh: usize,
 
-    pub probes: usize,
+    max_datagram_size: usize,
+
+    last_acked_sent_time: [Option<Instant>; packet::EPOCH_COUNT],
+
+    pub handshake_completed: bool,
+
+    pub loss_probes: [usize; packet::EPOCH_COUNT],
 }
 
 impl Default for Recovery {
     fn default() -> Recovery {
-        let now = Instant::now();
-
         Recovery {
             loss_detection_timer: None,
 
-            crypto_count: 0,
-
             pto_count: 0,
 
-            time_of_last_sent_crypto_pkt: now,
-
-            time_of_last_sent_ack_eliciting_pkt: now,
+            time_of_last_sent_ack_eliciting_pkt: [None; packet::EPOCH_COUNT],
 
-            largest_sent_pkt: 0,
+            largest_acked_pkt: [None; packet::EPOCH_COUNT],
 
-            largest_acked_pkt: 0,
+            ack_eliciting_in_flight: [0; packet::EPOCH_COUNT],
 
             latest_rtt: Duration::new(0, 0),
 
@@ -215,46 +228,46 @@ impl Default for Recovery {
 
             rttvar: Duration::new(0, 0),
 
+            first_rtt_sample: None,
+
             max_ack_delay: Duration::from_millis(25),
 
-            loss_time: None,
+            loss_time: [None; packet::EPOCH_COUNT],
 
             bytes_in_flight: 0,
 
-            crypto_bytes_in_flight: 0,
-
             cwnd: INITIAL_WINDOW,
 
             recovery_start_time: None,
 
             ssthresh: std::usize::MAX,
 
-            probes: 0,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+
+            last_acked_sent_time: [None; packet::EPOCH_COUNT],
+
+            handshake_completed: false,
+
+            loss_probes: [0; packet::EPOCH_COUNT],
         }
     }
 }
 
 impl Recovery {
     pub fn on_packet_sent(
-        &mut self, pkt: Sent, flight: &mut InFlight, now: Instant, trace_id: &str,
+        &mut self, pkt: Sent, epoch: packet::Epoch, flight: &mut InFlight,
+        now: Instant, trace_id: &str,
     ) {
         let pkt_num = pkt.pkt_num;
         let ack_eliciting = pkt.ack_eliciting;
-        let is_crypto = pkt.is_crypto;
         let sent_bytes = pkt.size;
 
-        self.largest_sent_pkt = pkt_num;
-
         flight.sent.insert(pkt_num, pkt);
 
         if ack_eliciting {
-            if is_crypto {
-                self.time_of_last_sent_crypto_pkt = now;
-
-                self.crypto_bytes_in_flight += sent_bytes;
-            }
+            self.time_of_last_sent_ack_eliciting_pkt[epoch] = Some(now);
 
-            self.time_of_last_sent_ack_eliciting_pkt = now;
+            self.ack_eliciting_in_flight[epoch] += 1;
 
             // OnPacketSentCC
             self.bytes_in_flight += sent_bytes;
@@ -267,22 +280,38 @@ impl Recovery {
 
     pub fn on_ack_received(
         &mut self, ranges: &ranges::RangeSet, ack_delay: u64,
-        flight: &mut InFlight, now: Instant, trace_id: &str,
+        epoch: packet::Epoch, flight: &mut InFlight, now: Instant,
+        trace_id: &str,
     ) {
-        self.largest_acked_pkt =
-            cmp::max(self.largest_acked_pkt, ranges.largest().unwrap());
+        let largest_acked = ranges.largest().unwrap();
+
+        self.largest_acked_pkt[epoch] =
+            Some(match self.largest_acked_pkt[epoch] {
+                Some(v) => cmp::max(v, largest_acked),
+
+                None => largest_acked,
+            });
 
-        if let Some(pkt) = flight.sent.get(&self.largest_acked_pkt) {
+        // Only take an RTT sample if the largest acknowledged packet is newly
+        // acknowledged and ACK-eliciting.
+        if let Some(pkt) = flight.sent.get(&largest_acked) {
             if pkt.ack_eliciting {
-                let ack_delay = Duration::from_micros(ack_delay);
-                self.update_rtt(pkt.time.elapsed(), ack_delay);
+                // The ACK delay is only meaningful for application data, as
+                // handshake packets are acknowledged immediately.
+                let ack_delay = if epoch == packet::EPOCH_APPLICATION {
+                    Duration::from_micros(ack_delay)
+                } else {
+                    Duration::new(0, 0)
+                };
+
+                self.update_rtt(now.duration_since(pkt.time), ack_delay, now);
             }
         }
 
         let mut has_newly_acked = false;
 
         for pn in ranges.flatten() {
-            let newly_acked = self.on_packet_acked(pn, flight);
+            let newly_acked = self.on_packet_acked(pn, epoch, flight);
             has_newly_acked = cmp::max(has_newly_acked, newly_acked);
 
             if newly_acked {
@@ -294,9 +323,8 @@ impl Recovery {
             return;
         }
 
-        self.detect_lost_packets(flight, now, trace_id);
+        self.detect_lost_packets(epoch, flight, now, trace_id);
 
-        self.crypto_count = 0;
         self.pto_count = 0;
 
         self.set_loss_detection_timer();
@@ -304,50 +332,120 @@ impl Recovery {
         trace!("{} {:?}", trace_id, self);
     }
 
+    /// Validates the ECN counts of an ACK frame against the previously
+    /// reported `prev_counts`, and reacts to newly reported CE marks.
+    ///
+    /// This needs to be called before the ACK frame is processed by
+    /// `on_ack_received()`. Returns false if validation failed, in which case
+    /// packets should not be ECN-marked anymore.
+    pub fn on_ecn_counts(
+        &mut self, ranges: &ranges::RangeSet,
+        ecn_counts: Option<frame::EcnCounts>, prev_counts: &mut frame::EcnCounts,
+        flight: &InFlight, now: Instant, trace_id: &str,
+    ) -> bool {
+        let newly_acked_ecn = flight.ecn_marked_count(ranges);
+
+        let ecn_counts = match ecn_counts {
+            Some(v) => v,
+
+            // ECN-marked packets were acknowledged without ECN counts, so
+            // the marks were likely cleared along the path.
+            None => return newly_acked_ecn == 0,
+        };
+
+        // Counts can't decrease, and ECT(1) is never sent.
+        if ecn_counts.ect0_count < prev_counts.ect0_count ||
+            ecn_counts.ecn_ce_count < prev_counts.ecn_ce_count ||
+            ecn_counts.ect1_count > 0
+        {
+            return false;
+        }
+
+        let ect0_increase = ecn_counts.ect0_count - prev_counts.ect0_count;
+        let ce_increase = ecn_counts.ecn_ce_count - prev_counts.ecn_ce_count;
+
+        // All newly acknowledged ECN-marked packets must be accounted for.
+        if ect0_increase + ce_increase < newly_acked_ecn {
+            return false;
+        }
+
+        *prev_counts = ecn_counts;
+
+        if ce_increase > 0 {
+            let largest_acked = ranges.largest().unwrap();
+
+            if let Some(p) = flight.sent.get(&largest_acked) {
+                trace!("{} ECN-CE marks received {}", trace_id, ce_increase);
+
+                self.congestion_event(p.time, now);
+            }
+        }
+
+        true
+    }
+
     pub fn on_loss_detection_timer(
-        &mut self, in_flight: &mut InFlight, hs_flight: &mut InFlight,
-        flight: &mut InFlight, now: Instant, trace_id: &str,
+        &mut self, flights: [&mut InFlight; packet::EPOCH_COUNT], now: Instant,
+        trace_id: &str,
     ) {
-        if self.crypto_bytes_in_flight > 0 {
-            self.crypto_count += 1;
-
-            let unacked_bytes = in_flight.retransmit_unacked_crypto(trace_id);
-            self.crypto_bytes_in_flight -= unacked_bytes;
-            self.bytes_in_flight -= unacked_bytes;
-
-            let unacked_bytes = hs_flight.retransmit_unacked_crypto(trace_id);
-            self.crypto_bytes_in_flight -= unacked_bytes;
-            self.bytes_in_flight -= unacked_bytes;
-
-            let unacked_bytes = flight.retransmit_unacked_crypto(trace_id);
-            self.crypto_bytes_in_flight -= unacked_bytes;
-            self.bytes_in_flight -= unacked_bytes;
-        } else if self.loss_time.is_some() {
-            self.detect_lost_packets(flight, now, trace_id);
-        } else {
-            self.pto_count += 1;
-            self.probes = 2;
+        let (earliest_loss_time, epoch) = self.loss_time_and_space();
+
+        if earliest_loss_time.is_some() {
+            // Time threshold loss detection.
+            self.detect_lost_packets(epoch, flights[epoch], now, trace_id);
+
+            self.set_loss_detection_timer();
+
+            trace!("{} {:?}", trace_id, self);
+            return;
         }
 
+        // PTO. Probes might not be able to carry new data, so retransmit the
+        // unacknowledged CRYPTO data of the space as well.
+        let (_, epoch) = self.pto_time_and_space();
+
+        flights[epoch].retransmit_unacked_crypto(trace_id);
+
+        self.pto_count += 1;
+
+        self.loss_probes[epoch] = MAX_PTO_PROBES;
+
         self.set_loss_detection_timer();
 
         trace!("{} {:?}", trace_id, self);
     }
 
-    pub fn drop_unacked_data(&mut self, flight: &mut InFlight) {
-        let (crypto_unacked_bytes, unacked_bytes) = flight.drop_unacked_data();
+    /// Discards the unacknowledged packets of a packet number space, e.g.
+    /// when its keys are dropped.
+    pub fn drop_unacked_data(
+        &mut self, epoch: packet::Epoch, flight: &mut InFlight,
+    ) {
+        let unacked_bytes = flight.drop_unacked_data();
 
-        self.crypto_bytes_in_flight -= crypto_unacked_bytes;
         self.bytes_in_flight -= unacked_bytes;
+
+        self.ack_eliciting_in_flight[epoch] = 0;
+        self.time_of_last_sent_ack_eliciting_pkt[epoch] = None;
+        self.loss_time[epoch] = None;
+        self.loss_probes[epoch] = 0;
+
+        self.pto_count = 0;
+
+        self.set_loss_detection_timer();
     }
 
     pub fn loss_detection_timer(&self) -> Option<Instant> {
         self.loss_detection_timer
     }
 
+    /// Returns the packet number space probes need to be sent on, if any.
+    pub fn probe_epoch(&self) -> Option<packet::Epoch> {
+        self.loss_probes.iter().position(|&v| v > 0)
+    }
+
     pub fn cwnd(&self) -> usize {
         // Ignore cwnd when sending probe packets.
-        if self.probes > 0 {
+        if self.probe_epoch().is_some() {
             return std::usize::MAX;
         }
 
@@ -358,6 +456,55 @@ impl Recovery {
         self.cwnd - self.bytes_in_flight
     }
 
+    /// Returns the size of the congestion window.
+    pub fn congestion_window(&self) -> usize {
+        self.cwnd
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    pub fn pto_count(&self) -> u32 {
+        self.pto_count
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.first_rtt_sample.map(|_| self.min_rtt)
+    }
+
+    pub fn latest_rtt(&self) -> Duration {
+        self.latest_rtt
+    }
+
+    /// Returns the name of the current congestion control state.
+    pub fn congestion_state(&self) -> &'static str {
+        if let Some(recovery_start_time) = self.recovery_start_time {
+            // Recovery ends once a packet sent after it started is acked.
+            let recovered = self
+                .last_acked_sent_time
+                .iter()
+                .filter_map(|&t| t)
+                .any(|t| t > recovery_start_time);
+
+            if !recovered {
+                return "recovery";
+            }
+        }
+
+        if self.cwnd < self.ssthresh {
+            return "slow_start";
+        }
+
+        "congestion_avoidance"
+    }
+
+    /// Updates the maximum datagram size used by congestion control, e.g.
+    /// after a larger path MTU was discovered.
+    pub fn set_max_datagram_size(&mut self, v: usize) {
+        self.max_datagram_size = v;
+    }
+
     pub fn rtt(&self) -> Duration {
         let zero = Duration::new(0, 0);
 
@@ -368,7 +515,19 @@ impl Recovery {
         self.smoothed_rtt
     }
 
-    fn update_rtt(&mut self, latest_rtt: Duration, ack_delay: Duration) {
+    pub fn rttvar(&self) -> Duration {
+        let zero = Duration::new(0, 0);
+
+        if self.smoothed_rtt == zero {
+            return INITIAL_RTT / 2;
+        }
+
+        self.rttvar
+    }
+
+    fn update_rtt(
+        &mut self, latest_rtt: Duration, ack_delay: Duration, now: Instant,
+    ) {
         let zero = Duration::new(0, 0);
 
         self.min_rtt = cmp::min(self.min_rtt, latest_rtt);
@@ -385,6 +544,8 @@ impl Recovery {
             self.rttvar = self.latest_rtt / 2;
 
             self.smoothed_rtt = self.latest_rtt;
+
+            self.first_rtt_sample = Some(now);
         } else {
             let rttvar_sample = sub_abs(self.smoothed_rtt, self.latest_rtt);
 
@@ -394,65 +555,104 @@ impl Recovery {
         }
     }
 
-    fn set_loss_detection_timer(&mut self) {
-        let zero = Duration::new(0, 0);
+    /// Returns the PTO period, without backoff and `max_ack_delay`.
+    fn pto(&self) -> Duration {
+        self.rtt() + cmp::max(self.rttvar() * 4, GRANULARITY)
+    }
 
-        if self.bytes_in_flight == 0 {
-            self.loss_detection_timer = None;
-            return;
+    /// Returns the earliest loss time and its packet number space.
+    fn loss_time_and_space(&self) -> (Option<Instant>, packet::Epoch) {
+        let mut time = self.loss_time[packet::EPOCH_INITIAL];
+        let mut epoch = packet::EPOCH_INITIAL;
+
+        for e in packet::EPOCH_HANDSHAKE..packet::EPOCH_COUNT {
+            let new_time = self.loss_time[e];
+
+            if time.is_none() || (new_time.is_some() && new_time < time) {
+                time = new_time;
+                epoch = e;
+            }
         }
 
-        if self.crypto_bytes_in_flight > 0 {
-            // Crypto retransmission timer.
-            let mut timeout = if self.smoothed_rtt == zero {
-                INITIAL_RTT * 2
-            } else {
-                self.smoothed_rtt * 2
-            };
+        (time, epoch)
+    }
 
-            timeout = cmp::max(timeout, GRANULARITY);
-            timeout *= 2_u32.pow(self.crypto_count);
+    /// Returns the earliest PTO expiration time and its packet number space.
+    fn pto_time_and_space(&self) -> (Option<Instant>, packet::Epoch) {
+        let backoff = 2_u32.pow(self.pto_count);
 
-            self.loss_detection_timer =
-                Some(self.time_of_last_sent_crypto_pkt + timeout);
+        let mut time = None;
+        let mut epoch = packet::EPOCH_INITIAL;
 
-            return;
+        for e in packet::EPOCH_INITIAL..packet::EPOCH_COUNT {
+            if self.ack_eliciting_in_flight[e] == 0 {
+                continue;
+            }
+
+            let mut duration = self.pto() * backoff;
+
+            if e == packet::EPOCH_APPLICATION {
+                // Application data is not probed until the handshake
+                // completes.
+                if !self.handshake_completed {
+                    break;
+                }
+
+                duration += self.max_ack_delay * backoff;
+            }
+
+            let new_time =
+                self.time_of_last_sent_ack_eliciting_pkt[e].map(|t| t + duration);
+
+            if time.is_none() || new_time < time {
+                time = new_time;
+                epoch = e;
+            }
         }
 
-        if self.loss_time.is_some() {
+        (time, epoch)
+    }
+
+    fn set_loss_detection_timer(&mut self) {
+        let (earliest_loss_time, _) = self.loss_time_and_space();
+
+        if earliest_loss_time.is_some() {
             // Time threshold loss detection.
-            self.loss_detection_timer = self.loss_time;
+            self.loss_detection_timer = earliest_loss_time;
             return;
         }
 
-        // PTO timer.
-        let mut timeout =
-            self.smoothed_rtt + (self.rttvar * 4) + self.max_ack_delay;
+        // PTO timer, when there are ACK-eliciting packets in flight.
+        let (timeout, _) = self.pto_time_and_space();
 
-        timeout = cmp::max(timeout, GRANULARITY);
-        timeout *= 2_u32.pow(self.pto_count);
-
-        self.loss_detection_timer =
-            Some(self.time_of_last_sent_ack_eliciting_pkt + timeout);
+        self.loss_detection_timer = timeout;
     }
 
     fn detect_lost_packets(
-        &mut self, flight: &mut InFlight, now: Instant, trace_id: &str,
+        &mut self, epoch: packet::Epoch, flight: &mut InFlight, now: Instant,
+        trace_id: &str,
     ) {
         let mut lost_pkt: Vec<u64> = Vec::new();
 
-        let largest_acked = self.largest_acked_pkt;
+        let largest_acked = match self.largest_acked_pkt[epoch] {
+            Some(v) => v,
+            None => return,
+        };
 
-        let loss_delay = (cmp::max(self.latest_rtt, self.smoothed_rtt) * 9) / 8;
+        let loss_delay = cmp::max(
+            (cmp::max(self.latest_rtt, self.smoothed_rtt) * 9) / 8,
+            GRANULARITY,
+        );
 
+        // Packets sent before this time are deemed lost.
         let lost_send_time = now - loss_delay;
 
-        let lost_pkt_num = largest_acked.saturating_sub(PACKET_THRESHOLD);
-
-        self.loss_time = None;
+        self.loss_time[epoch] = None;
 
         for (_, unacked) in flight.sent.range(..=largest_acked) {
-            if unacked.time <= lost_send_time || unacked.pkt_num <= lost_pkt_num {
+            if unacked.time <= lost_send_time ||
+                unacked.pkt_num + PACKET_THRESHOLD <= largest_acked
+            {
                 if unacked.ack_eliciting {
                     trace!("{} packet lost {}", trace_id, unacked.pkt_num);
                 }
@@ -460,20 +660,26 @@ impl Recovery {
                 // We can't remove the lost packet from |flight.sent| here, so
                 // simply keep track of the number so it can be removed later.
                 lost_pkt.push(unacked.pkt_num);
-            } else if self.loss_time.is_none() {
-                self.loss_time = Some(unacked.time + loss_delay);
             } else {
-                let loss_time = self.loss_time.unwrap();
-                self.loss_time =
-                    Some(cmp::min(loss_time, unacked.time + loss_delay));
+                let loss_time = unacked.time + loss_delay;
+
+                self.loss_time[epoch] = match self.loss_time[epoch] {
+                    Some(v) => Some(cmp::min(v, loss_time)),
+
+                    None => Some(loss_time),
+                };
             }
         }
 
         if !lost_pkt.is_empty() {
-            self.on_packets_lost(lost_pkt, flight, now);
+            self.on_packets_lost(lost_pkt, epoch, flight, now, trace_id);
         }
     }
 
+    fn minimum_window(&self) -> usize {
+        2 * self.max_datagram_size
+    }
+
     fn in_recovery(&self, sent_time: Instant) -> bool {
         match self.recovery_start_time {
             Some(recovery_start_time) => sent_time <= recovery_start_time,
@@ -482,19 +688,23 @@ impl Recovery {
         }
     }
 
-    fn on_packet_acked(&mut self, pkt_num: u64, flight: &mut InFlight) -> bool {
+    fn on_packet_acked(
+        &mut self, pkt_num: u64, epoch: packet::Epoch, flight: &mut InFlight,
+    ) -> bool {
         // Check if packet is newly acked.
         if let Some(mut p) = flight.sent.remove(&pkt_num) {
             flight.acked.append(&mut p.frames);
 
             if p.ack_eliciting {
-                // OnPacketAckedCC
-                self.bytes_in_flight -= p.size;
+                self.ack_eliciting_in_flight[epoch] -= 1;
 
-                if p.is_crypto {
-                    self.crypto_bytes_in_flight -= p.size;
+                if self.last_acked_sent_time[epoch].map_or(true, |t| p.time > t) {
+                    self.last_acked_sent_time[epoch] = Some(p.time);
                 }
 
+                // OnPacketAckedCC
+                self.bytes_in_flight -= p.size;
+
                 if self.in_recovery(p.time) {
                     return true;
                 }
@@ -502,7 +712,7 @@ impl Recovery {
                 if self.cwnd < self.ssthresh {
                     self.cwnd += p.size;
                 } else {
-                    self.cwnd += (MAX_DATAGRAM_SIZE * p.size) / self.cwnd;
+                    self.cwnd += (self.max_datagram_size * p.size) / self.cwnd;
                 }
             }
 
@@ -514,7 +724,8 @@ impl Recovery {
     }
 
     fn on_packets_lost(
-        &mut self, lost_pkt: Vec<u64>, flight: &mut InFlight, now: Instant,
+        &mut self, lost_pkt: Vec<u64>, epoch: packet::Epoch,
+        flight: &mut InFlight, now: Instant, trace_id: &str,
     ) {
         // Differently from OnPacketsLost(), we need to handle both
         // ACK-eliciting and non-ACK-eliciting packets, so need to keep of
@@ -522,10 +733,35 @@ impl Recovery {
         // congestion event later.
         let mut largest_lost_pkt_sent_time: Option<Instant> = None;
 
+        // The send times of the first and last ACK-eliciting packets of the
+        // current run of consecutive lost packets, considered for persistent
+        // congestion.
+        let mut period: Option<(Instant, Instant)> = None;
+        let mut prev_pkt_num: Option<u64> = None;
+
+        let mut persistent_congestion = false;
+
         for lost in lost_pkt {
             let mut p = flight.sent.remove(&lost).unwrap();
 
-            flight.lost_count += 1;
+            // A gap in packet numbers means that packets in between were
+            // acknowledged, so a new period starts.
+            if prev_pkt_num.map_or(false, |pn| pn + 1 != lost) {
+                period = None;
+            }
+
+            prev_pkt_num = Some(lost);
+
+            // A lost PMTU probe only means the path doesn't support its
+            // size, so it's not counted as lost and doesn't trigger a
+            // congestion event. Its frames are still retransmitted.
+            if !p.pmtud_probe {
+                flight.lost_count += 1;
+
+                if flight.track_lost_pkts {
+                    flight.lost_pkts.push(lost);
+                }
+            }
 
             if !p.ack_eliciting {
                 continue;
@@ -533,30 +769,70 @@ impl Recovery {
 
             self.bytes_in_flight -= p.size;
 
-            if p.is_crypto {
-                self.crypto_bytes_in_flight -= p.size;
-            }
+            self.ack_eliciting_in_flight[epoch] -= 1;
 
             flight.lost.append(&mut p.frames);
 
+            if p.pmtud_probe {
+                continue;
+            }
+
             largest_lost_pkt_sent_time = Some(p.time);
+
+            // Only packets sent after the first RTT sample are considered.
+            if self.first_rtt_sample.map_or(true, |t| p.time <= t) {
+                continue;
+            }
+
+            let start = period.map_or(p.time, |(start, _)| start);
+
+            period = Some((start, p.time));
+
+            if self.in_persistent_congestion(epoch, start, p.time) {
+                persistent_congestion = true;
+            }
         }
 
-        if largest_lost_pkt_sent_time.is_none() {
-            return;
+        if let Some(sent_time) = largest_lost_pkt_sent_time {
+            self.congestion_event(sent_time, now);
+        }
+
+        if persistent_congestion {
+            trace!("{} persistent congestion", trace_id);
+
+            self.cwnd = self.minimum_window();
+            self.recovery_start_time = None;
+        }
+    }
+
+    /// Returns true if the ACK-eliciting packets lost between `start` and
+    /// `end` indicate persistent congestion.
+    ///
+    /// Packets of other packet number spaces sent in the same period must not
+    /// have been acknowledged either.
+    fn in_persistent_congestion(
+        &self, epoch: packet::Epoch, start: Instant, end: Instant,
+    ) -> bool {
+        let duration =
+            (self.pto() + self.max_ack_delay) * PERSISTENT_CONGESTION_THRESHOLD;
+
+        if end - start < duration {
+            return false;
         }
 
-        // CongestionEvent
-        if !self.in_recovery(largest_lost_pkt_sent_time.unwrap()) {
+        !(packet::EPOCH_INITIAL..packet::EPOCH_COUNT)
+            .filter(|&e| e != epoch)
+            .filter_map(|e| self.last_acked_sent_time[e])
+            .any(|t| t > start && t < end)
+    }
+
+    fn congestion_event(&mut self, sent_time: Instant, now: Instant) {
+        if !self.in_recovery(sent_time) {
             self.recovery_start_time = Some(now);
 
             self.cwnd /= 2;
-            self.cwnd = cmp::max(self.cwnd, MINIMUM_WINDOW);
+            self.cwnd = cmp::max(self.cwnd, self.minimum_window());
             self.ssthresh = self.cwnd;
-
-            if self.pto_count > PERSISTENT_CONGESTION_THRESHOLD {
-                self.cwnd = MINIMUM_WINDOW;
-            }
         }
     }
 }
@@ -580,14 +856,14 @@ impl std::fmt::Debug for Recovery {
             },
         };
 
-        write!(f, "crypto={} ", self.crypto_bytes_in_flight)?;
         write!(f, "inflight={} ", self.bytes_in_flight)?;
         write!(f, "cwnd={} ", self.cwnd)?;
         write!(f, "latest_rtt={:?} ", self.latest_rtt)?;
         write!(f, "srtt={:?} ", self.smoothed_rtt)?;
         write!(f, "min_rtt={:?} ", self.min_rtt)?;
         write!(f, "rttvar={:?} ", self.rttvar)?;
-        write!(f, "probes={} ", self.probes)?;
+        write!(f, "pto_count={} ", self.pto_count)?;
+        write!(f, "probes={:?} ", self.loss_probes)?;
 
         Ok(())
     }
@@ -600,3 +876,273 @@ fn sub_abs(lhs: Duration, rhs: Duration) -> Duration {
         rhs - lhs
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stream;
+
+    fn sent(pkt_num: u64, now: Instant) -> Sent {
+        Sent::new(
+            pkt_num,
+            vec![frame::Frame::Ping],
+            1000,
+            true,
+            false,
+            false,
+            now,
+        )
+    }
+
+    fn ack(r: &mut Recovery, pkts: &[u64], flight: &mut InFlight, now: Instant) {
+        let mut ranges = ranges::RangeSet::default();
+
+        for &pn in pkts {
+            ranges.insert(pn..pn + 1);
+        }
+
+        r.on_ack_received(&ranges, 0, packet::EPOCH_APPLICATION, flight, now, "");
+    }
+
+    #[test]
+    fn pto() {
+        let mut r = Recovery::default();
+        r.handshake_completed = true;
+
+        let mut in_flight = InFlight::default();
+        let mut hs_flight = InFlight::default();
+        let mut flight = InFlight::default();
+
+        let now = Instant::now();
+
+        for pn in 0..2 {
+            r.on_packet_sent(
+                sent(pn, now),
+                packet::EPOCH_APPLICATION,
+                &mut flight,
+                now,
+                "",
+            );
+        }
+
+        // Without RTT samples, PTO = initial_rtt + 4 * initial_rtt / 2 +
+        // max_ack_delay.
+        assert_eq!(r.loss_detection_timer(), Some(now + 325 * GRANULARITY));
+        assert_eq!(r.probe_epoch(), None);
+
+        let t = r.loss_detection_timer().unwrap();
+
+        r.on_loss_detection_timer(
+            [&mut in_flight, &mut hs_flight, &mut flight],
+            t,
+            "",
+        );
+
+        // PTO doesn't declare packets lost, and the timer is backed off.
+        assert_eq!(flight.lost_count, 0);
+        assert_eq!(r.probe_epoch(), Some(packet::EPOCH_APPLICATION));
+        assert_eq!(r.loss_probes[packet::EPOCH_APPLICATION], MAX_PTO_PROBES);
+        assert_eq!(r.cwnd(), std::usize::MAX);
+        assert_eq!(r.loss_detection_timer(), Some(now + 650 * GRANULARITY));
+    }
+
+    #[test]
+    fn pto_handshake() {
+        let mut r = Recovery::default();
+
+        let mut in_flight = InFlight::default();
+        let mut hs_flight = InFlight::default();
+        let mut flight = InFlight::default();
+
+        let now = Instant::now();
+
+        let frames = vec![frame::Frame::Crypto {
+            data: stream::RangeBuf::from(b"hello", 0, false),
+        }];
+
+        r.on_packet_sent(
+            Sent::new(0, frames, 1000, true, true, false, now),
+            packet::EPOCH_HANDSHAKE,
+            &mut hs_flight,
+            now,
+            "",
+        );
+
+        r.on_packet_sent(
+            sent(0, now),
+            packet::EPOCH_APPLICATION,
+            &mut flight,
+            now,
+            "",
+        );
+
+        // Application data isn't probed before the handshake completes, and
+        // max_ack_delay doesn't apply to handshake packets.
+        assert_eq!(r.loss_detection_timer(), Some(now + 300 * GRANULARITY));
+
+        let t = r.loss_detection_timer().unwrap();
+
+        r.on_loss_detection_timer(
+            [&mut in_flight, &mut hs_flight, &mut flight],
+            t,
+            "",
+        );
+
+        assert_eq!(r.probe_epoch(), Some(packet::EPOCH_HANDSHAKE));
+
+        // Unacknowledged CRYPTO data is scheduled for retransmission.
+        assert_eq!(hs_flight.sent.len(), 1);
+        assert_eq!(hs_flight.lost.len(), 1);
+
+        hs_flight.lost.clear();
+
+        // The same data isn't scheduled again on the next PTO.
+        let t = r.loss_detection_timer().unwrap();
+
+        r.on_loss_detection_timer(
+            [&mut in_flight, &mut hs_flight, &mut flight],
+            t,
+            "",
+        );
+
+        assert_eq!(hs_flight.sent.len(), 1);
+        assert_eq!(hs_flight.lost.len(), 0);
+
+        // Discarding the handshake keys also discards its probes.
+        r.drop_unacked_data(packet::EPOCH_HANDSHAKE, &mut hs_flight);
+
+        assert_eq!(r.probe_epoch(), None);
+        assert_eq!(r.loss_detection_timer(), None);
+    }
+
+    #[test]
+    fn loss_on_reordering() {
+        let mut r = Recovery::default();
+        r.handshake_completed = true;
+
+        let mut in_flight = InFlight::default();
+        let mut hs_flight = InFlight::default();
+        let mut flight = InFlight::default();
+
+        let now = Instant::now();
+
+        for pn in 0..4 {
+            r.on_packet_sent(
+                sent(pn, now),
+                packet::EPOCH_APPLICATION,
+                &mut flight,
+                now,
+                "",
+            );
+        }
+
+        // Packet 0 is lost because of the packet threshold.
+        ack(&mut r, &[3], &mut flight, now + 50 * GRANULARITY);
+
+        assert_eq!(r.latest_rtt, 50 * GRANULARITY);
+        assert_eq!(flight.lost_count, 1);
+
+        // Packets 1 and 2 are lost after 9/8 RTT.
+        assert_eq!(
+            r.loss_detection_timer(),
+            Some(now + Duration::from_micros(56_250))
+        );
+
+        let t = r.loss_detection_timer().unwrap();
+
+        r.on_loss_detection_timer(
+            [&mut in_flight, &mut hs_flight, &mut flight],
+            t,
+            "",
+        );
+
+        assert_eq!(flight.lost_count, 3);
+        assert_eq!(r.loss_detection_timer(), None);
+
+        // A single congestion event for packets sent before recovery started.
+        assert_eq!(r.cwnd(), (INITIAL_WINDOW + 1000) / 2);
+    }
+
+    #[test]
+    fn persistent_congestion() {
+        let mut r = Recovery::default();
+        r.handshake_completed = true;
+
+        let mut flight = InFlight::default();
+
+        let now = Instant::now();
+
+        r.on_packet_sent(
+            sent(0, now),
+            packet::EPOCH_APPLICATION,
+            &mut flight,
+            now,
+            "",
+        );
+
+        ack(&mut r, &[0], &mut flight, now + 50 * GRANULARITY);
+
+        let times = [100, 400, 700, 700, 700, 700];
+
+        for (pn, t) in (1..).zip(times.iter()) {
+            let t = now + *t * GRANULARITY;
+
+            r.on_packet_sent(
+                sent(pn, t),
+                packet::EPOCH_APPLICATION,
+                &mut flight,
+                t,
+                "",
+            );
+        }
+
+        // Packets 1 to 3 are lost, spanning more than the persistent
+        // congestion duration.
+        ack(&mut r, &[6], &mut flight, now + 750 * GRANULARITY);
+
+        assert_eq!(flight.lost_count, 3);
+        assert_eq!(r.cwnd, r.minimum_window());
+    }
+
+    #[test]
+    fn no_persistent_congestion_with_gap() {
+        let mut r = Recovery::default();
+        r.handshake_completed = true;
+
+        let mut flight = InFlight::default();
+
+        let now = Instant::now();
+
+        r.on_packet_sent(
+            sent(0, now),
+            packet::EPOCH_APPLICATION,
+            &mut flight,
+            now,
+            "",
+        );
+
+        ack(&mut r, &[0], &mut flight, now + 50 * GRANULARITY);
+
+        let times = [100, 400, 700, 700, 700, 700];
+
+        for (pn, t) in (1..).zip(times.iter()) {
+            let t = now + *t * GRANULARITY;
+
+            r.on_packet_sent(
+                sent(pn, t),
+                packet::EPOCH_APPLICATION,
+                &mut flight,
+                t,
+                "",
+            );
+        }
+
+        // Packet 2 was received, so losing packets 1 and 3 is not persistent
+        // congestion.
+        ack(&mut r, &[2, 6], &mut flight, now + 750 * GRANULARITY);
+
+        assert_eq!(flight.lost_count, 2);
+        assert_eq!(r.cwnd, (INITIAL_WINDOW + 3000) / 2);
+    }
+}
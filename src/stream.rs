@@ -31,20 +31,44 @@ use std::collections::hash_map;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 
+use std::sync::Arc;
+
+use std::time::Duration;
+use std::time::Instant;
+
 use crate::Error;
 use crate::Result;
 
-const MAX_WRITE_SIZE: usize = 1000;
+use crate::ranges;
 
 #[derive(Default)]
 pub struct StreamMap {
     streams: HashMap<u64, Stream>,
 
+    /// Number of streams opened so far for each stream type, indexed by the
+    /// two least significant bits of the stream ID. Streams below this that
+    /// are no longer in `streams` have been collected.
+    opened_streams: [u64; 4],
+
     peer_max_streams_bidi: usize,
     peer_max_streams_uni: usize,
 
+    peer_opened_streams_bidi: usize,
+    peer_opened_streams_uni: usize,
+
     local_max_streams_bidi: usize,
+    local_max_streams_bidi_next: usize,
+
     local_max_streams_uni: usize,
+    local_max_streams_uni_next: usize,
+
+    local_opened_streams_bidi: usize,
+    local_opened_streams_uni: usize,
+
+    streams_blocked_bidi: Option<usize>,
+    streams_blocked_uni: Option<usize>,
+
+    max_stream_window: usize,
 }
 
 impl StreamMap {
@@ -56,55 +80,106 @@ impl StreamMap {
         &mut self, id: u64, max_rx_data: usize, max_tx_data: usize, local: bool,
         is_server: bool,
     ) -> Result<&mut Stream> {
-        let stream = match self.streams.entry(id) {
-            hash_map::Entry::Vacant(v) => {
-                if local != is_local(id, is_server) {
-                    return Err(Error::InvalidStreamState);
+        if self.streams.contains_key(&id) {
+            return Ok(self.streams.get_mut(&id).unwrap());
+        }
+
+        let ty = (id & 0x3) as usize;
+        let seq = id >> 2;
+
+        // Stream has already been closed and garbage collected.
+        if seq < self.opened_streams[ty] {
+            return Err(Error::Done);
+        }
+
+        if local != is_local(id, is_server) {
+            return Err(Error::InvalidStreamState);
+        }
+
+        // Opening a stream implicitly opens all lower-numbered streams of
+        // the same type.
+        let count = (seq - self.opened_streams[ty] + 1) as usize;
+
+        // Enforce stream count limits.
+        match (is_local(id, is_server), is_bidi(id)) {
+            (true, true) => {
+                if self.local_opened_streams_bidi + count >
+                    self.peer_max_streams_bidi
+                {
+                    self.streams_blocked_bidi = Some(self.peer_max_streams_bidi);
+
+                    return Err(Error::StreamLimit);
+                }
+
+                self.local_opened_streams_bidi += count;
+            },
+
+            (true, false) => {
+                if self.local_opened_streams_uni + count >
+                    self.peer_max_streams_uni
+                {
+                    self.streams_blocked_uni = Some(self.peer_max_streams_uni);
+
+                    return Err(Error::StreamLimit);
                 }
 
-                // Enforce stream count limits.
-                match (is_local(id, is_server), is_bidi(id)) {
-                    (true, true) =>
-                        self.peer_max_streams_bidi = self
-                            .peer_max_streams_bidi
-                            .checked_sub(1)
-                            .ok_or(Error::StreamLimit)?,
-
-                    (true, false) =>
-                        self.peer_max_streams_uni = self
-                            .peer_max_streams_uni
-                            .checked_sub(1)
-                            .ok_or(Error::StreamLimit)?,
-
-                    (false, true) =>
-                        self.local_max_streams_bidi = self
-                            .local_max_streams_bidi
-                            .checked_sub(1)
-                            .ok_or(Error::StreamLimit)?,
-
-                    (false, false) =>
-                        self.local_max_streams_uni = self
-                            .local_max_streams_uni
-                            .checked_sub(1)
-                            .ok_or(Error::StreamLimit)?,
-                };
-
-                let s = Stream::new(max_rx_data, max_tx_data);
-                v.insert(s)
+                self.local_opened_streams_uni += count;
             },
 
-            hash_map::Entry::Occupied(v) => v.into_mut(),
+            (false, true) => {
+                if self.peer_opened_streams_bidi + count >
+                    self.local_max_streams_bidi
+                {
+                    return Err(Error::StreamLimit);
+                }
+
+                self.peer_opened_streams_bidi += count;
+            },
+
+            (false, false) => {
+                if self.peer_opened_streams_uni + count >
+                    self.local_max_streams_uni
+                {
+                    return Err(Error::StreamLimit);
+                }
+
+                self.peer_opened_streams_uni += count;
+            },
         };
 
-        Ok(stream)
+        for seq in self.opened_streams[ty]..=seq {
+            let mut s = Stream::new(
+                max_rx_data,
+                max_tx_data,
+                is_bidi(id),
+                is_local(id, is_server),
+            );
+
+            s.set_max_rx_window(self.max_stream_window);
+
+            self.streams.insert(seq << 2 | ty as u64, s);
+        }
+
+        self.opened_streams[ty] = seq + 1;
+
+        Ok(self.streams.get_mut(&id).unwrap())
+    }
+
+    /// Sets the size the receive window of new streams can grow to.
+    pub fn set_max_stream_window(&mut self, v: usize) {
+        self.max_stream_window = v;
     }
 
     pub fn update_local_max_streams_bidi(&mut self, v: usize) {
         self.local_max_streams_bidi = cmp::max(self.local_max_streams_bidi, v);
+        self.local_max_streams_bidi_next =
+            cmp::max(self.local_max_streams_bidi_next, v);
     }
 
     pub fn update_local_max_streams_uni(&mut self, v: usize) {
         self.local_max_streams_uni = cmp::max(self.local_max_streams_uni, v);
+        self.local_max_streams_uni_next =
+            cmp::max(self.local_max_streams_uni_next, v);
     }
 
     pub fn update_peer_max_streams_bidi(&mut self, v: usize) {
@@ -115,6 +190,94 @@ impl StreamMap {
         self.peer_max_streams_uni = cmp::max(self.peer_max_streams_uni, v);
     }
 
+    pub fn max_streams_bidi_next(&self) -> usize {
+        self.local_max_streams_bidi_next
+    }
+
+    pub fn max_streams_uni_next(&self) -> usize {
+        self.local_max_streams_uni_next
+    }
+
+    pub fn update_max_streams_bidi(&mut self) {
+        self.local_max_streams_bidi = self.local_max_streams_bidi_next;
+    }
+
+    pub fn update_max_streams_uni(&mut self) {
+        self.local_max_streams_uni = self.local_max_streams_uni_next;
+    }
+
+    pub fn should_update_max_streams_bidi(&self) -> bool {
+        // Send MAX_STREAMS when the new limit is at least double the number
+        // of streams the peer can open before blocking.
+        self.local_max_streams_bidi_next != self.local_max_streams_bidi &&
+            self.local_max_streams_bidi_next / 2 >
+                self.local_max_streams_bidi - self.peer_opened_streams_bidi
+    }
+
+    pub fn should_update_max_streams_uni(&self) -> bool {
+        // Send MAX_STREAMS when the new limit is at least double the number
+        // of streams the peer can open before blocking.
+        self.local_max_streams_uni_next != self.local_max_streams_uni &&
+            self.local_max_streams_uni_next / 2 >
+                self.local_max_streams_uni - self.peer_opened_streams_uni
+    }
+
+    /// Removes the stream with the given ID from the map, and gives the peer
+    /// credit to open a new one if the stream was opened by the peer.
+    ///
+    /// The stream can't be re-created after being collected, so frames
+    /// referencing it will be ignored.
+    pub fn collect(&mut self, id: u64, is_server: bool) {
+        if self.streams.remove(&id).is_none() {
+            return;
+        }
+
+        if is_local(id, is_server) {
+            return;
+        }
+
+        if is_bidi(id) {
+            self.local_max_streams_bidi_next += 1;
+        } else {
+            self.local_max_streams_uni_next += 1;
+        }
+    }
+
+    /// Returns the peer's bidirectional stream limit that prevented a new
+    /// local stream from being opened, if it wasn't already reported.
+    pub fn streams_blocked_bidi(&self) -> Option<usize> {
+        self.streams_blocked_bidi
+    }
+
+    /// Returns the peer's unidirectional stream limit that prevented a new
+    /// local stream from being opened, if it wasn't already reported.
+    pub fn streams_blocked_uni(&self) -> Option<usize> {
+        self.streams_blocked_uni
+    }
+
+    pub fn reset_streams_blocked_bidi(&mut self) {
+        self.streams_blocked_bidi = None;
+    }
+
+    pub fn reset_streams_blocked_uni(&mut self) {
+        self.streams_blocked_uni = None;
+    }
+
+    pub fn is_collected(&self, id: u64) -> bool {
+        !self.streams.contains_key(&id) &&
+            (id >> 2) < self.opened_streams[(id & 0x3) as usize]
+    }
+
+    /// Returns the number of streams opened by the local endpoint.
+    pub fn local_opened_streams(&self) -> usize {
+        self.local_opened_streams_bidi + self.local_opened_streams_uni
+    }
+
+    /// Returns the number of streams opened by the peer.
+    pub fn peer_opened_streams(&self) -> usize {
+        self.peer_opened_streams_bidi + self.peer_opened_streams_uni
+    }
+
     pub fn readable(&mut self) -> Readable {
         Readable::new(&self.streams)
     }
@@ -130,6 +293,12 @@ impl StreamMap {
     pub fn has_out_of_credit(&self) -> bool {
         self.streams.values().any(|s| s.more_credit())
     }
+
+    pub fn has_blocked(&self) -> bool {
+        self.streams_blocked_bidi.is_some() ||
+            self.streams_blocked_uni.is_some() ||
+            self.streams.values().any(|s| s.blocked())
+    }
 }
 
 #[derive(Default)]
@@ -143,11 +312,22 @@ pub struct Stream {
     max_rx_data: usize,
     new_max_rx_data: usize,
 
+    rx_window: usize,
+    max_rx_window: usize,
+    last_rx_update: Option<Instant>,
+
     rx_fin_off: Option<usize>,
+
+    tx_blocked_off: Option<usize>,
+
+    bidi: bool,
+    local: bool,
 }
 
 impl Stream {
-    pub fn new(max_rx_data: usize, max_tx_data: usize) -> Stream {
+    pub fn new(
+        max_rx_data: usize, max_tx_data: usize, bidi: bool, local: bool,
+    ) -> Stream {
         Stream {
             recv: RecvBuf::default(),
             send: SendBuf::default(),
@@ -158,7 +338,16 @@ impl Stream {
             max_rx_data,
             new_max_rx_data: max_rx_data,
 
+            rx_window: max_rx_data,
+            max_rx_window: max_rx_data,
+            last_rx_update: None,
+
             rx_fin_off: None,
+
+            tx_blocked_off: None,
+
+            bidi,
+            local,
         }
     }
 
@@ -216,6 +405,10 @@ impl Stream {
 
         self.rx_fin_off = Some(final_size);
 
+        // The peer won't send the missing data, so drop what was buffered
+        // and consider the receive side complete.
+        self.recv.reset(final_size);
+
         // Return how many bytes need to be removed from the connection flow
         // control.
         Ok(final_size - self.rx_data)
@@ -225,6 +418,10 @@ impl Stream {
         self.send.push_slice(data, fin)
     }
 
+    pub fn send_push_owned(&mut self, data: Vec<u8>, fin: bool) -> Result<()> {
+        self.send.push_vec(data, fin)
+    }
+
     pub fn send_pop(&mut self, max_len: usize) -> Result<RangeBuf> {
         self.send.pop(max_len, self.max_tx_data)
     }
@@ -233,8 +430,41 @@ impl Stream {
         self.send.push(buf)
     }
 
-    pub fn update_max_rx_data(&mut self) -> usize {
-        self.max_rx_data = self.new_max_rx_data;
+    pub fn send_ack(&mut self, buf: &RangeBuf) {
+        self.send.ack(buf)
+    }
+
+    /// Records that a flow control update advertising `max_rx_data` was
+    /// sent, growing the receive window by the autotuned amount, if any.
+    pub fn update_max_rx_data(&mut self, max_rx_data: usize, now: Instant) {
+        self.rx_window += max_rx_data - self.new_max_rx_data;
+        self.new_max_rx_data = max_rx_data;
+        self.max_rx_data = max_rx_data;
+        self.last_rx_update = Some(now);
+    }
+
+    pub fn set_max_rx_window(&mut self, v: usize) {
+        self.max_rx_window = cmp::max(self.rx_window, v);
+    }
+
+    /// Returns the flow control limit to advertise next, including the grown
+    /// receive window if the previous update was sent less than two
+    /// round-trips ago, as that means the application consumes data faster
+    /// than the window allows the peer to send it.
+    ///
+    /// The window is only grown once the update is recorded with
+    /// `update_max_rx_data()`.
+    pub fn autotune_max_rx_data(&self, now: Instant, rtt: Duration) -> usize {
+        if let Some(last_rx_update) = self.last_rx_update {
+            if now - last_rx_update < rtt * 2 {
+                let window = cmp::min(
+                    self.rx_window.saturating_mul(2),
+                    self.max_rx_window,
+                );
+
+                return self.new_max_rx_data + window - self.rx_window;
+            }
+        }
 
         self.new_max_rx_data
     }
@@ -258,6 +488,49 @@ impl Stream {
             self.new_max_rx_data != self.max_rx_data &&
             self.new_max_rx_data / 2 > self.max_rx_data - self.rx_data
     }
+
+    /// Returns true if the stream has data to send but is blocked by the
+    /// peer's flow control limit, and this hasn't been reported yet.
+    pub fn blocked(&self) -> bool {
+        self.send.ready() &&
+            self.send.off() >= self.max_tx_data &&
+            self.tx_blocked_off != Some(self.max_tx_data)
+    }
+
+    /// Records that the stream was reported as blocked at the current limit.
+    pub fn update_blocked(&mut self) {
+        self.tx_blocked_off = Some(self.max_tx_data);
+    }
+
+    pub fn max_tx_data(&self) -> usize {
+        self.max_tx_data
+    }
+
+    /// Returns true if the stream has been fully used, and no more data will
+    /// be sent or received on it.
+    ///
+    /// This happens once the application has read all the data up to the
+    /// final size on the receive side, and the peer has acknowledged all the
+    /// data (including the fin) on the send side.
+    pub fn is_complete(&self) -> bool {
+        match (self.bidi, self.local) {
+            (true, _) => self.recv_complete() && self.send.is_complete(),
+
+            // Only the send side of a local unidirectional stream is used.
+            (false, true) => self.send.is_complete(),
+
+            // Only the receive side of a remote unidirectional stream is used.
+            (false, false) => self.recv_complete(),
+        }
+    }
+
+    fn recv_complete(&self) -> bool {
+        match self.rx_fin_off {
+            Some(fin_off) => self.recv.off() == fin_off && !self.recv.ready(),
+
+            None => false,
+        }
+    }
 }
 
 pub fn is_local(id: u64, is_server: bool) -> bool {
@@ -337,18 +610,12 @@ impl RecvBuf {
             };
 
             if buf.len() > cap {
-                let new_buf = RangeBuf {
-                    data: buf.data.split_off(cap),
-                    off: buf.off + cap,
-                    fin: buf.fin,
-                };
-
-                buf.fin = false;
+                let new_buf = buf.split_off(cap);
 
                 self.data.push(new_buf);
             }
 
-            out[len..len + buf.len()].copy_from_slice(&buf.data);
+            out[len..len + buf.len()].copy_from_slice(&buf);
 
             self.off += buf.len();
             self.len -= buf.len();
@@ -362,6 +629,12 @@ impl RecvBuf {
         Ok((len, fin))
     }
 
+    fn reset(&mut self, final_size: usize) {
+        self.data.clear();
+        self.off = final_size;
+        self.len = 0;
+    }
+
     fn ready(&self) -> bool {
         let buf = match self.data.peek() {
             Some(v) => v,
@@ -387,30 +660,29 @@ struct SendBuf {
     data: BinaryHeap<RangeBuf>,
     off: usize,
     len: usize,
+    fin_off: Option<usize>,
+    fin_acked: bool,
+    acked: ranges::RangeSet,
 }
 
 impl SendBuf {
     fn push_slice(&mut self, data: &[u8], fin: bool) -> Result<()> {
-        let mut len = 0;
-
-        if data.is_empty() {
-            let buf = RangeBuf::from(&[], self.off, fin);
-            return self.push(buf);
-        }
+        self.push_vec(data.to_vec(), fin)
+    }
 
-        // Split the input buffer into multiple RangeBufs. Otherwise a big
-        // buffer would need to be split later on when popping data, which
-        // would cause a partial copy of the buffer.
-        for chunk in data.chunks(MAX_WRITE_SIZE) {
-            len += chunk.len();
+    fn push_vec(&mut self, data: Vec<u8>, fin: bool) -> Result<()> {
+        let len = data.len();
 
-            let fin = len == data.len() && fin;
+        if fin {
+            self.fin_off = Some(self.off + len);
+        }
 
-            let buf = RangeBuf::from(chunk, self.off, fin);
-            self.push(buf)?;
+        // The whole buffer is kept as a single RangeBuf, as splitting it later
+        // on when popping data doesn't require copying it.
+        let buf = RangeBuf::from_vec(data, self.off, fin);
+        self.push(buf)?;
 
-            self.off += chunk.len();
-        }
+        self.off += len;
 
         Ok(())
     }
@@ -424,8 +696,7 @@ impl SendBuf {
     }
 
     fn pop(&mut self, max_len: usize, max_off: usize) -> Result<RangeBuf> {
-        let mut out = RangeBuf::default();
-        out.data = Vec::with_capacity(cmp::min(max_len, self.len()));
+        let mut bufs: Vec<RangeBuf> = Vec::new();
 
         let mut out_len = max_len;
         let mut out_off = self.data.peek().map_or_else(|| 0, |d| d.off());
@@ -443,32 +714,62 @@ impl SendBuf {
             if buf.len() > out_len || buf.max_off() >= max_off {
                 let new_len = cmp::min(out_len, max_off - buf.off());
 
-                let new_buf = RangeBuf {
-                    data: buf.data.split_off(new_len),
-                    off: buf.off + new_len,
-                    fin: buf.fin,
-                };
-
-                buf.fin = false;
+                let new_buf = buf.split_off(new_len);
 
                 self.data.push(new_buf);
             }
 
-            if out.is_empty() {
-                out.off = buf.off;
-            }
-
             self.len -= buf.len();
 
             out_len -= buf.len();
             out_off = buf.off() + buf.len();
 
-            out.fin = out.fin || buf.fin();
+            bufs.push(buf);
+        }
 
-            out.data.extend_from_slice(&buf.data);
+        // Data coming from a single buffer can be returned as-is, without
+        // copying it.
+        if bufs.len() <= 1 {
+            return Ok(bufs.pop().unwrap_or_default());
         }
 
-        Ok(out)
+        let mut data = Vec::with_capacity(max_len - out_len);
+
+        for buf in &bufs {
+            data.extend_from_slice(buf);
+        }
+
+        let fin = bufs.iter().any(RangeBuf::fin);
+
+        Ok(RangeBuf::from_vec(data, bufs[0].off(), fin))
+    }
+
+    fn ack(&mut self, buf: &RangeBuf) {
+        if !buf.is_empty() {
+            let start = buf.off() as u64;
+            let end = buf.max_off() as u64;
+
+            self.acked.insert(start..end);
+        }
+
+        if buf.fin() {
+            self.fin_acked = true;
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        let fin_off = match self.fin_off {
+            Some(v) => v as u64,
+
+            None => return false,
+        };
+
+        if !self.fin_acked {
+            return false;
+        }
+
+        // All data up to the final size must have been acknowledged.
+        fin_off == 0 || self.acked.iter().next() == Some(0..fin_off)
     }
 
     fn ready(&self) -> bool {
@@ -490,22 +791,58 @@ impl SendBuf {
 }
 
 /// Buffer holding data at a specific offset.
-#[derive(Debug, Default, Eq)]
+#[derive(Clone, Debug, Default, Eq)]
 pub struct RangeBuf {
-    data: Vec<u8>,
+    /// The underlying buffer, which can be shared between multiple RangeBufs
+    /// after splitting, each pointing to a different section of it.
+    data: Arc<Vec<u8>>,
+
+    /// The position of the first byte of `self` in `data`.
+    start: usize,
+
+    /// The number of bytes of `data` owned by `self`.
+    len: usize,
+
     off: usize,
     fin: bool,
 }
 
 impl RangeBuf {
     pub(crate) fn from(buf: &[u8], off: usize, fin: bool) -> RangeBuf {
+        RangeBuf::from_vec(Vec::from(buf), off, fin)
+    }
+
+    pub(crate) fn from_vec(data: Vec<u8>, off: usize, fin: bool) -> RangeBuf {
         RangeBuf {
-            data: Vec::from(buf),
+            len: data.len(),
+            data: Arc::new(data),
+            start: 0,
             off,
             fin,
         }
     }
 
+    /// Splits `self` in two at the given index, without copying data.
+    ///
+    /// `self` keeps the data before `at`, while the returned RangeBuf holds
+    /// the rest of it, as well as the `fin` flag.
+    fn split_off(&mut self, at: usize) -> RangeBuf {
+        assert!(at <= self.len);
+
+        let buf = RangeBuf {
+            data: self.data.clone(),
+            start: self.start + at,
+            len: self.len - at,
+            off: self.off + at,
+            fin: self.fin,
+        };
+
+        self.len = at;
+        self.fin = false;
+
+        buf
+    }
+
     /// Returns whether `self` holds the final offset in the stream.
     pub fn fin(&self) -> bool {
         self.fin
@@ -523,7 +860,7 @@ impl RangeBuf {
 
     /// Returns the length of `self`.
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.len
     }
 
     /// Returns true if `self` has a length of zero bytes.
@@ -536,13 +873,7 @@ impl std::ops::Deref for RangeBuf {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
-        &self.data
-    }
-}
-
-impl std::ops::DerefMut for RangeBuf {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        &mut self.data
+        &self.data[self.start..self.start + self.len]
     }
 }
 
@@ -741,6 +1072,32 @@ mod tests {
         assert_eq!(send.len(), 0);
     }
 
+    #[test]
+    fn owned_write() {
+        let mut send = SendBuf::default();
+
+        let data = b"somethinghelloworld".to_vec();
+        let ptr = data.as_ptr();
+
+        assert!(send.push_vec(data, true).is_ok());
+        assert_eq!(send.len(), 19);
+
+        let write = send.pop(9, std::usize::MAX).unwrap();
+        assert_eq!(write.off(), 0);
+        assert_eq!(write.len(), 9);
+        assert_eq!(write.fin(), false);
+        assert_eq!(&write[..], b"something");
+        assert_eq!(write.as_ptr(), ptr);
+
+        let write = send.pop(128, std::usize::MAX).unwrap();
+        assert_eq!(write.off(), 9);
+        assert_eq!(write.len(), 10);
+        assert_eq!(write.fin(), true);
+        assert_eq!(&write[..], b"helloworld");
+        assert_eq!(write.as_ptr(), ptr.wrapping_add(9));
+        assert_eq!(send.len(), 0);
+    }
+
     #[test]
     fn split_write() {
         let mut send = SendBuf::default();
@@ -907,7 +1264,7 @@ mod tests {
 
     #[test]
     fn recv_flow_control() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let mut buf = [0; 32];
@@ -928,7 +1285,8 @@ mod tests {
 
         assert!(stream.more_credit());
 
-        assert_eq!(stream.update_max_rx_data(), 25);
+        assert_eq!(stream.new_max_rx_data, 25);
+        stream.update_max_rx_data(25, Instant::now());
         assert!(!stream.more_credit());
 
         let third = RangeBuf::from(b"something", 10, false);
@@ -937,7 +1295,7 @@ mod tests {
 
     #[test]
     fn recv_past_fin() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -949,7 +1307,7 @@ mod tests {
 
     #[test]
     fn recv_fin_dup() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -967,7 +1325,7 @@ mod tests {
 
     #[test]
     fn recv_fin_change() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -979,7 +1337,7 @@ mod tests {
 
     #[test]
     fn recv_fin_lower_than_received() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -991,7 +1349,7 @@ mod tests {
 
     #[test]
     fn recv_fin_flow_control() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let mut buf = [0; 32];
@@ -1011,7 +1369,7 @@ mod tests {
 
     #[test]
     fn recv_fin_reset_mismatch() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, true);
@@ -1022,7 +1380,7 @@ mod tests {
 
     #[test]
     fn recv_reset_dup() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, false);
@@ -1032,9 +1390,30 @@ mod tests {
         assert_eq!(stream.recv_reset(5), Ok(0));
     }
 
+    #[test]
+    fn recv_reset_complete() {
+        let mut stream = Stream::new(15, 0, false, false);
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"world", 10, false);
+
+        assert_eq!(stream.recv_push(first), Ok(()));
+        assert_eq!(stream.recv_push(second), Ok(()));
+        assert!(!stream.is_complete());
+
+        assert_eq!(stream.recv_reset(15), Ok(0));
+        assert!(!stream.readable());
+        assert!(stream.is_complete());
+
+        // Retransmitted data is ignored.
+        let third = RangeBuf::from(b"fghij", 5, false);
+        assert_eq!(stream.recv_push(third), Ok(()));
+        assert!(!stream.readable());
+    }
+
     #[test]
     fn recv_reset_change() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, false);
@@ -1046,7 +1425,7 @@ mod tests {
 
     #[test]
     fn recv_reset_lower_than_received() {
-        let mut stream = Stream::new(15, 0);
+        let mut stream = Stream::new(15, 0, true, true);
         assert!(!stream.more_credit());
 
         let first = RangeBuf::from(b"hello", 0, false);
@@ -1057,7 +1436,7 @@ mod tests {
 
     #[test]
     fn send_flow_control() {
-        let mut stream = Stream::new(0, 15);
+        let mut stream = Stream::new(0, 15, true, true);
 
         let first = b"hello";
         let second = b"world";
@@ -1071,13 +1450,13 @@ mod tests {
         assert_eq!(write.off(), 0);
         assert_eq!(write.len(), 15);
         assert_eq!(write.fin(), false);
-        assert_eq!(write.data, b"helloworldsomet");
+        assert_eq!(&write[..], b"helloworldsomet");
 
         let write = stream.send_pop(25).unwrap();
         assert_eq!(write.off(), 0);
         assert_eq!(write.len(), 0);
         assert_eq!(write.fin(), false);
-        assert_eq!(write.data, b"");
+        assert_eq!(&write[..], b"");
 
         let first = RangeBuf::from(b"helloworldsomet", 0, false);
         assert_eq!(stream.send_push_front(first), Ok(()));
@@ -1086,12 +1465,187 @@ mod tests {
         assert_eq!(write.off(), 0);
         assert_eq!(write.len(), 10);
         assert_eq!(write.fin(), false);
-        assert_eq!(write.data, b"helloworld");
+        assert_eq!(&write[..], b"helloworld");
 
         let write = stream.send_pop(10).unwrap();
         assert_eq!(write.off(), 10);
         assert_eq!(write.len(), 5);
         assert_eq!(write.fin(), false);
-        assert_eq!(write.data, b"somet");
+        assert_eq!(&write[..], b"somet");
+    }
+
+    #[test]
+    fn send_complete() {
+        let mut stream = Stream::new(0, 15, false, true);
+        assert!(!stream.is_complete());
+
+        assert_eq!(stream.send_push(b"hello", false), Ok(()));
+        assert_eq!(stream.send_push(b"world", true), Ok(()));
+
+        let first = stream.send_pop(5).unwrap();
+        assert_eq!(first.fin(), false);
+
+        let second = stream.send_pop(5).unwrap();
+        assert_eq!(second.fin(), true);
+
+        stream.send_ack(&second);
+        assert!(!stream.is_complete());
+
+        stream.send_ack(&first);
+        assert!(stream.is_complete());
+    }
+
+    #[test]
+    fn recv_complete() {
+        let mut stream = Stream::new(15, 0, false, false);
+        assert!(!stream.is_complete());
+
+        let mut buf = [0; 32];
+
+        let first = RangeBuf::from(b"hello", 0, false);
+        let second = RangeBuf::from(b"", 5, true);
+
+        assert_eq!(stream.recv_push(first), Ok(()));
+        assert_eq!(stream.recv_push(second), Ok(()));
+        assert!(!stream.is_complete());
+
+        assert_eq!(stream.recv_pop(&mut buf[..3]), Ok((3, false)));
+        assert!(!stream.is_complete());
+
+        assert_eq!(stream.recv_pop(&mut buf), Ok((2, true)));
+        assert!(stream.is_complete());
+    }
+
+    #[test]
+    fn collect_max_streams() {
+        let mut streams = StreamMap::default();
+        streams.update_local_max_streams_bidi(2);
+
+        assert!(streams.get_or_create(0, 15, 15, false, true).is_ok());
+        assert!(streams.get_or_create(4, 15, 15, false, true).is_ok());
+        assert_eq!(
+            streams.get_or_create(8, 15, 15, false, true).err(),
+            Some(Error::StreamLimit)
+        );
+
+        assert!(!streams.should_update_max_streams_bidi());
+
+        streams.collect(0, true);
+        assert!(streams.is_collected(0));
+        assert!(streams.get_mut(0).is_none());
+
+        assert_eq!(
+            streams.get_or_create(0, 15, 15, false, true).err(),
+            Some(Error::Done)
+        );
+
+        assert!(streams.should_update_max_streams_bidi());
+        assert_eq!(streams.max_streams_bidi_next(), 3);
+
+        streams.update_max_streams_bidi();
+        assert!(!streams.should_update_max_streams_bidi());
+
+        assert!(streams.get_or_create(8, 15, 15, false, true).is_ok());
+        assert_eq!(
+            streams.get_or_create(12, 15, 15, false, true).err(),
+            Some(Error::StreamLimit)
+        );
+    }
+
+    #[test]
+    fn implicit_open() {
+        let mut streams = StreamMap::default();
+        streams.update_local_max_streams_bidi(3);
+
+        // Opening stream 8 also opens streams 0 and 4.
+        assert!(streams.get_or_create(8, 15, 15, false, true).is_ok());
+        assert_eq!(streams.peer_opened_streams(), 3);
+        assert!(streams.get_mut(0).is_some());
+        assert!(streams.get_mut(4).is_some());
+
+        // Frames for a lower stream arriving late still find the stream.
+        assert!(streams.get_or_create(4, 15, 15, false, true).is_ok());
+        assert_eq!(streams.peer_opened_streams(), 3);
+
+        streams.collect(4, true);
+        assert!(streams.is_collected(4));
+        assert!(!streams.is_collected(0));
+        assert!(!streams.is_collected(12));
+
+        // Streams of a different type are tracked separately.
+        assert!(!streams.is_collected(1));
+    }
+
+    #[test]
+    fn send_blocked() {
+        let mut stream = Stream::new(0, 5, true, true);
+        assert!(!stream.blocked());
+
+        assert_eq!(stream.send_push(b"helloworld", false), Ok(()));
+        assert!(!stream.blocked());
+
+        let write = stream.send_pop(10).unwrap();
+        assert_eq!(write.len(), 5);
+        assert!(stream.blocked());
+
+        stream.update_blocked();
+        assert!(!stream.blocked());
+
+        stream.update_max_tx_data(8);
+        assert!(!stream.blocked());
+
+        let write = stream.send_pop(10).unwrap();
+        assert_eq!(write.len(), 3);
+        assert!(stream.blocked());
+    }
+
+    #[test]
+    fn recv_window_autotune() {
+        let mut stream = Stream::new(15, 0, true, true);
+        stream.set_max_rx_window(40);
+
+        let mut buf = [0; 32];
+
+        let now = Instant::now();
+        let rtt = Duration::from_millis(100);
+
+        let first = RangeBuf::from(b"helloworld", 0, false);
+        assert_eq!(stream.recv_push(first), Ok(()));
+        assert_eq!(stream.recv_pop(&mut buf), Ok((10, false)));
+
+        // No previous update, so the window is not grown.
+        let max = stream.autotune_max_rx_data(now, rtt);
+        assert_eq!(max, 25);
+        stream.update_max_rx_data(max, now);
+
+        let second = RangeBuf::from(b"helloworld", 10, false);
+        assert_eq!(stream.recv_push(second), Ok(()));
+        assert_eq!(stream.recv_pop(&mut buf), Ok((10, false)));
+
+        // Update is within two RTTs of the previous one, grow the window.
+        let now = now + Duration::from_millis(50);
+        let max = stream.autotune_max_rx_data(now, rtt);
+        assert_eq!(max, 50);
+        stream.update_max_rx_data(max, now);
+
+        let third = RangeBuf::from(b"helloworld", 20, false);
+        assert_eq!(stream.recv_push(third), Ok(()));
+        assert_eq!(stream.recv_pop(&mut buf), Ok((10, false)));
+
+        // Window is capped to the maximum.
+        let now = now + Duration::from_millis(50);
+        let max = stream.autotune_max_rx_data(now, rtt);
+        assert_eq!(max, 70);
+        stream.update_max_rx_data(max, now);
+
+        let fourth = RangeBuf::from(b"helloworld", 30, false);
+        assert_eq!(stream.recv_push(fourth), Ok(()));
+        assert_eq!(stream.recv_pop(&mut buf), Ok((10, false)));
+
+        // Update is too late, keep the current window.
+        let now = now + Duration::from_millis(300);
+        let max = stream.autotune_max_rx_data(now, rtt);
+        assert_eq!(max, 80);
+        stream.update_max_rx_data(max, now);
     }
 }
@@ -0,0 +1,1350 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use std::ffi;
+use std::ptr;
+use std::slice;
+
+use libc::c_char;
+use libc::c_int;
+use libc::c_long;
+use libc::c_uint;
+use libc::c_void;
+use libc::size_t;
+
+use lazy_static;
+
+use crate::ClientAuth;
+use crate::Connection;
+
+use crate::crypto;
+use crate::octets;
+use crate::rand;
+
+use super::Error;
+use super::Result;
+use super::TlsContext;
+use super::TlsHandshake;
+
+const TLS1_3_VERSION: u16 = 0x0304;
+
+const TLSEXT_NAMETYPE_HOST_NAME: c_int = 0;
+
+const SSL_OP_NO_TICKET: u32 = 0x0000_4000;
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct SSL_METHOD(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct SSL_CTX(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct SSL(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct SSL_CIPHER(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct X509_VERIFY_PARAM(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct X509_STORE(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct X509(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_PKEY(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct BIO(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct CRYPTO_BUFFER(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_CIPHER(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_CIPHER_CTX(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct EVP_MD(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct HMAC_CTX(c_void);
+
+#[allow(non_camel_case_types)]
+#[repr(transparent)]
+struct STACK_OF(c_void);
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct SSL_QUIC_METHOD {
+    set_encryption_secrets: extern fn(
+        ssl: *mut SSL,
+        level: crypto::Level,
+        read_secret: *const u8,
+        write_secret: *const u8,
+        secret_len: usize,
+    ) -> c_int,
+
+    add_handshake_data: extern fn(
+        ssl: *mut SSL,
+        level: crypto::Level,
+        data: *const u8,
+        len: usize,
+    ) -> c_int,
+
+    flush_flight: extern fn(ssl: *mut SSL) -> c_int,
+
+    send_alert:
+        extern fn(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int,
+}
+
+lazy_static::lazy_static! {
+    static ref QUICHE_EX_DATA_INDEX: c_int = unsafe {
+        SSL_get_ex_new_index(0, ptr::null(), ptr::null(), ptr::null(), ptr::null())
+    };
+}
+
+static QUICHE_STREAM_METHOD: SSL_QUIC_METHOD = SSL_QUIC_METHOD {
+    set_encryption_secrets,
+    add_handshake_data,
+    flush_flight,
+    send_alert,
+};
+
+pub struct Context {
+    ctx: *mut SSL_CTX,
+
+    // Setting the verify mode with `SSL_CTX_set_verify()` also removes the
+    // custom verify callback, so keep track of both to re-apply them.
+    verify_mode: c_int,
+    custom_verify: bool,
+
+    // BoringSSL doesn't allow configuring TLS 1.3 cipher suites, so the
+    // negotiated one is checked against these by the handshake instead.
+    cipher_suites: Vec<crypto::Algorithm>,
+}
+
+impl TlsContext for Context {
+    type Handshake = Handshake;
+
+    fn new() -> Result<Context> {
+        unsafe {
+            let ctx = SSL_CTX_new(TLS_method());
+
+            map_result(SSL_CTX_set_default_verify_paths(ctx))?;
+
+            SSL_CTX_set_tlsext_servername_callback(ctx, select_server_name);
+
+            // Lines are only logged when the connection has a keylog writer.
+            SSL_CTX_set_keylog_callback(ctx, keylog);
+
+            Ok(Context {
+                ctx,
+                verify_mode: 0x00, // SSL_VERIFY_NONE
+                custom_verify: false,
+                cipher_suites: Vec::new(),
+            })
+        }
+    }
+
+    fn new_handshake(&mut self) -> Result<Handshake> {
+        unsafe {
+            let ssl = SSL_new(self.as_ptr());
+            Ok(Handshake {
+                ssl,
+                cipher_suites: self.cipher_suites.clone(),
+            })
+        }
+    }
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
+        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_use_certificate_chain_file(self.as_ptr(), cstr.as_ptr())
+        })
+    }
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()> {
+        let cstr = ffi::CString::new(file).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_use_PrivateKey_file(self.as_ptr(), cstr.as_ptr(), 1)
+        })
+    }
+
+    fn use_certificate_chain_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let certs = read_pem_certs(buf)?;
+
+        if certs.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        let mut res = Ok(());
+
+        // The context takes its own reference to each certificate.
+        for (i, cert) in certs.into_iter().enumerate() {
+            if res.is_ok() {
+                res = map_result(unsafe {
+                    if i == 0 {
+                        SSL_CTX_use_certificate(self.as_ptr(), cert)
+                    } else {
+                        SSL_CTX_add1_chain_cert(self.as_ptr(), cert)
+                    }
+                });
+            }
+
+            unsafe { X509_free(cert) };
+        }
+
+        res
+    }
+
+    fn use_certificate_chain_der(&mut self, chain: &[&[u8]]) -> Result<()> {
+        let (leaf, intermediates) = chain.split_first().ok_or(Error::TlsFail)?;
+
+        map_result(unsafe {
+            SSL_CTX_use_certificate_ASN1(self.as_ptr(), leaf.len(), leaf.as_ptr())
+        })?;
+
+        for der in intermediates {
+            let mut p = der.as_ptr();
+
+            let cert =
+                unsafe { d2i_X509(ptr::null_mut(), &mut p, der.len() as c_long) };
+
+            if cert.is_null() {
+                return Err(Error::TlsFail);
+            }
+
+            let rc = unsafe { SSL_CTX_add1_chain_cert(self.as_ptr(), cert) };
+
+            unsafe { X509_free(cert) };
+
+            map_result(rc)?;
+        }
+
+        Ok(())
+    }
+
+    fn use_privkey_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let pkey = unsafe {
+            let bio = BIO_new_mem_buf(
+                buf.as_ptr() as *const c_void,
+                buf.len() as isize,
+            );
+
+            if bio.is_null() {
+                return Err(Error::TlsFail);
+            }
+
+            let pkey = PEM_read_bio_PrivateKey(
+                bio,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null_mut(),
+            );
+
+            BIO_free(bio);
+
+            pkey
+        };
+
+        self.use_privkey(pkey)
+    }
+
+    fn use_privkey_der(&mut self, buf: &[u8]) -> Result<()> {
+        let mut der = buf.as_ptr();
+
+        let pkey = unsafe {
+            d2i_AutoPrivateKey(ptr::null_mut(), &mut der, buf.len() as c_long)
+        };
+
+        self.use_privkey(pkey)
+    }
+
+    fn load_verify_locations_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let certs = read_pem_certs(buf)?;
+
+        if certs.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        let store = unsafe { SSL_CTX_get_cert_store(self.as_ptr()) };
+
+        let mut res = Ok(());
+
+        for cert in certs {
+            // The store takes its own reference to the certificate.
+            if res.is_ok() {
+                res = map_result(unsafe { X509_STORE_add_cert(store, cert) });
+            }
+
+            unsafe { X509_free(cert) };
+        }
+
+        res
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        let mode = if verify {
+            0x01 // SSL_VERIFY_PEER
+        } else {
+            0x00 // SSL_VERIFY_NONE
+        };
+
+        self.set_verify_mode(mode);
+    }
+
+    fn set_groups(&mut self, groups: &str) -> Result<()> {
+        let cstr = ffi::CString::new(groups).map_err(|_| Error::TlsFail)?;
+        map_result(unsafe {
+            SSL_CTX_set1_curves_list(self.as_ptr(), cstr.as_ptr())
+        })
+    }
+
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()> {
+        self.cipher_suites = suites.to_vec();
+        Ok(())
+    }
+
+    fn set_ticket_key_callback(&mut self) -> Result<()> {
+        map_result(unsafe {
+            SSL_CTX_set_tlsext_ticket_key_cb(self.as_ptr(), ticket_key)
+        })
+    }
+
+    fn set_session_tickets(&mut self, enable: bool) {
+        unsafe {
+            if enable {
+                SSL_CTX_clear_options(self.as_ptr(), SSL_OP_NO_TICKET);
+            } else {
+                SSL_CTX_set_options(self.as_ptr(), SSL_OP_NO_TICKET);
+            }
+        }
+    }
+
+    fn set_client_auth(&mut self, mode: ClientAuth) {
+        let mode = match mode {
+            ClientAuth::None => 0x00, // SSL_VERIFY_NONE
+
+            ClientAuth::Request => 0x01, // SSL_VERIFY_PEER
+
+            // SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT
+            ClientAuth::Require => 0x03,
+        };
+
+        self.set_verify_mode(mode);
+    }
+
+    fn set_custom_verify(&mut self) {
+        self.custom_verify = true;
+
+        // Setting a callback enables verification, but keep
+        // SSL_VERIFY_FAIL_IF_NO_PEER_CERT, if set.
+        self.set_verify_mode(self.verify_mode | 0x01);
+    }
+
+    fn set_alpn(&mut self, v: &[Vec<u8>]) -> Result<()> {
+        let mut protos: Vec<u8> = Vec::new();
+
+        for proto in v {
+            protos.push(proto.len() as u8);
+            protos.append(&mut proto.clone());
+        }
+
+        // Configure ALPN for servers.
+        unsafe {
+            SSL_CTX_set_alpn_select_cb(
+                self.as_ptr(),
+                select_alpn,
+                ptr::null_mut(),
+            );
+        }
+
+        // Configure ALPN for clients.
+        map_result_zero_is_success(unsafe {
+            SSL_CTX_set_alpn_protos(self.as_ptr(), protos.as_ptr(), protos.len())
+        })
+    }
+}
+
+impl Context {
+    fn use_privkey(&mut self, pkey: *mut EVP_PKEY) -> Result<()> {
+        if pkey.is_null() {
+            return Err(Error::TlsFail);
+        }
+
+        let rc = unsafe { SSL_CTX_use_PrivateKey(self.as_ptr(), pkey) };
+
+        unsafe { EVP_PKEY_free(pkey) };
+
+        map_result(rc)
+    }
+
+    fn as_ptr(&self) -> *mut SSL_CTX {
+        self.ctx
+    }
+
+    fn set_verify_mode(&mut self, mode: c_int) {
+        self.verify_mode = mode;
+
+        unsafe {
+            if self.custom_verify {
+                SSL_CTX_set_custom_verify(self.as_ptr(), mode, custom_verify);
+            } else {
+                SSL_CTX_set_verify(self.as_ptr(), mode, ptr::null());
+            }
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { SSL_CTX_free(self.as_ptr()) }
+    }
+}
+
+pub struct Handshake {
+    ssl: *mut SSL,
+
+    // The cipher suites allowed by the context, or empty if all are.
+    cipher_suites: Vec<crypto::Algorithm>,
+}
+
+impl TlsHandshake for Handshake {
+    fn init(&self, conn: &Connection) -> Result<()> {
+        self.set_state(conn.is_server);
+
+        self.set_ex_data(*QUICHE_EX_DATA_INDEX, conn)?;
+
+        self.set_min_proto_version(TLS1_3_VERSION);
+        self.set_max_proto_version(TLS1_3_VERSION);
+
+        self.set_quic_method()?;
+
+        self.set_quiet_shutdown(true);
+
+        Ok(())
+    }
+
+    fn set_host_name(&mut self, name: &str) -> Result<()> {
+        let cstr = ffi::CString::new(name).map_err(|_| Error::TlsFail)?;
+        map_result_ssl(self, unsafe {
+            SSL_set_tlsext_host_name(self.as_ptr(), cstr.as_ptr())
+        })?;
+
+        let param = unsafe { SSL_get0_param(self.as_ptr()) };
+
+        map_result(unsafe {
+            X509_VERIFY_PARAM_set1_host(param, cstr.as_ptr(), name.len())
+        })
+    }
+
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
+        map_result_ssl(self, unsafe {
+            SSL_set_quic_transport_params(self.as_ptr(), buf.as_ptr(), buf.len())
+        })
+    }
+
+    fn get_quic_transport_params(&self) -> &[u8] {
+        let mut ptr: *const u8 = ptr::null();
+        let mut len: usize = 0;
+
+        unsafe {
+            SSL_get_peer_quic_transport_params(self.as_ptr(), &mut ptr, &mut len);
+        }
+
+        if len == 0 {
+            return &mut [];
+        }
+
+        unsafe { slice::from_raw_parts(ptr, len) }
+    }
+
+    fn get_server_name(&self) -> Option<&str> {
+        let name = unsafe {
+            let ptr =
+                SSL_get_servername(self.as_ptr(), TLSEXT_NAMETYPE_HOST_NAME);
+
+            if ptr.is_null() {
+                return None;
+            }
+
+            ffi::CStr::from_ptr(ptr)
+        };
+
+        name.to_str().ok()
+    }
+
+    fn get_peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+        let chain = unsafe { SSL_get0_peer_certificates(self.as_ptr()) };
+
+        if chain.is_null() {
+            return None;
+        }
+
+        let num = unsafe { sk_num(chain) };
+
+        let certs = (0..num)
+            .map(|i| unsafe {
+                let buf = sk_value(chain, i) as *const CRYPTO_BUFFER;
+
+                slice::from_raw_parts(
+                    CRYPTO_BUFFER_data(buf),
+                    CRYPTO_BUFFER_len(buf),
+                )
+            })
+            .collect();
+
+        Some(certs)
+    }
+
+    fn is_peer_verified(&self) -> bool {
+        let has_cert =
+            unsafe { !SSL_get0_peer_certificates(self.as_ptr()).is_null() };
+
+        has_cert && unsafe { SSL_get_verify_result(self.as_ptr()) } == 0
+    }
+
+    fn get_alpn_protocol(&self) -> &[u8] {
+        let mut ptr: *const u8 = ptr::null();
+        let mut len: u32 = 0;
+
+        unsafe {
+            SSL_get0_alpn_selected(self.as_ptr(), &mut ptr, &mut len);
+        }
+
+        if len == 0 {
+            return &mut [];
+        }
+
+        unsafe { slice::from_raw_parts(ptr, len as usize) }
+    }
+
+    fn provide_data(&mut self, level: crypto::Level, buf: &[u8]) -> Result<()> {
+        map_result_ssl(self, unsafe {
+            SSL_provide_quic_data(self.as_ptr(), level, buf.as_ptr(), buf.len())
+        })
+    }
+
+    fn do_handshake(&mut self) -> Result<()> {
+        let rc = unsafe { SSL_do_handshake(self.as_ptr()) };
+
+        self.check_cipher_suite()?;
+
+        map_result_ssl(self, rc)
+    }
+
+    fn process_post_handshake(&mut self) -> Result<()> {
+        map_result_ssl(self, unsafe {
+            SSL_process_quic_post_handshake(self.as_ptr())
+        })
+    }
+
+    fn get_write_level(&self) -> crypto::Level {
+        unsafe { SSL_quic_write_level(self.as_ptr()) }
+    }
+
+    fn cipher(&self) -> Result<crypto::Algorithm> {
+        get_cipher_from_ptr(self.as_ptr())
+    }
+
+    fn group(&self) -> Option<&'static str> {
+        let name = unsafe {
+            let id = SSL_get_curve_id(self.as_ptr());
+
+            if id == 0 {
+                return None;
+            }
+
+            let ptr = SSL_get_curve_name(id);
+
+            if ptr.is_null() {
+                return None;
+            }
+
+            ffi::CStr::from_ptr(ptr)
+        };
+
+        name.to_str().ok()
+    }
+
+    fn is_resumed(&self) -> bool {
+        unsafe { SSL_session_reused(self.as_ptr()) == 1 }
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        map_result_ssl(self, unsafe { SSL_clear(self.as_ptr()) })
+    }
+}
+
+impl Handshake {
+    pub fn from_void(ssl: *mut c_void) -> Handshake {
+        let ssl = ssl as *mut SSL;
+        Handshake {
+            ssl,
+            cipher_suites: Vec::new(),
+        }
+    }
+
+    pub fn get_error(&self, ret_code: c_int) -> c_int {
+        unsafe { SSL_get_error(self.as_ptr(), ret_code) }
+    }
+
+    pub fn set_state(&self, is_server: bool) {
+        unsafe {
+            if is_server {
+                SSL_set_accept_state(self.as_ptr());
+            } else {
+                SSL_set_connect_state(self.as_ptr());
+            }
+        }
+    }
+
+    pub fn set_ex_data<T>(&self, idx: c_int, data: &T) -> Result<()> {
+        map_result(unsafe {
+            let ptr = data as *const T as *const c_void;
+            SSL_set_ex_data(self.as_ptr(), idx, ptr)
+        })
+    }
+
+    pub fn set_quic_method(&self) -> Result<()> {
+        map_result(unsafe {
+            SSL_set_quic_method(self.as_ptr(), &QUICHE_STREAM_METHOD)
+        })
+    }
+
+    pub fn set_min_proto_version(&self, version: u16) {
+        unsafe { SSL_set_min_proto_version(self.as_ptr(), version) }
+    }
+
+    pub fn set_max_proto_version(&self, version: u16) {
+        unsafe { SSL_set_max_proto_version(self.as_ptr(), version) }
+    }
+
+    pub fn set_quiet_shutdown(&self, mode: bool) {
+        unsafe { SSL_set_quiet_shutdown(self.as_ptr(), if mode { 1 } else { 0 }) }
+    }
+
+    /// Aborts the handshake with a `handshake_failure` alert if the
+    /// negotiated cipher suite isn't one of the allowed ones.
+    fn check_cipher_suite(&self) -> Result<()> {
+        if self.cipher_suites.is_empty() {
+            return Ok(());
+        }
+
+        // The cipher isn't known until the ServerHello is processed.
+        let cipher = match get_cipher_from_ptr(self.as_ptr()) {
+            Ok(v) => v,
+            Err(_) => return Ok(()),
+        };
+
+        if self.cipher_suites.contains(&cipher) {
+            return Ok(());
+        }
+
+        let conn = get_ex_data_from_ptr::<Connection>(
+            self.as_ptr(),
+            *QUICHE_EX_DATA_INDEX,
+        )
+        .ok_or(Error::TlsFail)?;
+
+        // handshake_failure
+        super::send_alert(conn, self.get_write_level(), 0x28);
+
+        Err(Error::TlsFail)
+    }
+
+    fn as_ptr(&self) -> *mut SSL {
+        self.ssl
+    }
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        unsafe { SSL_free(self.as_ptr()) }
+    }
+}
+
+fn get_ex_data_from_ptr<'a, T>(ptr: *mut SSL, idx: c_int) -> Option<&'a mut T> {
+    unsafe {
+        let data = SSL_get_ex_data(ptr, idx) as *mut T;
+        data.as_mut()
+    }
+}
+
+fn get_cipher_from_ptr(ptr: *mut SSL) -> Result<crypto::Algorithm> {
+    let cipher = map_result_ptr(unsafe { SSL_get_current_cipher(ptr) })?;
+
+    let cipher_id = unsafe { SSL_CIPHER_get_id(cipher) };
+
+    let alg = match cipher_id {
+        0x0300_1301 => crypto::Algorithm::AES128_GCM,
+        0x0300_1302 => crypto::Algorithm::AES256_GCM,
+        0x0300_1303 => crypto::Algorithm::ChaCha20_Poly1305,
+        _ => return Err(Error::TlsFail),
+    };
+
+    Ok(alg)
+}
+
+extern fn set_encryption_secrets(
+    ssl: *mut SSL, level: crypto::Level, read_secret: *const u8,
+    write_secret: *const u8, secret_len: usize,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 0,
+        };
+
+    let aead = match get_cipher_from_ptr(ssl) {
+        Ok(v) => v,
+        Err(_) => return 0,
+    };
+
+    let read_secret = unsafe { slice::from_raw_parts(read_secret, secret_len) };
+    let write_secret = unsafe { slice::from_raw_parts(write_secret, secret_len) };
+
+    match super::set_secrets(conn, level, aead, read_secret, write_secret) {
+        Ok(_) => 1,
+        Err(_) => 0,
+    }
+}
+
+extern fn add_handshake_data(
+    ssl: *mut SSL, level: crypto::Level, data: *const u8, len: usize,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 0,
+        };
+
+    let buf = unsafe { slice::from_raw_parts(data, len) };
+
+    match super::add_handshake_data(conn, level, buf) {
+        Ok(_) => 1,
+        Err(_) => 0,
+    }
+}
+
+extern fn flush_flight(_ssl: *mut SSL) -> c_int {
+    // We don't really need to anything here since the output packets are
+    // generated separately, when conn.send() is called.
+
+    1
+}
+
+extern fn send_alert(ssl: *mut SSL, level: crypto::Level, alert: u8) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 0,
+        };
+
+    super::send_alert(conn, level, alert);
+
+    1
+}
+
+extern fn keylog(ssl: *mut SSL, line: *const c_char) {
+    let data = unsafe { ffi::CStr::from_ptr(line).to_bytes() };
+
+    let conn = get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX);
+
+    super::log_keys(conn, data);
+}
+
+extern fn select_alpn(
+    ssl: *mut SSL, out: *mut *const u8, out_len: *mut u8, inp: *mut u8,
+    in_len: c_uint, _arg: *mut c_void,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 3, // SSL_TLSEXT_ERR_NOACK
+        };
+
+    trace!("{} alpn callback", conn.trace_id());
+
+    if conn.application_protos.is_empty() {
+        return 3; // SSL_TLSEXT_ERR_NOACK
+    }
+
+    let mut protos = octets::Octets::with_slice(unsafe {
+        slice::from_raw_parts_mut(inp, in_len as usize)
+    });
+
+    while let Ok(proto) = protos.get_bytes_with_u8_length() {
+        let found = conn.application_protos.iter().any(|expected| {
+            if expected.len() == proto.len() &&
+                expected.as_slice() == proto.as_ref()
+            {
+                unsafe {
+                    *out = expected.as_slice().as_ptr();
+                    *out_len = expected.len() as u8;
+                }
+
+                return true;
+            }
+
+            false
+        });
+
+        if found {
+            return 0; // SSL_TLSEXT_ERR_OK
+        }
+    }
+
+    3 // SSL_TLSEXT_ERR_NOACK
+}
+
+extern fn select_server_name(
+    ssl: *mut SSL, _alert: *mut c_int, _arg: *mut c_void,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 0, // SSL_TLSEXT_ERR_OK
+        };
+
+    let cb = match conn.server_name_cb {
+        Some(ref v) => v,
+        None => return 0, // SSL_TLSEXT_ERR_OK
+    };
+
+    let name = match conn.tls_state.get_server_name() {
+        Some(v) => v,
+        None => return 0, // SSL_TLSEXT_ERR_OK
+    };
+
+    trace!("{} server name callback name={}", conn.trace_id(), name);
+
+    if let Some(config) = cb(name) {
+        // The connection takes its own reference to the new context, so the
+        // config doesn't need to outlive it.
+        let ctx = unsafe { SSL_set_SSL_CTX(ssl, config.tls_ctx.as_ptr()) };
+
+        if ctx.is_null() {
+            return 2; // SSL_TLSEXT_ERR_ALERT_FATAL
+        }
+    }
+
+    0 // SSL_TLSEXT_ERR_OK
+}
+
+extern fn custom_verify(ssl: *mut SSL, out_alert: *mut u8) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return 1, // ssl_verify_invalid
+        };
+
+    let cb = match conn.verify_cb {
+        Some(ref v) => v,
+        None => return 1, // ssl_verify_invalid
+    };
+
+    let chain = conn.tls_state.get_peer_cert_chain().unwrap_or_default();
+
+    trace!("{} verify callback certs={}", conn.trace_id(), chain.len());
+
+    if cb(&chain, conn.tls_state.get_server_name()) {
+        return 0; // ssl_verify_ok
+    }
+
+    unsafe {
+        *out_alert = 42; // SSL_AD_BAD_CERTIFICATE
+    }
+
+    1 // ssl_verify_invalid
+}
+
+extern fn ticket_key(
+    ssl: *mut SSL, key_name: *mut u8, iv: *mut u8,
+    cipher_ctx: *mut EVP_CIPHER_CTX, hmac_ctx: *mut HMAC_CTX, encrypt: c_int,
+) -> c_int {
+    let conn =
+        match get_ex_data_from_ptr::<Connection>(ssl, *QUICHE_EX_DATA_INDEX) {
+            Some(v) => v,
+            None => return -1,
+        };
+
+    let name = unsafe { slice::from_raw_parts_mut(key_name, 16) };
+    let iv = unsafe { slice::from_raw_parts_mut(iv, 16) };
+
+    let (idx, key) = if encrypt == 1 {
+        // New tickets are always encrypted with the current key.
+        let key = match conn.ticket_keys.first() {
+            Some(v) => v,
+            None => return -1,
+        };
+
+        name.copy_from_slice(&key[..16]);
+
+        rand::rand_bytes(iv);
+
+        let rc = unsafe {
+            EVP_EncryptInit_ex(
+                cipher_ctx,
+                EVP_aes_128_cbc(),
+                ptr::null_mut(),
+                key[32..].as_ptr(),
+                iv.as_ptr(),
+            )
+        };
+
+        if rc != 1 {
+            return -1;
+        }
+
+        (0, key)
+    } else {
+        let found = conn
+            .ticket_keys
+            .iter()
+            .enumerate()
+            .find(|(_, key)| key[..16] == *name);
+
+        // Tickets encrypted with an unknown key are ignored, which causes a
+        // full handshake.
+        let (idx, key) = match found {
+            Some(v) => v,
+            None => return 0,
+        };
+
+        let rc = unsafe {
+            EVP_DecryptInit_ex(
+                cipher_ctx,
+                EVP_aes_128_cbc(),
+                ptr::null_mut(),
+                key[32..].as_ptr(),
+                iv.as_ptr(),
+            )
+        };
+
+        if rc != 1 {
+            return -1;
+        }
+
+        (idx, key)
+    };
+
+    let rc = unsafe {
+        HMAC_Init_ex(
+            hmac_ctx,
+            key[16..32].as_ptr() as *const c_void,
+            16,
+            EVP_sha256(),
+            ptr::null_mut(),
+        )
+    };
+
+    if rc != 1 {
+        return -1;
+    }
+
+    trace!(
+        "{} ticket key callback encrypt={} key={}",
+        conn.trace_id,
+        encrypt,
+        idx
+    );
+
+    // Ask for tickets encrypted with a previous key to be renewed.
+    if idx > 0 {
+        return 2;
+    }
+
+    1
+}
+
+fn map_result(bssl_result: c_int) -> Result<()> {
+    match bssl_result {
+        1 => Ok(()),
+        _ => Err(Error::TlsFail),
+    }
+}
+
+fn map_result_zero_is_success(bssl_result: c_int) -> Result<()> {
+    match bssl_result {
+        0 => Ok(()),
+        _ => Err(Error::TlsFail),
+    }
+}
+
+fn map_result_ptr<'a, T>(bssl_result: *const T) -> Result<&'a T> {
+    match unsafe { bssl_result.as_ref() } {
+        Some(v) => Ok(v),
+        None => Err(Error::TlsFail),
+    }
+}
+
+fn map_result_ssl(ssl: &Handshake, bssl_result: c_int) -> Result<()> {
+    match bssl_result {
+        1 => Ok(()),
+
+        _ => {
+            let ssl_err = ssl.get_error(bssl_result);
+            match ssl_err {
+                // SSL_ERROR_SSL
+                1 => {
+                    log_ssl_error();
+
+                    Err(Error::TlsFail)
+                },
+
+                // SSL_ERROR_WANT_READ
+                2 => Err(Error::WantRead),
+
+                // SSL_ERROR_WANT_WRITE
+                3 => Err(Error::WantWrite),
+
+                // SSL_ERROR_WANT_X509_LOOKUP
+                4 => Err(Error::PendingOperation),
+
+                // SSL_ERROR_SYSCALL
+                5 => Err(Error::SyscallFail),
+
+                // SSL_ERROR_PENDING_CERTIFICATE
+                12 => Err(Error::PendingOperation),
+
+                // SSL_ERROR_WANT_PRIVATE_KEY_OPERATION
+                13 => Err(Error::PendingOperation),
+
+                // SSL_ERROR_PENDING_TICKET
+                14 => Err(Error::PendingOperation),
+
+                _ => Err(Error::TlsFail),
+            }
+        },
+    }
+}
+
+/// Parses all the PEM-encoded certificates in `buf`.
+///
+/// The caller owns the returned certificates and must free them.
+fn read_pem_certs(buf: &[u8]) -> Result<Vec<*mut X509>> {
+    let mut certs = Vec::new();
+
+    unsafe {
+        let bio =
+            BIO_new_mem_buf(buf.as_ptr() as *const c_void, buf.len() as isize);
+
+        if bio.is_null() {
+            return Err(Error::TlsFail);
+        }
+
+        loop {
+            let cert = PEM_read_bio_X509(
+                bio,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null_mut(),
+            );
+
+            if cert.is_null() {
+                break;
+            }
+
+            certs.push(cert);
+        }
+
+        BIO_free(bio);
+
+        // Reading past the last certificate leaves an error in the queue.
+        ERR_clear_error();
+    }
+
+    Ok(certs)
+}
+
+fn log_ssl_error() {
+    let err = [0; 1024];
+
+    unsafe {
+        let e = ERR_peek_error();
+        ERR_error_string_n(e, err.as_ptr(), err.len());
+    }
+
+    trace!("{}", std::str::from_utf8(&err).unwrap());
+}
+
+extern {
+    // SSL_METHOD
+    fn TLS_method() -> *const SSL_METHOD;
+
+    // SSL_CTX
+    fn SSL_CTX_new(method: *const SSL_METHOD) -> *mut SSL_CTX;
+    fn SSL_CTX_free(ctx: *mut SSL_CTX);
+
+    fn SSL_CTX_use_certificate_chain_file(
+        ctx: *mut SSL_CTX, file: *const c_char,
+    ) -> c_int;
+
+    fn SSL_CTX_use_PrivateKey_file(
+        ctx: *mut SSL_CTX, file: *const c_char, ty: c_int,
+    ) -> c_int;
+
+    fn SSL_CTX_use_certificate(ctx: *mut SSL_CTX, x509: *mut X509) -> c_int;
+
+    fn SSL_CTX_use_certificate_ASN1(
+        ctx: *mut SSL_CTX, der_len: usize, der: *const u8,
+    ) -> c_int;
+
+    fn SSL_CTX_add1_chain_cert(ctx: *mut SSL_CTX, x509: *mut X509) -> c_int;
+
+    fn SSL_CTX_use_PrivateKey(ctx: *mut SSL_CTX, pkey: *mut EVP_PKEY) -> c_int;
+
+    fn SSL_CTX_get_cert_store(ctx: *const SSL_CTX) -> *mut X509_STORE;
+
+    fn SSL_CTX_set_default_verify_paths(ctx: *mut SSL_CTX) -> c_int;
+
+    fn SSL_CTX_set_verify(ctx: *mut SSL_CTX, mode: c_int, cb: *const c_void);
+
+    fn SSL_CTX_set1_curves_list(
+        ctx: *mut SSL_CTX, curves: *const c_char,
+    ) -> c_int;
+
+    fn SSL_CTX_set_options(ctx: *mut SSL_CTX, options: u32) -> u32;
+
+    fn SSL_CTX_clear_options(ctx: *mut SSL_CTX, options: u32) -> u32;
+
+    fn SSL_CTX_set_tlsext_ticket_key_cb(
+        ctx: *mut SSL_CTX,
+        cb: extern fn(
+            ssl: *mut SSL,
+            key_name: *mut u8,
+            iv: *mut u8,
+            cipher_ctx: *mut EVP_CIPHER_CTX,
+            hmac_ctx: *mut HMAC_CTX,
+            encrypt: c_int,
+        ) -> c_int,
+    ) -> c_int;
+
+    fn SSL_CTX_set_custom_verify(
+        ctx: *mut SSL_CTX, mode: c_int,
+        cb: extern fn(ssl: *mut SSL, out_alert: *mut u8) -> c_int,
+    );
+
+    fn SSL_CTX_set_keylog_callback(
+        ctx: *mut SSL_CTX, cb: extern fn(ssl: *mut SSL, line: *const c_char),
+    );
+
+    fn SSL_CTX_set_tlsext_servername_callback(
+        ctx: *mut SSL_CTX,
+        cb: extern fn(
+            ssl: *mut SSL,
+            alert: *mut c_int,
+            arg: *mut c_void,
+        ) -> c_int,
+    ) -> c_int;
+
+    fn SSL_CTX_set_alpn_protos(
+        ctx: *mut SSL_CTX, protos: *const u8, protos_len: usize,
+    ) -> c_int;
+
+    fn SSL_CTX_set_alpn_select_cb(
+        ctx: *mut SSL_CTX,
+        cb: extern fn(
+            ssl: *mut SSL,
+            out: *mut *const u8,
+            out_len: *mut u8,
+            inp: *mut u8,
+            in_len: c_uint,
+            arg: *mut c_void,
+        ) -> c_int,
+        arg: *mut c_void,
+    );
+
+    // SSL
+    fn SSL_get_ex_new_index(
+        argl: c_long, argp: *const c_void, unused: *const c_void,
+        dup_unused: *const c_void, free_func: *const c_void,
+    ) -> c_int;
+
+    fn SSL_new(ctx: *mut SSL_CTX) -> *mut SSL;
+
+    fn SSL_get_error(ssl: *mut SSL, ret_code: c_int) -> c_int;
+
+    fn SSL_set_accept_state(ssl: *mut SSL);
+    fn SSL_set_connect_state(ssl: *mut SSL);
+
+    fn SSL_get0_param(ssl: *mut SSL) -> *mut X509_VERIFY_PARAM;
+
+    fn SSL_set_ex_data(ssl: *mut SSL, idx: c_int, ptr: *const c_void) -> c_int;
+    fn SSL_get_ex_data(ssl: *mut SSL, idx: c_int) -> *mut c_void;
+
+    fn SSL_get_current_cipher(ssl: *mut SSL) -> *const SSL_CIPHER;
+
+    fn SSL_get_curve_id(ssl: *mut SSL) -> u16;
+
+    fn SSL_get_curve_name(curve_id: u16) -> *const c_char;
+
+    fn SSL_get0_peer_certificates(ssl: *mut SSL) -> *const STACK_OF;
+
+    fn SSL_get_verify_result(ssl: *mut SSL) -> c_long;
+
+    fn SSL_set_min_proto_version(ssl: *mut SSL, version: u16);
+    fn SSL_set_max_proto_version(ssl: *mut SSL, version: u16);
+
+    fn SSL_set_quiet_shutdown(ssl: *mut SSL, mode: c_int);
+
+    fn SSL_set_tlsext_host_name(ssl: *mut SSL, name: *const c_char) -> c_int;
+
+    fn SSL_get_servername(ssl: *mut SSL, ty: c_int) -> *const c_char;
+
+    fn SSL_set_SSL_CTX(ssl: *mut SSL, ctx: *mut SSL_CTX) -> *mut SSL_CTX;
+
+    fn SSL_set_quic_transport_params(
+        ssl: *mut SSL, params: *const u8, params_len: usize,
+    ) -> c_int;
+
+    fn SSL_set_quic_method(
+        ssl: *mut SSL, quic_method: *const SSL_QUIC_METHOD,
+    ) -> c_int;
+
+    fn SSL_get_peer_quic_transport_params(
+        ssl: *mut SSL, out_params: *mut *const u8, out_params_len: *mut usize,
+    );
+
+    fn SSL_get0_alpn_selected(
+        ssl: *mut SSL, out: *mut *const u8, out_len: *mut u32,
+    );
+
+    fn SSL_provide_quic_data(
+        ssl: *mut SSL, level: crypto::Level, data: *const u8, len: usize,
+    ) -> c_int;
+
+    fn SSL_do_handshake(ssl: *mut SSL) -> c_int;
+
+    fn SSL_process_quic_post_handshake(ssl: *mut SSL) -> c_int;
+
+    fn SSL_quic_write_level(ssl: *mut SSL) -> crypto::Level;
+
+    fn SSL_session_reused(ssl: *mut SSL) -> c_int;
+
+    fn SSL_clear(ssl: *mut SSL) -> c_int;
+
+    fn SSL_free(ssl: *mut SSL);
+
+    // SSL_CIPHER
+    fn SSL_CIPHER_get_id(cipher: *const SSL_CIPHER) -> c_uint;
+
+    // X509_VERIFY_PARAM
+    fn X509_VERIFY_PARAM_set1_host(
+        param: *mut X509_VERIFY_PARAM, name: *const c_char, namelen: size_t,
+    ) -> c_int;
+
+    // EVP_CIPHER
+    fn EVP_aes_128_cbc() -> *const EVP_CIPHER;
+
+    fn EVP_EncryptInit_ex(
+        ctx: *mut EVP_CIPHER_CTX, cipher: *const EVP_CIPHER, engine: *mut c_void,
+        key: *const u8, iv: *const u8,
+    ) -> c_int;
+
+    fn EVP_DecryptInit_ex(
+        ctx: *mut EVP_CIPHER_CTX, cipher: *const EVP_CIPHER, engine: *mut c_void,
+        key: *const u8, iv: *const u8,
+    ) -> c_int;
+
+    // EVP_MD
+    fn EVP_sha256() -> *const EVP_MD;
+
+    // HMAC
+    fn HMAC_Init_ex(
+        ctx: *mut HMAC_CTX, key: *const c_void, key_len: usize,
+        md: *const EVP_MD, engine: *mut c_void,
+    ) -> c_int;
+
+    // CRYPTO_BUFFER
+    fn CRYPTO_BUFFER_data(buf: *const CRYPTO_BUFFER) -> *const u8;
+
+    fn CRYPTO_BUFFER_len(buf: *const CRYPTO_BUFFER) -> usize;
+
+    // STACK_OF
+    fn sk_num(stack: *const STACK_OF) -> usize;
+
+    fn sk_value(stack: *const STACK_OF, idx: usize) -> *mut c_void;
+
+    // X509
+    fn X509_free(x509: *mut X509);
+
+    fn d2i_X509(
+        out: *mut *mut X509, inp: *mut *const u8, len: c_long,
+    ) -> *mut X509;
+
+    // X509_STORE
+    fn X509_STORE_add_cert(store: *mut X509_STORE, x509: *mut X509) -> c_int;
+
+    // EVP_PKEY
+    fn EVP_PKEY_free(pkey: *mut EVP_PKEY);
+
+    fn d2i_AutoPrivateKey(
+        out: *mut *mut EVP_PKEY, inp: *mut *const u8, len: c_long,
+    ) -> *mut EVP_PKEY;
+
+    // BIO
+    fn BIO_new_mem_buf(buf: *const c_void, len: isize) -> *mut BIO;
+
+    fn BIO_free(bio: *mut BIO) -> c_int;
+
+    // PEM
+    fn PEM_read_bio_X509(
+        bio: *mut BIO, out: *mut *mut X509, cb: *const c_void, u: *mut c_void,
+    ) -> *mut X509;
+
+    fn PEM_read_bio_PrivateKey(
+        bio: *mut BIO, out: *mut *mut EVP_PKEY, cb: *const c_void, u: *mut c_void,
+    ) -> *mut EVP_PKEY;
+
+    // ERR
+    fn ERR_peek_error() -> c_uint;
+
+    fn ERR_clear_error();
+
+    fn ERR_error_string_n(err: c_uint, buf: *const u8, len: usize);
+}
@@ -0,0 +1,292 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! TLS handshake backends.
+//!
+//! The QUIC handshake is driven through the [`TlsContext`] and
+//! [`TlsHandshake`] traits. By default they are implemented on top of
+//! BoringSSL, while the `no_bssl` feature selects a pure-Rust implementation
+//! based on rustls instead.
+//!
+//! Both backends report the handshake's progress to the connection through
+//! the functions in this module, which install the packet protection keys
+//! and queue outgoing CRYPTO data.
+
+use std::io::prelude::*;
+
+use crate::ClientAuth;
+use crate::Connection;
+
+use crate::crypto;
+
+#[cfg(not(feature = "no_bssl"))]
+mod boringssl;
+
+#[cfg(not(feature = "no_bssl"))]
+pub use self::boringssl::Context;
+
+#[cfg(not(feature = "no_bssl"))]
+pub use self::boringssl::Handshake;
+
+#[cfg(feature = "no_bssl")]
+mod rustls;
+
+#[cfg(feature = "no_bssl")]
+pub use self::rustls::Context;
+
+#[cfg(feature = "no_bssl")]
+pub use self::rustls::Handshake;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "no_bssl", allow(dead_code))]
+pub enum Error {
+    TlsFail,
+    WantRead,
+    WantWrite,
+    SyscallFail,
+    PendingOperation,
+}
+
+const TLS_ALERT_ERROR: u16 = 0x100;
+
+/// The length of a session ticket key: a 16 bytes key name, followed by a 16
+/// bytes HMAC-SHA256 key and a 16 bytes AES-128 key.
+pub const TICKET_KEY_LEN: usize = 48;
+
+/// The TLS configuration shared between multiple connections.
+pub trait TlsContext: Sized {
+    type Handshake: TlsHandshake;
+
+    fn new() -> Result<Self>;
+
+    fn new_handshake(&mut self) -> Result<Self::Handshake>;
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()>;
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()>;
+
+    fn use_certificate_chain_pem(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn use_certificate_chain_der(&mut self, chain: &[&[u8]]) -> Result<()>;
+
+    fn use_privkey_pem(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn use_privkey_der(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn load_verify_locations_pem(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn set_verify(&mut self, verify: bool);
+
+    fn set_groups(&mut self, groups: &str) -> Result<()>;
+
+    /// Restricts the TLS 1.3 cipher suites that connections may negotiate.
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()>;
+
+    /// Makes servers use the connection's `ticket_keys` for session tickets.
+    fn set_ticket_key_callback(&mut self) -> Result<()>;
+
+    fn set_session_tickets(&mut self, enable: bool);
+
+    fn set_client_auth(&mut self, mode: ClientAuth);
+
+    /// Makes peers be verified by the connection's `verify_cb`.
+    fn set_custom_verify(&mut self);
+
+    fn set_alpn(&mut self, v: &[Vec<u8>]) -> Result<()>;
+}
+
+/// The TLS state of a single connection.
+pub trait TlsHandshake {
+    /// Binds the handshake to the given connection.
+    ///
+    /// The connection must not move for as long as the handshake is in use.
+    fn init(&self, conn: &Connection) -> Result<()>;
+
+    fn set_host_name(&mut self, name: &str) -> Result<()>;
+
+    /// Sets the encoded local transport parameters.
+    ///
+    /// This can be called again before the handshake starts, e.g. when the
+    /// version of the connection changes.
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn get_quic_transport_params(&self) -> &[u8];
+
+    fn get_server_name(&self) -> Option<&str>;
+
+    fn get_peer_cert_chain(&self) -> Option<Vec<&[u8]>>;
+
+    fn is_peer_verified(&self) -> bool;
+
+    fn get_alpn_protocol(&self) -> &[u8];
+
+    fn provide_data(&mut self, level: crypto::Level, buf: &[u8]) -> Result<()>;
+
+    /// Continues the handshake, returning `Ok` once it's complete.
+    fn do_handshake(&mut self) -> Result<()>;
+
+    /// Processes messages received after the handshake, such as session
+    /// tickets.
+    fn process_post_handshake(&mut self) -> Result<()>;
+
+    fn get_write_level(&self) -> crypto::Level;
+
+    fn cipher(&self) -> Result<crypto::Algorithm>;
+
+    fn group(&self) -> Option<&'static str>;
+
+    fn is_resumed(&self) -> bool;
+
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// Installs the packet protection keys derived from the given secrets for
+/// the given encryption level.
+fn set_secrets(
+    conn: &mut Connection, level: crypto::Level, aead: crypto::Algorithm,
+    read_secret: &[u8], write_secret: &[u8],
+) -> Result<()> {
+    trace!(
+        "{} tls set encryption secret lvl={:?}",
+        conn.trace_id,
+        level
+    );
+
+    let space = match level {
+        crypto::Level::Initial => &mut conn.initial,
+        // TODO: implement 0-RTT
+        crypto::Level::ZeroRTT => unimplemented!("0-RTT"),
+        crypto::Level::Handshake => &mut conn.handshake,
+        crypto::Level::Application => &mut conn.application,
+    };
+
+    let key_len = aead.key_len();
+    let nonce_len = aead.nonce_len();
+
+    let mut key = vec![0; key_len];
+    let mut iv = vec![0; nonce_len];
+    let mut pn_key = vec![0; key_len];
+
+    crypto::derive_pkt_key(aead, read_secret, &mut key)
+        .map_err(|_| Error::TlsFail)?;
+    crypto::derive_pkt_iv(aead, read_secret, &mut iv)
+        .map_err(|_| Error::TlsFail)?;
+    crypto::derive_hdr_key(aead, read_secret, &mut pn_key)
+        .map_err(|_| Error::TlsFail)?;
+
+    let open = crypto::Open::new(aead, &key, &iv, &pn_key)
+        .map_err(|_| Error::TlsFail)?;
+
+    space.crypto_open = Some(open);
+
+    crypto::derive_pkt_key(aead, write_secret, &mut key)
+        .map_err(|_| Error::TlsFail)?;
+    crypto::derive_pkt_iv(aead, write_secret, &mut iv)
+        .map_err(|_| Error::TlsFail)?;
+    crypto::derive_hdr_key(aead, write_secret, &mut pn_key)
+        .map_err(|_| Error::TlsFail)?;
+
+    let seal = crypto::Seal::new(aead, &key, &iv, &pn_key)
+        .map_err(|_| Error::TlsFail)?;
+
+    space.crypto_seal = Some(seal);
+
+    Ok(())
+}
+
+/// Queues handshake data to be sent to the peer at the given encryption
+/// level.
+fn add_handshake_data(
+    conn: &mut Connection, level: crypto::Level, buf: &[u8],
+) -> Result<()> {
+    trace!(
+        "{} tls write message lvl={:?} len={}",
+        conn.trace_id,
+        level,
+        buf.len()
+    );
+
+    let space = match level {
+        crypto::Level::Initial => &mut conn.initial,
+        crypto::Level::ZeroRTT => unreachable!(),
+        crypto::Level::Handshake => &mut conn.handshake,
+        crypto::Level::Application => &mut conn.application,
+    };
+
+    space
+        .crypto_stream
+        .send_push(buf, false)
+        .map_err(|_| Error::TlsFail)?;
+
+    Ok(())
+}
+
+/// Records a TLS alert as the connection's error, so it's sent to the peer
+/// in a CONNECTION_CLOSE frame.
+fn send_alert(conn: &mut Connection, level: crypto::Level, alert: u8) {
+    trace!(
+        "{} tls send alert lvl={:?} alert={:x}",
+        conn.trace_id,
+        level,
+        alert
+    );
+
+    let error: u16 = TLS_ALERT_ERROR + u16::from(alert);
+    conn.error = Some(error);
+}
+
+/// Logs a line in the keylog format to the connection's keylog writer, if
+/// it has one.
+fn log_keys(conn: Option<&mut Connection>, data: &[u8]) {
+    let conn = match conn {
+        Some(v) => v,
+
+        None => return,
+    };
+
+    let keylog = match conn.keylog.as_ref() {
+        Some(v) => v,
+
+        None => return,
+    };
+
+    let mut buf = Vec::with_capacity(data.len() + 6);
+
+    buf.extend_from_slice(b"QUIC_");
+    buf.extend_from_slice(data);
+    buf.push(b'\n');
+
+    // The lock is held until the whole line is written, so lines from
+    // connections sharing a writer don't get interleaved.
+    let mut writer = keylog.lock().unwrap();
+
+    if let Err(e) = writer.write_all(&buf) {
+        trace!("{} keylog write failed: {:?}", conn.trace_id, e);
+    }
+}
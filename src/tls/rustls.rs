@@ -0,0 +1,923 @@
+// Copyright (C) 2018, Cloudflare, Inc.
+// Copyright (C) 2018, Alessandro Ghedini
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are
+// met:
+//
+//     * Redistributions of source code must retain the above copyright notice,
+//       this list of conditions and the following disclaimer.
+//
+//     * Redistributions in binary form must reproduce the above copyright
+//       notice, this list of conditions and the following disclaimer in the
+//       documentation and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS
+// IS" AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO,
+// THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR
+// PURPOSE ARE DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR
+// CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL,
+// EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO,
+// PROCUREMENT OF SUBSTITUTE GOODS OR SERVICES; LOSS OF USE, DATA, OR
+// PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF
+// LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING
+// NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE OF THIS
+// SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Pure-Rust TLS backend, based on rustls.
+//!
+//! Unlike BoringSSL, rustls doesn't allow configuring key exchange groups or
+//! loading the system's trusted CA certificates, and it doesn't tell whether
+//! a session was resumed.
+
+use std::io;
+
+use std::sync::atomic;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use ring::aead;
+
+use rustls::quic::ClientQuicExt;
+use rustls::quic::ServerQuicExt;
+
+use crate::ClientAuth;
+use crate::Connection;
+
+use crate::crypto;
+use crate::rand;
+
+use super::Error;
+use super::Result;
+use super::TlsContext;
+use super::TlsHandshake;
+
+const TLS_AD_INTERNAL_ERROR: u8 = 80;
+
+/// The lifetime hint of session tickets encrypted with the configured keys.
+const TICKET_LIFETIME: u32 = 2 * 60 * 60;
+
+#[derive(Clone)]
+struct Settings {
+    cert_chain: Vec<rustls::Certificate>,
+    cert_key: Option<rustls::sign::CertifiedKey>,
+    roots: rustls::RootCertStore,
+    verify: bool,
+    custom_verify: bool,
+    client_auth: ClientAuth,
+    cipher_suites: Vec<crypto::Algorithm>,
+    tickets: bool,
+    ticket_keys: bool,
+    ticketer: Arc<dyn rustls::ProducesTickets>,
+    client_sessions: Arc<rustls::ClientSessionMemoryCache>,
+    server_sessions: Arc<rustls::ServerSessionMemoryCache>,
+}
+
+pub struct Context(Arc<Settings>);
+
+impl TlsContext for Context {
+    type Handshake = Handshake;
+
+    fn new() -> Result<Context> {
+        Ok(Context(Arc::new(Settings {
+            cert_chain: Vec::new(),
+            cert_key: None,
+            roots: rustls::RootCertStore::empty(),
+            verify: false,
+            custom_verify: false,
+            client_auth: ClientAuth::None,
+            cipher_suites: Vec::new(),
+            tickets: true,
+            ticket_keys: false,
+            ticketer: rustls::Ticketer::new(),
+            client_sessions: rustls::ClientSessionMemoryCache::new(32),
+            server_sessions: rustls::ServerSessionMemoryCache::new(256),
+        })))
+    }
+
+    fn new_handshake(&mut self) -> Result<Handshake> {
+        Ok(Handshake {
+            hooks: Arc::new(Hooks {
+                conn: atomic::AtomicPtr::new(std::ptr::null_mut()),
+                settings: self.0.clone(),
+                server_name: Mutex::new(None),
+                cb_verified: atomic::AtomicBool::new(false),
+                resumed: atomic::AtomicBool::new(false),
+            }),
+            session: None,
+            server_name: None,
+            peer_certs: None,
+            write_level: crypto::Level::Initial,
+            pending: Vec::new(),
+            transport_params: Vec::new(),
+        })
+    }
+
+    fn use_certificate_chain_file(&mut self, file: &str) -> Result<()> {
+        let buf = std::fs::read(file).map_err(|_| Error::TlsFail)?;
+        self.use_certificate_chain_pem(&buf)
+    }
+
+    fn use_privkey_file(&mut self, file: &str) -> Result<()> {
+        let buf = std::fs::read(file).map_err(|_| Error::TlsFail)?;
+        self.use_privkey_pem(&buf)
+    }
+
+    fn use_certificate_chain_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let certs = rustls::internal::pemfile::certs(&mut io::Cursor::new(buf))
+            .map_err(|_| Error::TlsFail)?;
+
+        self.set_cert_chain(certs)
+    }
+
+    fn use_certificate_chain_der(&mut self, chain: &[&[u8]]) -> Result<()> {
+        let certs = chain
+            .iter()
+            .map(|der| rustls::Certificate(der.to_vec()))
+            .collect();
+
+        self.set_cert_chain(certs)
+    }
+
+    fn use_privkey_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let mut keys = rustls::internal::pemfile::pkcs8_private_keys(
+            &mut io::Cursor::new(buf),
+        )
+        .map_err(|_| Error::TlsFail)?;
+
+        if keys.is_empty() {
+            keys = rustls::internal::pemfile::rsa_private_keys(
+                &mut io::Cursor::new(buf),
+            )
+            .map_err(|_| Error::TlsFail)?;
+        }
+
+        let key = keys.into_iter().next().ok_or(Error::TlsFail)?;
+
+        self.set_privkey(key)
+    }
+
+    fn use_privkey_der(&mut self, buf: &[u8]) -> Result<()> {
+        self.set_privkey(rustls::PrivateKey(buf.to_vec()))
+    }
+
+    fn load_verify_locations_pem(&mut self, buf: &[u8]) -> Result<()> {
+        let settings = Arc::make_mut(&mut self.0);
+
+        let (added, _) = settings
+            .roots
+            .add_pem_file(&mut io::Cursor::new(buf))
+            .map_err(|_| Error::TlsFail)?;
+
+        if added == 0 {
+            return Err(Error::TlsFail);
+        }
+
+        Ok(())
+    }
+
+    fn set_verify(&mut self, verify: bool) {
+        let settings = Arc::make_mut(&mut self.0);
+
+        // As with BoringSSL, servers verifying peers request a certificate.
+        settings.verify = verify;
+        settings.client_auth = if verify {
+            ClientAuth::Request
+        } else {
+            ClientAuth::None
+        };
+    }
+
+    fn set_groups(&mut self, _groups: &str) -> Result<()> {
+        Err(Error::TlsFail)
+    }
+
+    fn set_cipher_suites(&mut self, suites: &[crypto::Algorithm]) -> Result<()> {
+        Arc::make_mut(&mut self.0).cipher_suites = suites.to_vec();
+
+        Ok(())
+    }
+
+    fn set_ticket_key_callback(&mut self) -> Result<()> {
+        Arc::make_mut(&mut self.0).ticket_keys = true;
+
+        Ok(())
+    }
+
+    fn set_session_tickets(&mut self, enable: bool) {
+        Arc::make_mut(&mut self.0).tickets = enable;
+    }
+
+    fn set_client_auth(&mut self, mode: ClientAuth) {
+        let settings = Arc::make_mut(&mut self.0);
+
+        settings.client_auth = mode;
+        settings.verify = mode != ClientAuth::None;
+    }
+
+    fn set_custom_verify(&mut self) {
+        Arc::make_mut(&mut self.0).custom_verify = true;
+    }
+
+    fn set_alpn(&mut self, _v: &[Vec<u8>]) -> Result<()> {
+        // Protocols are taken from the connection, as with BoringSSL.
+        Ok(())
+    }
+}
+
+impl Context {
+    fn set_cert_chain(&mut self, certs: Vec<rustls::Certificate>) -> Result<()> {
+        if certs.is_empty() {
+            return Err(Error::TlsFail);
+        }
+
+        let settings = Arc::make_mut(&mut self.0);
+
+        settings.cert_chain = certs;
+
+        if let Some(cert_key) = settings.cert_key.as_mut() {
+            cert_key.cert = settings.cert_chain.clone();
+        }
+
+        Ok(())
+    }
+
+    fn set_privkey(&mut self, key: rustls::PrivateKey) -> Result<()> {
+        let key =
+            rustls::sign::any_supported_type(&key).map_err(|_| Error::TlsFail)?;
+
+        let settings = Arc::make_mut(&mut self.0);
+
+        settings.cert_key = Some(rustls::sign::CertifiedKey::new(
+            settings.cert_chain.clone(),
+            Arc::new(key),
+        ));
+
+        Ok(())
+    }
+}
+
+impl Settings {
+    fn cert_key(&self) -> Option<rustls::sign::CertifiedKey> {
+        self.cert_key.clone().filter(|k| !k.cert.is_empty())
+    }
+}
+
+pub struct Handshake {
+    hooks: Arc<Hooks>,
+
+    session: Option<Box<dyn rustls::Session>>,
+
+    server_name: Option<String>,
+
+    peer_certs: Option<Vec<rustls::Certificate>>,
+
+    write_level: crypto::Level,
+
+    pending: Vec<Vec<u8>>,
+
+    transport_params: Vec<u8>,
+}
+
+impl TlsHandshake for Handshake {
+    fn init(&self, conn: &Connection) -> Result<()> {
+        let ptr = conn as *const Connection as *mut Connection;
+        self.hooks.conn.store(ptr, atomic::Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn set_host_name(&mut self, name: &str) -> Result<()> {
+        webpki::DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| Error::TlsFail)?;
+
+        self.server_name = Some(name.to_string());
+
+        *self.hooks.server_name.lock().unwrap() = self.server_name.clone();
+
+        Ok(())
+    }
+
+    fn set_quic_transport_params(&mut self, buf: &[u8]) -> Result<()> {
+        self.transport_params = buf.to_vec();
+
+        Ok(())
+    }
+
+    fn get_quic_transport_params(&self) -> &[u8] {
+        self.session
+            .as_ref()
+            .and_then(|s| s.get_quic_transport_parameters())
+            .unwrap_or(&[])
+    }
+
+    fn get_server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    fn get_peer_cert_chain(&self) -> Option<Vec<&[u8]>> {
+        let certs = self.peer_certs.as_ref()?;
+
+        Some(certs.iter().map(|c| c.0.as_slice()).collect())
+    }
+
+    fn is_peer_verified(&self) -> bool {
+        let settings = &self.hooks.settings;
+
+        if self.peer_certs.is_none() || self.session_handshaking() {
+            return false;
+        }
+
+        // Without a callback, the handshake only completes with a peer's
+        // certificate when the default verifier accepted it.
+        if settings.custom_verify {
+            self.hooks.cb_verified.load(atomic::Ordering::Relaxed)
+        } else {
+            settings.verify
+        }
+    }
+
+    fn get_alpn_protocol(&self) -> &[u8] {
+        self.session
+            .as_ref()
+            .and_then(|s| s.get_alpn_protocol())
+            .unwrap_or(&[])
+    }
+
+    fn provide_data(&mut self, _level: crypto::Level, buf: &[u8]) -> Result<()> {
+        // As with BoringSSL, data is only processed by the next call to
+        // do_handshake().
+        self.pending.push(buf.to_vec());
+
+        Ok(())
+    }
+
+    fn do_handshake(&mut self) -> Result<()> {
+        let conn = self.hooks.conn().ok_or(Error::TlsFail)?;
+
+        if self.session.is_none() {
+            // Servers wait for the ClientHello, as the transport parameters
+            // depend on the version used by the client.
+            if conn.is_server && self.pending.is_empty() {
+                return Err(Error::WantRead);
+            }
+
+            self.session = Some(self.new_session(conn)?);
+        }
+
+        self.read_pending(conn)?;
+
+        let session = self.session.as_mut().unwrap();
+
+        loop {
+            let mut buf = Vec::new();
+
+            let secrets = session.write_hs(&mut buf);
+
+            if !buf.is_empty() {
+                super::add_handshake_data(conn, self.write_level, &buf)?;
+            }
+
+            let secrets = match secrets {
+                Some(v) => v,
+                None => break,
+            };
+
+            // Handshake secrets are followed by the 1-RTT ones.
+            let level = match self.write_level {
+                crypto::Level::Initial => crypto::Level::Handshake,
+                _ => crypto::Level::Application,
+            };
+
+            let aead = cipher_from_session(session.as_ref())?;
+
+            let (read_secret, write_secret) = if conn.is_server {
+                (&secrets.client, &secrets.server)
+            } else {
+                (&secrets.server, &secrets.client)
+            };
+
+            if let Err(e) =
+                super::set_secrets(conn, level, aead, read_secret, write_secret)
+            {
+                super::send_alert(conn, self.write_level, TLS_AD_INTERNAL_ERROR);
+
+                return Err(e);
+            }
+
+            self.write_level = level;
+        }
+
+        if self.peer_certs.is_none() {
+            self.peer_certs = session.get_peer_certificates();
+        }
+
+        if self.server_name.is_none() {
+            self.server_name = self.hooks.server_name.lock().unwrap().clone();
+        }
+
+        if session.is_handshaking() {
+            return Err(Error::WantRead);
+        }
+
+        // Servers don't send their certificate when resuming a session.
+        if !conn.is_server && self.peer_certs.is_none() {
+            self.hooks.resumed.store(true, atomic::Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn process_post_handshake(&mut self) -> Result<()> {
+        let conn = self.hooks.conn().ok_or(Error::TlsFail)?;
+
+        self.read_pending(conn)
+    }
+
+    fn get_write_level(&self) -> crypto::Level {
+        self.write_level
+    }
+
+    fn cipher(&self) -> Result<crypto::Algorithm> {
+        let session = self.session.as_ref().ok_or(Error::TlsFail)?;
+
+        cipher_from_session(session.as_ref())
+    }
+
+    fn group(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn is_resumed(&self) -> bool {
+        !self.session_handshaking() &&
+            self.hooks.resumed.load(atomic::Ordering::Relaxed)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.session = None;
+        self.peer_certs = None;
+        self.write_level = crypto::Level::Initial;
+        self.pending.clear();
+
+        self.hooks
+            .cb_verified
+            .store(false, atomic::Ordering::Relaxed);
+        self.hooks.resumed.store(false, atomic::Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+impl Handshake {
+    fn new_session(&self, conn: &Connection) -> Result<Box<dyn rustls::Session>> {
+        let settings = &self.hooks.settings;
+
+        let raw_params = self.transport_params.clone();
+
+        let suites: Vec<&'static rustls::SupportedCipherSuite> =
+            rustls::ALL_CIPHERSUITES
+                .iter()
+                .cloned()
+                .filter(|s| match cipher_from_suite(s) {
+                    Ok(alg) =>
+                        settings.cipher_suites.is_empty() ||
+                            settings.cipher_suites.contains(&alg),
+
+                    Err(_) => false,
+                })
+                .collect();
+
+        let hooks = self.hooks.clone();
+
+        if conn.is_server {
+            let verifier: Arc<dyn rustls::ClientCertVerifier> =
+                if settings.custom_verify {
+                    hooks.clone()
+                } else {
+                    match settings.client_auth {
+                        ClientAuth::None => rustls::NoClientAuth::new(),
+
+                        ClientAuth::Request =>
+                            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(
+                                settings.roots.clone(),
+                            ),
+
+                        ClientAuth::Require =>
+                            rustls::AllowAnyAuthenticatedClient::new(
+                                settings.roots.clone(),
+                            ),
+                    }
+                };
+
+            let mut config = rustls::ServerConfig::new(verifier);
+
+            config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+            config.ciphersuites = suites;
+            config.set_protocols(&conn.application_protos);
+            config.cert_resolver = hooks.clone();
+
+            if settings.tickets {
+                config.session_storage = settings.server_sessions.clone();
+                config.ticketer = hooks.clone();
+            } else {
+                config.session_storage =
+                    Arc::new(rustls::NoServerSessionStorage {});
+            }
+
+            // Lines are only logged when the connection has a keylog writer.
+            config.key_log = hooks;
+
+            let session =
+                rustls::ServerSession::new_quic(&Arc::new(config), raw_params);
+
+            return Ok(Box::new(session));
+        }
+
+        let mut config = rustls::ClientConfig::new();
+
+        config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        config.ciphersuites = suites;
+        config.root_store = settings.roots.clone();
+        config.set_protocols(&conn.application_protos);
+        config.set_persistence(settings.client_sessions.clone());
+
+        if settings.custom_verify || !settings.verify {
+            config.dangerous().set_certificate_verifier(hooks.clone());
+        }
+
+        if let Some(cert_key) = settings.cert_key() {
+            config.client_auth_cert_resolver = Arc::new(ClientCert(cert_key));
+        }
+
+        // Lines are only logged when the connection has a keylog writer.
+        config.key_log = hooks;
+
+        // rustls always needs a name to verify the server's certificate
+        // against, so use a placeholder that's not sent to the server.
+        let host_name = match self.server_name {
+            Some(ref v) => v.as_str(),
+
+            None => {
+                config.enable_sni = false;
+                "invalid"
+            },
+        };
+
+        let host_name = webpki::DNSNameRef::try_from_ascii_str(host_name)
+            .map_err(|_| Error::TlsFail)?;
+
+        let session = rustls::ClientSession::new_quic(
+            &Arc::new(config),
+            host_name,
+            raw_params,
+        );
+
+        Ok(Box::new(session))
+    }
+
+    fn read_pending(&mut self, conn: &mut Connection) -> Result<()> {
+        let session = self.session.as_mut().ok_or(Error::TlsFail)?;
+
+        for buf in self.pending.drain(..) {
+            if session.read_hs(&buf).is_err() {
+                let alert = session
+                    .get_alert()
+                    .map(|a| a.get_u8())
+                    .unwrap_or(TLS_AD_INTERNAL_ERROR);
+
+                super::send_alert(conn, self.write_level, alert);
+
+                return Err(Error::TlsFail);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn session_handshaking(&self) -> bool {
+        self.session
+            .as_ref()
+            .map(|s| s.is_handshaking())
+            .unwrap_or(true)
+    }
+}
+
+/// The state shared by a handshake with the callbacks it installs on rustls
+/// configs, which need to reach the connection.
+struct Hooks {
+    conn: atomic::AtomicPtr<Connection>,
+
+    settings: Arc<Settings>,
+
+    server_name: Mutex<Option<String>>,
+
+    cb_verified: atomic::AtomicBool,
+
+    resumed: atomic::AtomicBool,
+}
+
+impl Hooks {
+    fn conn<'a>(&self) -> Option<&'a mut Connection> {
+        unsafe { self.conn.load(atomic::Ordering::Relaxed).as_mut() }
+    }
+
+    fn verify(&self, certs: &[rustls::Certificate]) -> bool {
+        let conn = match self.conn() {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let cb = match conn.verify_cb {
+            Some(ref v) => v,
+            None => return false,
+        };
+
+        let chain: Vec<&[u8]> = certs.iter().map(|c| c.0.as_slice()).collect();
+
+        trace!("{} verify callback certs={}", conn.trace_id(), chain.len());
+
+        let name = self.server_name.lock().unwrap().clone();
+
+        let verified = cb(&chain, name.as_deref());
+
+        self.cb_verified.store(verified, atomic::Ordering::Relaxed);
+
+        verified
+    }
+
+    fn decrypt_ticket(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if !self.settings.ticket_keys {
+            return self.settings.ticketer.decrypt(cipher);
+        }
+
+        let conn = self.conn()?;
+
+        if cipher.len() < 16 {
+            return None;
+        }
+
+        // Tickets encrypted with an unknown key are ignored, which causes a
+        // full handshake.
+        let (idx, key) = conn
+            .ticket_keys
+            .iter()
+            .enumerate()
+            .find(|(_, key)| key[..16] == cipher[..16])?;
+
+        trace!(
+            "{} ticket key callback encrypt=0 key={}",
+            conn.trace_id,
+            idx
+        );
+
+        open_ticket(key, cipher)
+    }
+}
+
+// The connection is only reached from the callbacks while the handshake
+// that owns them is driven by the connection itself, like BoringSSL's.
+unsafe impl Send for Hooks {}
+unsafe impl Sync for Hooks {}
+
+impl rustls::ServerCertVerifier for Hooks {
+    fn verify_server_cert(
+        &self, _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate], _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<rustls::ServerCertVerified, rustls::TLSError> {
+        // Without a callback, this is only used when verification is
+        // disabled.
+        if !self.settings.custom_verify || self.verify(presented_certs) {
+            return Ok(rustls::ServerCertVerified::assertion());
+        }
+
+        Err(cert_rejected())
+    }
+}
+
+impl rustls::ClientCertVerifier for Hooks {
+    fn client_auth_mandatory(&self) -> bool {
+        self.settings.client_auth == ClientAuth::Require
+    }
+
+    fn client_auth_root_subjects(&self) -> rustls::DistinguishedNames {
+        self.settings.roots.get_subjects()
+    }
+
+    fn verify_client_cert(
+        &self, presented_certs: &[rustls::Certificate],
+    ) -> std::result::Result<rustls::ClientCertVerified, rustls::TLSError> {
+        if self.verify(presented_certs) {
+            return Ok(rustls::ClientCertVerified::assertion());
+        }
+
+        Err(cert_rejected())
+    }
+}
+
+impl rustls::ResolvesServerCert for Hooks {
+    fn resolve(
+        &self, server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        let name: Option<&str> = server_name.map(|n| n.into());
+
+        if let Some(name) = name {
+            *self.server_name.lock().unwrap() = Some(name.to_string());
+        }
+
+        let default = self.settings.cert_key();
+
+        let (conn, name) = match (self.conn(), name) {
+            (Some(conn), Some(name)) => (conn, name),
+            _ => return default,
+        };
+
+        let cb = match conn.server_name_cb {
+            Some(ref v) => v,
+            None => return default,
+        };
+
+        trace!("{} server name callback name={}", conn.trace_id(), name);
+
+        match cb(name) {
+            Some(config) => config.tls_ctx.0.cert_key(),
+            None => default,
+        }
+    }
+}
+
+impl rustls::ProducesTickets for Hooks {
+    fn enabled(&self) -> bool {
+        self.settings.tickets
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        if !self.settings.ticket_keys {
+            return self.settings.ticketer.get_lifetime();
+        }
+
+        TICKET_LIFETIME
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        if !self.settings.ticket_keys {
+            return self.settings.ticketer.encrypt(plain);
+        }
+
+        let conn = self.conn()?;
+
+        // New tickets are always encrypted with the current key.
+        let key = conn.ticket_keys.first()?;
+
+        trace!("{} ticket key callback encrypt=1 key=0", conn.trace_id);
+
+        seal_ticket(key, plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let plain = self.decrypt_ticket(cipher);
+
+        if plain.is_some() {
+            self.resumed.store(true, atomic::Ordering::Relaxed);
+        }
+
+        plain
+    }
+}
+
+impl rustls::KeyLog for Hooks {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}", label, hex(client_random), hex(secret));
+
+        super::log_keys(self.conn(), line.as_bytes());
+    }
+}
+
+/// Presents the configured certificate to servers that request one.
+struct ClientCert(rustls::sign::CertifiedKey);
+
+impl rustls::ResolvesClientCert for ClientCert {
+    fn resolve(
+        &self, _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn cipher_from_session(
+    session: &dyn rustls::Session,
+) -> Result<crypto::Algorithm> {
+    let suite = session.get_negotiated_ciphersuite().ok_or(Error::TlsFail)?;
+
+    cipher_from_suite(suite)
+}
+
+fn cipher_from_suite(
+    suite: &rustls::SupportedCipherSuite,
+) -> Result<crypto::Algorithm> {
+    let alg = match suite.suite.get_u16() {
+        0x1301 => crypto::Algorithm::AES128_GCM,
+        0x1302 => crypto::Algorithm::AES256_GCM,
+        0x1303 => crypto::Algorithm::ChaCha20_Poly1305,
+        _ => return Err(Error::TlsFail),
+    };
+
+    Ok(alg)
+}
+
+fn cert_rejected() -> rustls::TLSError {
+    rustls::TLSError::General("certificate rejected".to_string())
+}
+
+/// Encrypts a session ticket with AES-128-GCM, using the key's name as
+/// additional data.
+///
+/// Unlike BoringSSL's tickets, which are authenticated with the key's
+/// HMAC-SHA256 key, the AEAD's tag is used for authentication instead.
+fn seal_ticket(
+    key: &[u8; super::TICKET_KEY_LEN], plain: &[u8],
+) -> Option<Vec<u8>> {
+    let seal_key = aead::SealingKey::new(&aead::AES_128_GCM, &key[32..]).ok()?;
+
+    let mut nonce = [0; 12];
+    rand::rand_bytes(&mut nonce);
+
+    let tag_len = aead::AES_128_GCM.tag_len();
+
+    let mut out = Vec::with_capacity(16 + nonce.len() + plain.len() + tag_len);
+
+    out.extend_from_slice(&key[..16]);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(plain);
+    out.resize(out.len() + tag_len, 0);
+
+    let ad = aead::Aad::from(&key[..16]);
+
+    aead::seal_in_place(
+        &seal_key,
+        aead::Nonce::assume_unique_for_key(nonce),
+        ad,
+        &mut out[16 + nonce.len()..],
+        tag_len,
+    )
+    .ok()?;
+
+    Some(out)
+}
+
+fn open_ticket(
+    key: &[u8; super::TICKET_KEY_LEN], cipher: &[u8],
+) -> Option<Vec<u8>> {
+    if cipher.len() < 16 + 12 {
+        return None;
+    }
+
+    let open_key = aead::OpeningKey::new(&aead::AES_128_GCM, &key[32..]).ok()?;
+
+    let nonce = aead::Nonce::try_assume_unique_for_key(&cipher[16..28]).ok()?;
+
+    let ad = aead::Aad::from(&key[..16]);
+
+    let mut buf = cipher[28..].to_vec();
+
+    let plain = aead::open_in_place(&open_key, nonce, ad, 0, &mut buf).ok()?;
+
+    Some(plain.to_vec())
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticket_roundtrip() {
+        let mut key = [0; super::super::TICKET_KEY_LEN];
+        rand::rand_bytes(&mut key);
+
+        let ticket = seal_ticket(&key, b"resumption state").unwrap();
+        assert_eq!(&ticket[..16], &key[..16]);
+
+        let plain = open_ticket(&key, &ticket).unwrap();
+        assert_eq!(&plain[..], b"resumption state");
+
+        let mut tampered = ticket.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        assert_eq!(open_ticket(&key, &tampered), None);
+
+        let mut other = key;
+        other[40] ^= 0xff;
+        assert_eq!(open_ticket(&other, &ticket), None);
+    }
+}